@@ -1,3 +1,4 @@
+#[cfg(not(test))]
 #[macro_export]
 macro_rules! msr {
     ($reg:expr, $op:expr) => {
@@ -8,6 +9,7 @@ macro_rules! msr {
     }
 }
 
+#[cfg(not(test))]
 #[macro_export]
 macro_rules! mrs {
     ($var:expr, $spr:expr) => { 
@@ -17,6 +19,28 @@ macro_rules! mrs {
     }
 }
 
+/*
+ * Host builds for `cargo test` have no system registers: writes are
+ * dropped and every register reads as zero.
+ */
+#[cfg(test)]
+#[macro_export]
+macro_rules! msr {
+    ($reg:expr, $op:expr) => {
+        let _ = $op;
+    }
+}
+
+#[cfg(test)]
+#[macro_export]
+macro_rules! mrs {
+    ($var:expr, $spr:expr) => {
+        $var = 0;
+    }
+}
+
+use crate::common::bitfield;
+
 pub enum ExceptionLevel {
     EL0 = 0,
     EL1 = 1,
@@ -40,7 +64,9 @@ pub enum Shareable {
     FullSystem,
 }
 
+#[cfg_attr(test, allow(unused_variables))]
 pub fn data_barrier(sh: Shareable) -> () {
+    #[cfg(not(test))]
     match sh {
         Shareable::Non => unsafe { asm!("dsb nsh"); },
         Shareable::Inner => unsafe { asm!("dsb ish"); },
@@ -49,7 +75,245 @@ pub fn data_barrier(sh: Shareable) -> () {
     }
 }
 
+pub fn id_aa64mmfr0() -> u64 {
+    let mmfr0: u64;
+
+    mrs!(mmfr0, "ID_AA64MMFR0_EL1");
+
+    mmfr0
+}
+
+pub fn id_aa64mmfr1() -> u64 {
+    let mmfr1: u64;
+
+    mrs!(mmfr1, "ID_AA64MMFR1_EL1");
+
+    mmfr1
+}
+
+/* CTR_EL0.DminLine, log2 of the smallest data cache line in words */
+const CTR_EL0_DMINLINE_SHIFT: u64 = 16;
+const CTR_EL0_DMINLINE_MASK: u64 = 0xf;
+
+/// The smallest data cache line size in bytes, for data cache maintenance
+pub fn dcache_line_size() -> u64 {
+    let ctr: u64;
+
+    mrs!(ctr, "CTR_EL0");
+
+    4 << ((ctr >> CTR_EL0_DMINLINE_SHIFT) & CTR_EL0_DMINLINE_MASK)
+}
+
+/// Clean and invalidate [address, address + size) to the Point of
+/// Coherency, so that accesses that miss the caches (a guest running
+/// with its MMU off, say) see what the hypervisor wrote
+pub fn dcache_clean_invalidate_poc(address: u64, size: u64) -> () {
+    let line = dcache_line_size();
+    let mut line_address = address & !(line - 1);
+
+    while line_address < address + size {
+        #[cfg(not(test))]
+        unsafe { asm!("dc civac, $0" :: "r"(line_address)); }
+        line_address += line;
+    }
+
+    data_barrier(Shareable::Inner);
+}
+
+/*
+ * PAR_EL1.PA holds PA[47:12], or PA[51:12] once the PA range is 52 bits
+ * (FEAT_LPA).  F is bit 0.
+ */
+const PAR_EL1_F: u64 = 1;
+const PAR_EL1_PA_MASK: u64 = bitfield(48, 12);
+const PAR_EL1_PA_MASK_52_BITS: u64 = bitfield(52, 12);
+
+/* ID_AA64MMFR0_EL1.PARange for 52 bits */
+const ID_AA64MMFR0_PARANGE_MASK: u64 = 0xf;
+const ID_AA64MMFR0_PARANGE_52_BITS: u64 = 0b0110;
+
+fn par_pa_mask(mmfr0: u64) -> u64 {
+    if mmfr0 & ID_AA64MMFR0_PARANGE_MASK == ID_AA64MMFR0_PARANGE_52_BITS {
+        PAR_EL1_PA_MASK_52_BITS
+    } else {
+        PAR_EL1_PA_MASK
+    }
+}
+
+/// Translate a guest virtual address with the guest's stage 1 tables,
+/// as loaded in the EL1 registers, for a read or a write.  Returns the
+/// IPA, or None if the guest's stage 1 would fault.
+#[cfg_attr(test, allow(unused_variables))]
+pub fn at_s1e1(va: u64, write: bool) -> Option<u64> {
+    let par: u64;
+    let guest_par: u64;
+
+    /* PAR_EL1 belongs to the guest */
+    mrs!(guest_par, "PAR_EL1");
+    #[cfg(not(test))]
+    unsafe {
+        if write {
+            asm!("at s1e1w, $0" :: "r"(va));
+        } else {
+            asm!("at s1e1r, $0" :: "r"(va));
+        }
+    }
+    isb();
+    mrs!(par, "PAR_EL1");
+    msr!("PAR_EL1", guest_par);
+
+    if par & PAR_EL1_F != 0 {
+        return None;
+    }
+
+    Some((par & par_pa_mask(id_aa64mmfr0())) | (va & 0xfff))
+}
+
+/*
+ * HPFAR_EL2.FIPA holds IPA[47:12] in bits [39:4], or IPA[51:12] in bits
+ * [43:4] once the PA range is 52 bits
+ */
+const HPFAR_EL2_FIPA_MASK: u64 = bitfield(40, 4);
+const HPFAR_EL2_FIPA_MASK_52_BITS: u64 = bitfield(44, 4);
+const HPFAR_EL2_FIPA_SHIFT: u64 = 8;
+
+fn hpfar_fipa_mask(mmfr0: u64) -> u64 {
+    if mmfr0 & ID_AA64MMFR0_PARANGE_MASK == ID_AA64MMFR0_PARANGE_52_BITS {
+        HPFAR_EL2_FIPA_MASK_52_BITS
+    } else {
+        HPFAR_EL2_FIPA_MASK
+    }
+}
+
+/// The IPA of the page whose stage 2 translation faulted, as reported
+/// in HPFAR_EL2
+pub fn hpfar_ipa() -> u64 {
+    let hpfar: u64;
+
+    mrs!(hpfar, "HPFAR_EL2");
+
+    (hpfar & hpfar_fipa_mask(id_aa64mmfr0())) << HPFAR_EL2_FIPA_SHIFT
+}
+
 pub fn isb() -> () {
+    #[cfg(not(test))]
     unsafe{ asm!("isb") }
 }
 
+
+/*
+ * TLB maintenance
+ *
+ * All of these broadcast to the Inner Shareable domain so that other
+ * PEs drop their stale entries too.  The leading DSB makes the
+ * descriptor update visible to the table walkers before the
+ * invalidation, the trailing DSB waits for the invalidation to
+ * complete on every PE.
+ *
+ * The instructions themselves are left out of host builds, which
+ * record what would have been invalidated instead, see tlbi_log().
+ */
+
+#[cfg(test)]
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Tlbi {
+    Vae2is(u64),
+    Ipas2e1is(u64),
+    Vmalle1is,
+    Vmalls12e1is,
+    Alle1is,
+}
+
+#[cfg(test)]
+thread_local! {
+    static TLBI_LOG: core::cell::RefCell<Vec<Tlbi>> = core::cell::RefCell::new(Vec::new());
+}
+
+#[cfg(test)]
+fn record(tlbi: Tlbi) -> () {
+    TLBI_LOG.with(|log| log.borrow_mut().push(tlbi));
+}
+
+/// The invalidations this test thread has done since the last call
+#[cfg(test)]
+pub fn tlbi_log() -> Vec<Tlbi> {
+    TLBI_LOG.with(|log| log.replace(Vec::new()))
+}
+
+/// Invalidate the EL2 stage 1 entries for the page containing vaddr
+pub fn tlbi_vae2is(vaddr: u64) -> () {
+    data_barrier(Shareable::Inner);
+    #[cfg(not(test))]
+    unsafe { asm!("tlbi vae2is, $0" :: "r"(vaddr >> 12)); }
+    #[cfg(test)]
+    record(Tlbi::Vae2is(vaddr & !0xfff));
+    data_barrier(Shareable::Inner);
+    isb();
+}
+
+/// Invalidate the stage 2 entries for the page containing ipa.
+///
+/// This only applies to the VMID currently in VTTBR_EL2.
+pub fn tlbi_ipas2e1is(ipa: u64) -> () {
+    data_barrier(Shareable::Inner);
+    #[cfg(not(test))]
+    unsafe { asm!("tlbi ipas2e1is, $0" :: "r"(ipa >> 12)); }
+    #[cfg(test)]
+    record(Tlbi::Ipas2e1is(ipa & !0xfff));
+}
+
+/// Invalidate all stage 1 (and combined stage 1 + 2) EL1&0 entries for
+/// the VMID currently in VTTBR_EL2.
+///
+/// This must follow any tlbi_ipas2e1is(), since TLBs may hold entries
+/// that combine both stages of translation and those are not tagged
+/// by IPA.
+pub fn tlbi_vmalle1is() -> () {
+    data_barrier(Shareable::Inner);
+    #[cfg(not(test))]
+    unsafe { asm!("tlbi vmalle1is"); }
+    #[cfg(test)]
+    record(Tlbi::Vmalle1is);
+    data_barrier(Shareable::Inner);
+    isb();
+}
+
+/// Invalidate all stage 1 and stage 2 EL1&0 entries for the VMID
+/// currently in VTTBR_EL2
+pub fn tlbi_vmalls12e1is() -> () {
+    data_barrier(Shareable::Inner);
+    #[cfg(not(test))]
+    unsafe { asm!("tlbi vmalls12e1is"); }
+    #[cfg(test)]
+    record(Tlbi::Vmalls12e1is);
+    data_barrier(Shareable::Inner);
+    isb();
+}
+
+/// Invalidate all EL1&0 entries, for every VMID
+pub fn tlbi_alle1is() -> () {
+    data_barrier(Shareable::Inner);
+    #[cfg(not(test))]
+    unsafe { asm!("tlbi alle1is"); }
+    #[cfg(test)]
+    record(Tlbi::Alle1is);
+    data_barrier(Shareable::Inner);
+    isb();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn par_holds_52_bit_addresses_with_lpa() {
+        assert_eq!(par_pa_mask(0b0101), 0x0000_ffff_ffff_f000);
+        assert_eq!(par_pa_mask(0b0110), 0x000f_ffff_ffff_f000);
+    }
+
+    #[test]
+    fn hpfar_holds_52_bit_ipas_with_lpa() {
+        assert_eq!(hpfar_fipa_mask(0b0101) << HPFAR_EL2_FIPA_SHIFT, 0x0000_ffff_ffff_f000);
+        assert_eq!(hpfar_fipa_mask(0b0110) << HPFAR_EL2_FIPA_SHIFT, 0x000f_ffff_ffff_f000);
+    }
+}
@@ -0,0 +1,508 @@
+/*
+ * Page deduplication across VMs.
+ *
+ * Many near-identical guests hold the same kernel image and the same
+ * zero pages.  The scanner hashes guest pages, and when it finds two
+ * with the same contents it maps both IPAs to one of the frames,
+ * read-only, and frees the other.  The first guest write to a shared
+ * page takes a stage 2 permission fault, and break_sharing() gives the
+ * guest a private copy again (or the frame itself once nobody else
+ * maps it).
+ *
+ * This is opt-in: nothing is shared until dedup::init() has been
+ * called, which start.rs only does with DEDUP_ENABLED set.  Every VM's
+ * RAM is then scanned, a few pages at a time, whenever the current VM
+ * idles, see VmList::idle().
+ *
+ * Only pages are merged, huge pages are left alone until something
+ * splits them.  Pages that are being dirty logged are skipped, see
+ * dirty.rs.  Unmapping a shared page does not drop its reference, so
+ * its frame is leaked rather than freed under another VM; only VM
+ * teardown gives references back, see drop_reference().
+ *
+ * The duplicate frames go back through release_frames(), since they
+ * hold guest data: identical, but not necessarily the next owner's.
+ */
+
+use alloc::boxed::Box;
+
+use crate::lpae::{PageTableTreeStage2, TranslationTree, Granule, MapError, PTE_SW_SHARED, PTE_SW_LOGGED};
+use crate::phys::PhysicalMemory;
+use crate::memory_attrs::{Stage2Access, Stage2MemoryType};
+use crate::walk::translate;
+use crate::vm::Vm;
+use crate::vmid;
+use crate::spinlock::{SpinLock, SpinLockGuard};
+
+/* S2AP[1], the write permission */
+const S2AP_WRITE: u64 = Stage2Access::WriteOnly as u64;
+
+const MAX_SHARED_FRAMES: usize = 256;
+const MAX_CANDIDATES: usize = 256;
+
+/// A guest memory range to scan, in the VM at vms[vm]
+#[derive(Copy, Clone, Debug)]
+pub struct ScanRange {
+    pub vm: usize,
+    pub ipa: u64,
+    pub size: u64,
+}
+
+/// A frame mapped read-only by `refs` guest pages
+#[derive(Copy, Clone, Debug)]
+struct SharedFrame {
+    address: u64,
+    hash: u64,
+    refs: u64,
+}
+
+/// A page seen during a scan that nothing has matched yet
+#[derive(Copy, Clone, Debug)]
+struct Candidate {
+    vm: usize,
+    ipa: u64,
+    frame: u64,
+    hash: u64,
+}
+
+pub struct DedupScanner {
+    granule: Granule,
+    shared: [Option<SharedFrame>; MAX_SHARED_FRAMES],
+
+    /* The pass so far: where it is up to, in pages, and what it has seen */
+    cursor: u64,
+    candidates: [Option<Candidate>; MAX_CANDIDATES],
+}
+
+/// FNV-1a over the frame's words
+fn hash_frame(mem: &dyn PhysicalMemory, frame: u64, size: u64) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+
+    for address in (frame..frame + size).step_by(8) {
+        hash ^= mem.read_u64(address);
+        hash = hash.wrapping_mul(0x100_0000_01b3);
+    }
+
+    hash
+}
+
+fn same_contents(mem: &dyn PhysicalMemory, a: u64, b: u64, size: u64) -> bool {
+    (0..size).step_by(8).all(|offset| mem.read_u64(a + offset) == mem.read_u64(b + offset))
+}
+
+fn stage2(vms: &mut [Option<Box<Vm>>], vm: usize) -> &mut PageTableTreeStage2 {
+    &mut vms[vm].as_mut().unwrap().stage2
+}
+
+/// Map ipa, a page, read-only to the shared frame
+fn share(tree: &mut PageTableTreeStage2,
+         mem: &mut dyn PhysicalMemory,
+         ipa: u64,
+         frame: u64) -> Result<(), MapError> {
+    let granule = tree.granule();
+
+    tree.update_range(mem, ipa, granule.page_size(), &mut |entry, _address, _level| {
+        let mut new = entry;
+        new.set_output_address(frame, granule);
+        new.set_s2ap(entry.s2ap() & !S2AP_WRITE);
+        new.set_software(entry.software() | PTE_SW_SHARED);
+        new
+    })
+}
+
+impl DedupScanner {
+    pub fn new(granule: Granule) -> DedupScanner {
+        DedupScanner {
+            granule: granule,
+            shared: [None; MAX_SHARED_FRAMES],
+            cursor: 0,
+            candidates: [None; MAX_CANDIDATES],
+        }
+    }
+
+    /// The number of frames currently shared
+    pub fn shared_frames(&self) -> usize {
+        self.shared.iter().filter(|shared| shared.is_some()).count()
+    }
+
+    fn find_shared(&mut self, address: u64) -> Option<&mut SharedFrame> {
+        self.shared.iter_mut()
+            .filter_map(|shared| shared.as_mut())
+            .find(|shared| shared.address == address)
+    }
+
+    /// Returns the frame mapping the page at ipa, if it is a writable
+    /// page of guest RAM that nothing else has claimed
+    fn mergeable(&self, tree: &PageTableTreeStage2, mem: &dyn PhysicalMemory, ipa: u64) -> Option<u64> {
+        let translation = translate(tree, mem, ipa).ok()?;
+        let descriptor = translation.descriptor;
+
+        let ok = translation.level == 3 &&
+                 descriptor.s2ap() == Stage2Access::ReadWrite as u64 &&
+                 descriptor.software() & (PTE_SW_SHARED | PTE_SW_LOGGED) == 0 &&
+                 Stage2MemoryType::from_mem_attr(descriptor.mem_attr()) ==
+                     Some(Stage2MemoryType::NormalWriteBack);
+
+        if ok {
+            Some(translation.output_address)
+        } else {
+            None
+        }
+    }
+
+    /// Scan up to `budget` pages of `ranges`, carrying on from where the
+    /// last scan stopped, and merge the identical ones.  Returns the
+    /// number of frames freed.  A page matches any page seen since the
+    /// pass over all of `ranges` began, in whichever VM.
+    ///
+    /// The guests must not run during the scan.  Stage 2 updates only
+    /// invalidate the TLBs for the VMID in VTTBR_EL2, so every VM with a
+    /// page merged is flushed by VMID before this returns.
+    pub fn scan(&mut self,
+                vms: &mut [Option<Box<Vm>>],
+                mem: &mut dyn PhysicalMemory,
+                ranges: &[ScanRange],
+                budget: u64) -> Result<usize, MapError> {
+        let mut changed = 0;
+        let result = self.merge(vms, mem, ranges, budget, &mut changed);
+
+        for (i, vm) in vms.iter().enumerate() {
+            if changed & (1 << i) != 0 {
+                if let Some(vmid) = vm.as_ref().and_then(|vm| vm.vmid) {
+                    vmid::flush_vmid(vmid);
+                }
+            }
+        }
+
+        result
+    }
+
+    /// Whether the candidate still maps the frame it was seen in, and so
+    /// can be merged
+    fn still_mergeable(&self, vms: &[Option<Box<Vm>>], mem: &dyn PhysicalMemory, candidate: Candidate) -> bool {
+        match vms[candidate.vm].as_ref() {
+            Some(vm) => self.mergeable(&vm.stage2, mem, candidate.ipa) == Some(candidate.frame),
+            None => false,
+        }
+    }
+
+    /// scan() without the TLB maintenance, setting bit i of `changed`
+    /// for each vms[i] that had a page merged
+    fn merge(&mut self,
+             vms: &mut [Option<Box<Vm>>],
+             mem: &mut dyn PhysicalMemory,
+             ranges: &[ScanRange],
+             budget: u64,
+             changed: &mut u64) -> Result<usize, MapError> {
+        let page_size = self.granule.page_size();
+        let mut position = 0;
+        let mut scanned = 0;
+        let mut freed = 0;
+
+        assert!(vms.len() <= 64);
+
+        for range in ranges.iter() {
+            let pages = range.size / page_size;
+
+            if position + pages <= self.cursor {
+                position += pages;
+                continue;
+            }
+
+            let first = self.cursor.saturating_sub(position);
+
+            for page in first..pages {
+                if scanned == budget {
+                    self.cursor = position + page;
+                    return Ok(freed);
+                }
+                scanned += 1;
+
+                let ipa = range.ipa + page * page_size;
+                let frame = match vms[range.vm].as_ref() {
+                    Some(vm) => match self.mergeable(&vm.stage2, mem, ipa) {
+                        Some(frame) => frame,
+                        None => continue,
+                    },
+                    None => continue,
+                };
+                let hash = hash_frame(mem, frame, page_size);
+
+                /* Already shared by someone else? */
+                let shared = self.shared.iter_mut()
+                    .filter_map(|shared| shared.as_mut())
+                    .find(|shared| shared.hash == hash &&
+                                   same_contents(mem, shared.address, frame, page_size));
+
+                if let Some(shared) = shared {
+                    share(stage2(vms, range.vm), mem, ipa, shared.address)?;
+                    *changed |= 1 << range.vm;
+                    shared.refs += 1;
+                    mem.release_frames(frame, page_size);
+                    freed += 1;
+                    continue;
+                }
+
+                /* Seen the same page earlier in this pass? */
+                let matched = self.candidates.iter()
+                    .position(|slot| match slot {
+                        Some(candidate) => candidate.hash == hash &&
+                                           same_contents(mem, candidate.frame, frame, page_size),
+                        None => false,
+                    });
+                let slot = self.shared.iter().position(|shared| shared.is_none());
+
+                /* The guest may have moved or freed the candidate since a previous scan */
+                let matched = match matched {
+                    Some(matched) => {
+                        let candidate = self.candidates[matched].unwrap();
+
+                        if self.still_mergeable(vms, mem, candidate) {
+                            Some(matched)
+                        } else {
+                            self.candidates[matched] = None;
+                            None
+                        }
+                    },
+                    None => None,
+                };
+
+                match (matched, slot) {
+                    (Some(matched), Some(slot)) => {
+                        let candidate = self.candidates[matched].take().unwrap();
+
+                        /* Tracked as soon as it is mapped shared, in case the second share fails */
+                        share(stage2(vms, candidate.vm), mem, candidate.ipa, candidate.frame)?;
+                        *changed |= 1 << candidate.vm;
+                        self.shared[slot] = Some(SharedFrame {
+                            address: candidate.frame,
+                            hash: hash,
+                            refs: 1,
+                        });
+
+                        share(stage2(vms, range.vm), mem, ipa, candidate.frame)?;
+                        *changed |= 1 << range.vm;
+                        self.shared[slot].as_mut().unwrap().refs += 1;
+                        mem.release_frames(frame, page_size);
+                        freed += 1;
+                    },
+                    (Some(_), None) => (),
+                    (None, _) => {
+                        if let Some(free) = self.candidates.iter_mut().find(|slot| slot.is_none()) {
+                            *free = Some(Candidate { vm: range.vm, ipa: ipa, frame: frame, hash: hash });
+                        }
+                    },
+                }
+            }
+
+            position += pages;
+        }
+
+        /* A whole pass done, the next one starts afresh */
+        self.cursor = 0;
+        self.candidates = [None; MAX_CANDIDATES];
+
+        Ok(freed)
+    }
+
+    /// Handle a stage 2 permission fault on a write to ipa.  If ipa maps
+    /// a shared frame, give the guest a writable frame of its own, with
+    /// the same contents, and return true.
+    ///
+    /// `logged` must be set if ipa is being dirty logged, so that the
+    /// new page is logged as well.
+    pub fn break_sharing(&mut self,
+                         tree: &mut PageTableTreeStage2,
+                         mem: &mut dyn PhysicalMemory,
+                         ipa: u64,
+                         logged: bool) -> bool {
+        let page_size = self.granule.page_size();
+        let granule = self.granule;
+
+        let translation = match translate(tree, mem, ipa) {
+            Ok(translation) => translation,
+            Err(_) => return false,
+        };
+
+        if translation.descriptor.software() & PTE_SW_SHARED == 0 {
+            return false;
+        }
+
+        let frame = translation.output_address & !(page_size - 1);
+        let shared = self.find_shared(frame).unwrap();
+
+        let private = if shared.refs == 1 {
+            /* The last user keeps the frame */
+            self.shared.iter_mut()
+                .find(|shared| shared.map(|shared| shared.address) == Some(frame))
+                .unwrap()
+                .take();
+            frame
+        } else {
+            let copy = match mem.alloc_frames(page_size, page_size) {
+                Some(copy) => copy,
+                None => return false,
+            };
+
+            for offset in (0..page_size).step_by(8) {
+                let word = mem.read_u64(frame + offset);
+                mem.write_u64(copy + offset, word);
+            }
+
+            shared.refs -= 1;
+            copy
+        };
+
+        tree.update_range(mem, ipa & !(page_size - 1), page_size, &mut |entry, _address, _level| {
+            let mut new = entry;
+            new.set_output_address(private, granule);
+            new.set_s2ap(entry.s2ap() | S2AP_WRITE);
+            new.set_software(if logged { PTE_SW_LOGGED } else { 0 });
+            new
+        }).unwrap();
+
+        true
+    }
+
+    /// A guest page mapping the shared frame at `frame` is going away
+    /// with its VM.  Returns true if it was the last one, in which case
+    /// the frame is the caller's to release.
+    pub fn drop_reference(&mut self, frame: u64) -> bool {
+        let slot = self.shared.iter_mut()
+            .find(|shared| shared.map(|shared| shared.address) == Some(frame))
+            .unwrap();
+
+        let shared = slot.as_mut().unwrap();
+        if shared.refs > 1 {
+            shared.refs -= 1;
+            return false;
+        }
+
+        slot.take();
+        true
+    }
+}
+
+static DEDUP_SCANNER: SpinLock<Option<Box<DedupScanner>>> = SpinLock::new(None);
+
+/// Turn on page sharing
+pub fn init(granule: Granule) -> () {
+    /* Allocated before the lock is taken, the heap comes first */
+    let scanner = Box::new(DedupScanner::new(granule));
+
+    *DEDUP_SCANNER.lock() = Some(scanner);
+}
+
+/// The scanner, None unless page sharing is on, locked until the guard
+/// is dropped.  Take it after the frame allocator, see spinlock.rs.
+pub fn dedup_scanner() -> SpinLockGuard<'static, Option<Box<DedupScanner>>> {
+    DEDUP_SCANNER.lock()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lpae::PhysAddrRange;
+    use crate::memory_attrs::Stage2Flags;
+    use crate::phys::SimulatedMemory;
+
+    const PAGE: u64 = 0x1000;
+    const GUEST: u64 = 0x4000_0000;
+
+    fn guest(mem: &mut SimulatedMemory, contents: &[u64]) -> Vm {
+        let granule = Granule::Kb4;
+        let mut tree = PageTableTreeStage2::new(mem, granule, PhysAddrRange::new(0b0010, granule)).unwrap();
+
+        for (i, &value) in contents.iter().enumerate() {
+            let frame = mem.alloc_frames(PAGE, PAGE).unwrap();
+            for offset in (0..PAGE).step_by(8) {
+                mem.write_u64(frame + offset, value);
+            }
+            tree.map_range(mem, GUEST + i as u64 * PAGE, frame, PAGE, Stage2Flags::normal()).unwrap();
+        }
+
+        Vm::new(tree)
+    }
+
+    fn tree(vms: &[Option<Box<Vm>>], vm: usize) -> &PageTableTreeStage2 {
+        &vms[vm].as_ref().unwrap().stage2
+    }
+
+    fn frame(tree: &PageTableTreeStage2, mem: &SimulatedMemory, ipa: u64) -> u64 {
+        translate(tree, mem, ipa).unwrap().output_address
+    }
+
+    #[test]
+    fn identical_pages_are_merged_and_copied_on_write() {
+        let mut mem = SimulatedMemory::new(0x1000_0000, 0x20_0000);
+        let mut vms = [Some(Box::new(guest(&mut mem, &[1, 2, 3]))),
+                       Some(Box::new(guest(&mut mem, &[2, 4, 2])))];
+        let mut scanner = DedupScanner::new(Granule::Kb4);
+        let before = mem.allocated();
+
+        let ranges = [
+            ScanRange { vm: 0, ipa: GUEST, size: 3 * PAGE },
+            ScanRange { vm: 1, ipa: GUEST, size: 3 * PAGE },
+        ];
+        let freed = scanner.scan(&mut vms, &mut mem, &ranges, !0).unwrap();
+
+        /* a's page of 2s, b's two pages of 2s: one frame for all three */
+        assert_eq!(freed, 2);
+        assert_eq!(mem.allocated(), before - 2 * PAGE);
+        assert_eq!(scanner.shared_frames(), 1);
+
+        let shared = frame(tree(&vms, 0), &mem, GUEST + PAGE);
+        assert_eq!(frame(tree(&vms, 1), &mem, GUEST), shared);
+        assert_eq!(frame(tree(&vms, 1), &mem, GUEST + 2 * PAGE), shared);
+        assert_eq!(translate(tree(&vms, 1), &mem, GUEST).unwrap().descriptor.s2ap(), Stage2Access::ReadOnly as u64);
+        assert_ne!(frame(tree(&vms, 0), &mem, GUEST), frame(tree(&vms, 1), &mem, GUEST + PAGE));
+
+        /* Nothing is shared twice */
+        assert_eq!(scanner.scan(&mut vms, &mut mem, &ranges, !0).unwrap(), 0);
+
+        /* Unshared pages are not ours to handle */
+        assert!(!scanner.break_sharing(stage2(&mut vms, 0), &mut mem, GUEST, false));
+
+        assert!(scanner.break_sharing(stage2(&mut vms, 1), &mut mem, GUEST + 8, false));
+        let copy = frame(tree(&vms, 1), &mem, GUEST);
+        assert_ne!(copy, shared);
+        assert_eq!(mem.read_u64(copy + 0x800), 2);
+        assert_eq!(translate(tree(&vms, 1), &mem, GUEST).unwrap().descriptor.s2ap(), Stage2Access::ReadWrite as u64);
+
+        assert!(scanner.break_sharing(stage2(&mut vms, 0), &mut mem, GUEST + PAGE, true));
+        let descriptor = translate(tree(&vms, 0), &mem, GUEST + PAGE).unwrap().descriptor;
+        assert_eq!(descriptor.software(), PTE_SW_LOGGED);
+
+        /* The last user gets the shared frame back */
+        assert!(scanner.break_sharing(stage2(&mut vms, 1), &mut mem, GUEST + 2 * PAGE, false));
+        assert_eq!(frame(tree(&vms, 1), &mem, GUEST + 2 * PAGE), shared);
+        assert_eq!(scanner.shared_frames(), 0);
+    }
+
+    #[test]
+    fn scans_pick_up_where_they_stopped() {
+        let mut mem = SimulatedMemory::new(0x1000_0000, 0x20_0000);
+        let mut vms = [Some(Box::new(guest(&mut mem, &[1, 2, 3]))),
+                       None,
+                       Some(Box::new(guest(&mut mem, &[3, 4, 2])))];
+        let mut scanner = DedupScanner::new(Granule::Kb4);
+
+        let ranges = [
+            ScanRange { vm: 0, ipa: GUEST, size: 3 * PAGE },
+            ScanRange { vm: 1, ipa: GUEST, size: 3 * PAGE },
+            ScanRange { vm: 2, ipa: GUEST, size: 3 * PAGE },
+        ];
+
+        /* Matches are found across scans, and across VMs */
+        let freed: usize = (0..9).map(|_| scanner.scan(&mut vms, &mut mem, &ranges, 1).unwrap()).sum();
+        assert_eq!(freed, 2);
+        assert_eq!(scanner.shared_frames(), 2);
+        assert_eq!(frame(tree(&vms, 2), &mem, GUEST), frame(tree(&vms, 0), &mem, GUEST + 2 * PAGE));
+        assert_eq!(frame(tree(&vms, 2), &mem, GUEST + 2 * PAGE), frame(tree(&vms, 0), &mem, GUEST + PAGE));
+
+        /* The pass is over, the next one starts from the top */
+        assert_eq!(scanner.cursor, 0);
+        assert_eq!(scanner.scan(&mut vms, &mut mem, &ranges, 2).unwrap(), 0);
+        assert_eq!(scanner.cursor, 2);
+    }
+}
@@ -0,0 +1,424 @@
+/*
+ * Stage 2 dirty page logging.
+ *
+ * While a range of guest memory is logged, every writable page in it
+ * is write-protected at stage 2 and marked with a software bit.  The
+ * guest's first write to such a page takes a permission fault,
+ * handle_write_fault() records the page in the dirty bitmap and gives
+ * the write access back, and the guest retries the write.
+ *
+ * If the CPU manages dirty state itself (VTCR_EL2.HD), the pages get
+ * the DBM bit too and the MMU gives the write access back without a
+ * fault.  Either way a logged page that is writable again is dirty,
+ * so take_dirty_bitmap() collects those pages and write-protects them
+ * for the next round.
+ *
+ * Blocks in the range are split into pages when logging starts, so
+ * that one write does not dirty a whole huge page.  They stay split
+ * until Vm::collapse_huge_pages() merges them again.  RAM that the
+ * guest's faults back while logging is mapped page by page and logged
+ * too, see log_mapping().  Other mappings made while logging are not
+ * logged, and protect_range() ends logging for the pages it makes
+ * read-only.
+ *
+ * With VTCR_EL2.HD the MMU makes a DBM page writable the moment a vCPU
+ * writes to it, so the guest may dirty a page while take_dirty_bitmap()
+ * is write-protecting it.  The descriptor is swapped atomically for the
+ * write-protected one, and a write that lands first fails the swap and
+ * is seen on the next try.  The bitmap is only handed over once the TLB
+ * entries for the pages are gone, so a write through a stale entry
+ * still lands before the caller copies the page.  Everything else only
+ * ever makes logged pages writable, which the MMU cannot race with.
+ */
+
+use crate::lpae::{PageTableTreeStage2, PageTableEntry, TranslationTree, MapError, PTE_SW_LOGGED};
+use crate::phys::PhysicalMemory;
+use crate::memory_attrs::Stage2Access;
+use crate::walk::translate;
+use crate::aarch64::{tlbi_ipas2e1is, tlbi_vmalle1is, data_barrier, Shareable};
+
+/* S2AP[1], the write permission */
+const S2AP_WRITE: u64 = Stage2Access::WriteOnly as u64;
+
+/// The dirty bitmap for [start, start + size) of a guest's IPA space,
+/// one bit per page
+pub struct DirtyLog<'a> {
+    start: u64,
+    size: u64,
+    page_shift: u64,
+    hardware: bool,
+    bitmap: &'a mut [u64],
+}
+
+impl<'a> DirtyLog<'a> {
+    /// The number of u64s needed for the bitmap of `pages` pages
+    pub fn bitmap_words(pages: u64) -> usize {
+        ((pages + 63) / 64) as usize
+    }
+
+    pub fn start(&self) -> u64 {
+        self.start
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Whether the MMU, rather than write faults, marks pages dirty
+    pub fn uses_hardware(&self) -> bool {
+        self.hardware
+    }
+
+    pub fn contains(&self, ipa: u64) -> bool {
+        ipa >= self.start && ipa < self.start + self.size
+    }
+
+    /// The bitmap, for its owner to free once logging has stopped
+    pub fn into_bitmap(self) -> &'a mut [u64] {
+        self.bitmap
+    }
+
+    /// Whether any of [ipa, ipa + size) is logged
+    pub fn overlaps(&self, ipa: u64, size: u64) -> bool {
+        ipa < self.start + self.size && self.start < ipa + size
+    }
+
+    pub fn is_dirty(&self, ipa: u64) -> bool {
+        if !self.contains(ipa) {
+            return false;
+        }
+
+        let page = (ipa - self.start) >> self.page_shift;
+
+        self.bitmap[(page / 64) as usize] & (1 << (page % 64)) != 0
+    }
+
+    /// Mark the pages of [ipa, ipa + size) that are logged dirty
+    pub fn mark(&mut self, ipa: u64, size: u64) -> () {
+        let start = if ipa > self.start { ipa } else { self.start };
+        let end = if ipa + size < self.start + self.size { ipa + size } else { self.start + self.size };
+        let mut address = start;
+
+        while address < end {
+            let page = (address - self.start) >> self.page_shift;
+            self.bitmap[(page / 64) as usize] |= 1 << (page % 64);
+            address += 1 << self.page_shift;
+        }
+    }
+}
+
+/// Write-protect a writable leaf for logging
+fn write_protect(entry: PageTableEntry, hardware: bool) -> PageTableEntry {
+    let mut new = entry;
+
+    if entry.software() & PTE_SW_LOGGED == 0 && entry.s2ap() & S2AP_WRITE == 0 {
+        /* Read-only anyway, the guest cannot dirty it */
+        return entry;
+    }
+
+    new.set_software(entry.software() | PTE_SW_LOGGED);
+    new.set_s2ap(entry.s2ap() & !S2AP_WRITE);
+    new.set_dirty_bit_modifier(hardware);
+    new
+}
+
+/// Returns true if a logged leaf has been written to since it was
+/// last write-protected
+fn is_written(entry: PageTableEntry) -> bool {
+    entry.software() & PTE_SW_LOGGED != 0 && entry.s2ap() & S2AP_WRITE != 0
+}
+
+/// Start logging writes to [start, start + size) of tree, recording
+/// them in `bitmap`, which must hold DirtyLog::bitmap_words() words.
+///
+/// `hardware` should be set if VTCR_EL2.HD is.
+pub fn start_logging<'a>(tree: &mut PageTableTreeStage2,
+                         mem: &mut dyn PhysicalMemory,
+                         start: u64,
+                         size: u64,
+                         hardware: bool,
+                         bitmap: &'a mut [u64]) -> Result<DirtyLog<'a>, MapError> {
+    let page_shift = tree.granule().page_shift();
+
+    assert!(bitmap.len() >= DirtyLog::bitmap_words(size >> page_shift));
+    for word in bitmap.iter_mut() {
+        *word = 0;
+    }
+
+    tree.split_range(mem, start, size)?;
+    tree.update_range(mem, start, size,
+                      &mut |entry, _address, _level| write_protect(entry, hardware))?;
+
+    Ok(DirtyLog {
+        start: start,
+        size: size,
+        page_shift: page_shift,
+        hardware: hardware,
+        bitmap: bitmap,
+    })
+}
+
+/// Log the pages of [ipa, ipa + size), mapped in the logged range since
+/// logging started.  They must have been mapped read-only, so that the
+/// guest cannot write to them unlogged meanwhile, and get their write
+/// access on the first write like every other logged page.
+pub fn log_mapping(tree: &mut PageTableTreeStage2,
+                   mem: &mut dyn PhysicalMemory,
+                   log: &DirtyLog,
+                   ipa: u64,
+                   size: u64) -> Result<(), MapError> {
+    let hardware = log.hardware;
+
+    tree.update_range(mem, ipa, size, &mut |entry, _address, _level| {
+        let mut new = entry;
+        new.set_software(entry.software() | PTE_SW_LOGGED);
+        new.set_dirty_bit_modifier(hardware);
+        new
+    })
+}
+
+/// Handle a stage 2 permission fault on a guest write to ipa.  Returns
+/// false if logging did not cause the fault, which is then the
+/// caller's to deal with.
+pub fn handle_write_fault(tree: &mut PageTableTreeStage2,
+                          mem: &mut dyn PhysicalMemory,
+                          log: &mut DirtyLog,
+                          ipa: u64) -> bool {
+    if !log.contains(ipa) {
+        return false;
+    }
+
+    let translation = match translate(tree, mem, ipa) {
+        Ok(translation) => translation,
+        Err(_) => return false,
+    };
+
+    if translation.descriptor.software() & PTE_SW_LOGGED == 0 {
+        return false;
+    }
+
+    let block = ipa & !(translation.block_size - 1);
+
+    /* Exactly one leaf, so this cannot need a split */
+    tree.update_range(mem, block, translation.block_size, &mut |entry, _address, _level| {
+        let mut new = entry;
+        new.set_s2ap(entry.s2ap() | S2AP_WRITE);
+        new
+    }).unwrap();
+
+    log.mark(block, translation.block_size);
+    true
+}
+
+/// Copy the dirty bitmap into `dirty` and clear it, write-protecting
+/// the dirty pages again so that the next round of writes is logged.
+pub fn take_dirty_bitmap(tree: &mut PageTableTreeStage2,
+                         mem: &mut dyn PhysicalMemory,
+                         log: &mut DirtyLog,
+                         dirty: &mut [u64]) -> () {
+    let granule = tree.granule();
+    let (start, size, hardware) = (log.start, log.size, log.hardware);
+
+    assert!(dirty.len() >= log.bitmap.len());
+
+    /* Only the permission changes, which needs no break-before-make */
+    tree.for_each_leaf(mem, start, size, &mut |mem, descriptor, entry, address, level| {
+        let mut entry = entry;
+
+        while is_written(entry) {
+            match mem.compare_exchange_u64(descriptor, entry.0, write_protect(entry, hardware).0) {
+                Ok(_) => {
+                    log.mark(address, granule.level_size(level));
+                    tlbi_ipas2e1is(address);
+                },
+                Err(current) => entry = PageTableEntry(current),
+            }
+        }
+    });
+
+    data_barrier(Shareable::Inner);
+    tlbi_vmalle1is();
+
+    for (word, dirty) in log.bitmap.iter_mut().zip(dirty.iter_mut()) {
+        *dirty = *word;
+        *word = 0;
+    }
+}
+
+/// Stop logging, giving every logged page its write access back
+pub fn stop_logging(tree: &mut PageTableTreeStage2,
+                    mem: &mut dyn PhysicalMemory,
+                    log: &DirtyLog) -> Result<(), MapError> {
+    tree.update_range(mem, log.start, log.size, &mut |entry, _address, _level| {
+        if entry.software() & PTE_SW_LOGGED == 0 {
+            return entry;
+        }
+
+        let mut new = entry;
+        new.set_software(entry.software() & !PTE_SW_LOGGED);
+        new.set_s2ap(entry.s2ap() | S2AP_WRITE);
+        new.set_dirty_bit_modifier(false);
+        new
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lpae::{Granule, PhysAddrRange, PTE_ACCESS_FLAG_SHIFT};
+    use crate::memory_attrs::Stage2Flags;
+    use crate::phys::SimulatedMemory;
+
+    const PAGE: u64 = 0x1000;
+    const GUEST: u64 = 0x4000_0000;
+
+    fn guest(mem: &mut SimulatedMemory) -> PageTableTreeStage2 {
+        let granule = Granule::Kb4;
+        let mut tree = PageTableTreeStage2::new(mem, granule, PhysAddrRange::new(0b0010, granule)).unwrap();
+
+        /* A 2MB block, followed by writable and read-only pages */
+        tree.map_range(mem, GUEST, GUEST, 0x20_0000, Stage2Flags::normal()).unwrap();
+        tree.map_range(mem, GUEST + 0x20_0000, GUEST + 0x20_0000, 4 * PAGE,
+                       Stage2Flags::normal()).unwrap();
+        tree.map_range(mem, GUEST + 0x20_4000, GUEST + 0x20_4000, PAGE,
+                       Stage2Flags::normal().read_only()).unwrap();
+        tree
+    }
+
+    fn s2ap(tree: &PageTableTreeStage2, mem: &SimulatedMemory, ipa: u64) -> u64 {
+        translate(tree, mem, ipa).unwrap().descriptor.s2ap()
+    }
+
+    #[test]
+    fn write_faults_are_logged() {
+        let mut mem = SimulatedMemory::new(0x1000_0000, 0x10_0000);
+        let mut tree = guest(&mut mem);
+        let size = 0x20_0000 + 5 * PAGE;
+        let mut bitmap = [0xffff; 9];
+        let mut log = start_logging(&mut tree, &mut mem, GUEST, size, false, &mut bitmap).unwrap();
+
+        assert_eq!(s2ap(&tree, &mem, GUEST + 0x20_1000), Stage2Access::ReadOnly as u64);
+        assert_eq!(s2ap(&tree, &mem, GUEST + 0x1234), Stage2Access::ReadOnly as u64);
+        assert!(!log.is_dirty(GUEST + 0x20_1000));
+
+        /* Read-only pages were never the logging's to write-protect */
+        assert!(!handle_write_fault(&mut tree, &mut mem, &mut log, GUEST + 0x20_4000));
+        assert!(!handle_write_fault(&mut tree, &mut mem, &mut log, GUEST + 0x30_0000));
+
+        assert!(handle_write_fault(&mut tree, &mut mem, &mut log, GUEST + 0x20_1008));
+        assert_eq!(s2ap(&tree, &mem, GUEST + 0x20_1000), Stage2Access::ReadWrite as u64);
+        assert!(log.is_dirty(GUEST + 0x20_1000));
+        assert!(!log.is_dirty(GUEST + 0x20_2000));
+
+        /* The block was split, so a write to it dirties one page */
+        assert_eq!(translate(&tree, &mem, GUEST + 0x1_0000).unwrap().level, 3);
+        assert!(handle_write_fault(&mut tree, &mut mem, &mut log, GUEST + 0x1_0000));
+        assert!(log.is_dirty(GUEST + 0x1_0000) && !log.is_dirty(GUEST + 0x1_1000));
+
+        let mut dirty = [0; 9];
+        take_dirty_bitmap(&mut tree, &mut mem, &mut log, &mut dirty);
+        assert_eq!(dirty[0], 1 << 16);
+        assert_eq!(dirty[1..8], [0; 7]);
+        assert_eq!(dirty[8], 0b10);
+        assert!(!log.is_dirty(GUEST + 0x20_1000));
+        assert_eq!(s2ap(&tree, &mem, GUEST + 0x20_1000), Stage2Access::ReadOnly as u64);
+
+        /* Outside the log, nothing is dirty */
+        assert!(!log.is_dirty(GUEST - PAGE));
+        assert!(!log.is_dirty(GUEST + size));
+
+        stop_logging(&mut tree, &mut mem, &log).unwrap();
+        assert_eq!(s2ap(&tree, &mem, GUEST + 0x20_1000), Stage2Access::ReadWrite as u64);
+        assert_eq!(s2ap(&tree, &mem, GUEST + 0x20_4000), Stage2Access::ReadOnly as u64);
+        assert_eq!(translate(&tree, &mem, GUEST + 0x20_1000).unwrap().descriptor.software(), 0);
+    }
+
+    #[test]
+    fn hardware_dirty_state_is_collected() {
+        let mut mem = SimulatedMemory::new(0x1000_0000, 0x10_0000);
+        let mut tree = guest(&mut mem);
+        let mut bitmap = [0; 1];
+        let mut log = start_logging(&mut tree, &mut mem, GUEST + 0x20_0000, 4 * PAGE,
+                                    true, &mut bitmap).unwrap();
+
+        let page = translate(&tree, &mem, GUEST + 0x20_2000).unwrap();
+        assert!(page.descriptor.is_dirty_bit_modifier());
+
+        /* What the MMU does on a write to a DBM page */
+        let mut written = page.descriptor;
+        written.set_s2ap(Stage2Access::ReadWrite as u64);
+        tree.update_range(&mut mem, GUEST + 0x20_2000, PAGE, &mut |_entry, _address, _level| written).unwrap();
+
+        let mut dirty = [0; 1];
+        take_dirty_bitmap(&mut tree, &mut mem, &mut log, &mut dirty);
+        assert_eq!(dirty[0], 0b100);
+        assert_eq!(s2ap(&tree, &mem, GUEST + 0x20_2000), Stage2Access::ReadOnly as u64);
+
+        take_dirty_bitmap(&mut tree, &mut mem, &mut log, &mut dirty);
+        assert_eq!(dirty[0], 0);
+    }
+
+    /* Memory where the MMU updates one descriptor just before the first
+     * compare-and-swap on it */
+    struct RacingMemory {
+        mem: SimulatedMemory,
+        descriptor: u64,
+        set: u64,
+    }
+
+    impl PhysicalMemory for RacingMemory {
+        fn read_u64(&self, address: u64) -> u64 {
+            self.mem.read_u64(address)
+        }
+
+        fn write_u64(&mut self, address: u64, value: u64) -> () {
+            self.mem.write_u64(address, value)
+        }
+
+        fn compare_exchange_u64(&mut self, address: u64, current: u64, new: u64) -> Result<u64, u64> {
+            if address == self.descriptor && self.set != 0 {
+                let word = self.mem.read_u64(address);
+                self.mem.write_u64(address, word | self.set);
+                self.set = 0;
+            }
+            self.mem.compare_exchange_u64(address, current, new)
+        }
+
+        fn alloc_frames(&mut self, size: u64, align: u64) -> Option<u64> {
+            self.mem.alloc_frames(size, align)
+        }
+
+        fn free_frames(&mut self, address: u64, size: u64) -> () {
+            self.mem.free_frames(address, size)
+        }
+    }
+
+    #[test]
+    fn hardware_updates_are_not_lost() {
+        let mut mem = SimulatedMemory::new(0x1000_0000, 0x10_0000);
+        let mut tree = guest(&mut mem);
+        let mut bitmap = [0; 1];
+        let mut log = start_logging(&mut tree, &mut mem, GUEST + 0x20_0000, 4 * PAGE,
+                                    true, &mut bitmap).unwrap();
+
+        /* Written, and not yet accessed as far as the access flag goes */
+        let mut written = translate(&tree, &mem, GUEST + 0x20_1000).unwrap().descriptor;
+        written.set_s2ap(Stage2Access::ReadWrite as u64);
+        written.set_access_flag(false);
+        tree.update_range(&mut mem, GUEST + 0x20_1000, PAGE, &mut |_entry, _address, _level| written).unwrap();
+
+        let mut descriptor = 0;
+        tree.for_each_leaf(&mut mem, GUEST + 0x20_1000, PAGE,
+                           &mut |_mem, address, _entry, _ipa, _level| descriptor = address);
+
+        let mut mem = RacingMemory { mem: mem, descriptor: descriptor, set: 1 << PTE_ACCESS_FLAG_SHIFT };
+
+        let mut dirty = [0; 1];
+        take_dirty_bitmap(&mut tree, &mut mem, &mut log, &mut dirty);
+        assert_eq!(dirty[0], 0b10);
+
+        let page = translate(&tree, &mem.mem, GUEST + 0x20_1000).unwrap().descriptor;
+        assert!(page.is_access_flag());
+        assert_eq!(page.s2ap(), Stage2Access::ReadOnly as u64);
+    }
+}
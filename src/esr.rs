@@ -10,7 +10,7 @@ use crate::uart::uart_write;
 use crate::aarch64::ExceptionLevel;
 
 const ESR_ELx_EC_UNKNOWN: u64 = (0x00);
-const ESR_ELx_EC_WFx: u64 =  (0x01);
+pub const ESR_ELx_EC_WFx: u64 =  (0x01);
 /* Unallocated EC: 0x02 */
 const ESR_ELx_EC_CP15_32: u64 = (0x03);
 const ESR_ELx_EC_CP15_64: u64 = (0x04);
@@ -36,12 +36,12 @@ const ESR_ELx_EC_SVE: u64 =  (0x19);
 /* Unallocated EC: 0x1A - 0x1E */
 const ESR_ELx_EC_ERET: u64 = 0b011010;
 const ESR_ELx_EC_IMP_DEF: u64 = (0x1f); /* EL3 only */
-const ESR_ELx_EC_IABT_LOW: u64 = (0x20);
-const ESR_ELx_EC_IABT_CUR: u64 = (0x21);
+pub const ESR_ELx_EC_IABT_LOW: u64 = (0x20);
+pub const ESR_ELx_EC_IABT_CUR: u64 = (0x21);
 const ESR_ELx_EC_PC_ALIGN: u64 = (0x22);
 /* Unallocated EC: 0x23 */
-const ESR_ELx_EC_DABT_LOW: u64 = (0x24);
-const ESR_ELx_EC_DABT_CUR: u64 = (0x25);
+pub const ESR_ELx_EC_DABT_LOW: u64 = (0x24);
+pub const ESR_ELx_EC_DABT_CUR: u64 = (0x25);
 const ESR_ELx_EC_SP_ALIGN: u64 = (0x26);
 /* Unallocated EC: 0x27 */
 const ESR_ELx_EC_FP_EXC32: u64 = (0x28);
@@ -64,7 +64,7 @@ const ESR_ELx_EC_BRK64: u64 = (0x3C);
 /* Unallocated EC: 0x3D - 0x3F */
 const ESR_ELx_EC_MAX: u64 =  (0x3F);
 
-const ESR_ELx_EC_SHIFT: u64 = (26);
+pub const ESR_ELx_EC_SHIFT: u64 = (26);
 const ESR_ELx_EC_MASK: u64 =  ((0x3F) << ESR_ELx_EC_SHIFT);
 
 pub const fn esr_elx_ec(esr: u64) -> u64 {
@@ -72,14 +72,31 @@ pub const fn esr_elx_ec(esr: u64) -> u64 {
 }
 
 const ESR_ELx_IL_SHIFT: u64 = (25);
-const ESR_ELx_IL: u64 =  ((1) << ESR_ELx_IL_SHIFT);
+pub const ESR_ELx_IL: u64 =  ((1) << ESR_ELx_IL_SHIFT);
 const ESR_ELx_ISS_MASK: u64 = (ESR_ELx_IL - 1);
 
-/*
 /* ISS field definitions shared by different classes */
-const ESR_ELx_WNR_SHIFT: u64 = (6)
-const ESR_ELx_WNR: u64 =  ((1) << ESR_ELx_WNR_SHIFT)
+const ESR_ELx_WNR_SHIFT: u64 = 6;
+pub const ESR_ELx_WNR: u64 = bit(ESR_ELx_WNR_SHIFT);
+
+/* Shared ISS field definitions for Data/Instruction aborts */
+const ESR_ELx_S1PTW_SHIFT: u64 = 7;
+pub const ESR_ELx_S1PTW: u64 = bit(ESR_ELx_S1PTW_SHIFT);
 
+/* Shared ISS fault status code(IFSC/DFSC) for Data/Instruction aborts */
+pub const ESR_ELx_FSC: u64 = 0x3F;
+pub const ESR_ELx_FSC_TYPE: u64 = 0x3C;
+pub const ESR_ELx_FSC_EXTABT: u64 = 0x10;
+pub const ESR_ELx_FSC_ACCESS: u64 = 0x08;
+pub const ESR_ELx_FSC_FAULT: u64 = 0x04;
+pub const ESR_ELx_FSC_PERM: u64 = 0x0C;
+
+/// The fault status code of a Data or Instruction Abort, without the level
+pub const fn esr_elx_fsc_type(esr: u64) -> u64 {
+    esr & ESR_ELx_FSC_TYPE
+}
+
+/*
 /* Asynchronous Error Type */
 const ESR_ELx_IDS_SHIFT: u64 = (24)
 const ESR_ELx_IDS: u64 =  ((1) << ESR_ELx_IDS_SHIFT)
@@ -99,17 +116,9 @@ const ESR_ELx_FnV_SHIFT: u64 = (10)
 const ESR_ELx_FnV: u64 =  ((1) << ESR_ELx_FnV_SHIFT)
 const ESR_ELx_EA_SHIFT: u64 = (9)
 const ESR_ELx_EA: u64 =  ((1) << ESR_ELx_EA_SHIFT)
-const ESR_ELx_S1PTW_SHIFT: u64 = (7)
-const ESR_ELx_S1PTW: u64 =  ((1) << ESR_ELx_S1PTW_SHIFT)
 
 /* Shared ISS fault status code(IFSC/DFSC) for Data/Instruction aborts */
-const ESR_ELx_FSC: u64 =  (0x3F)
-const ESR_ELx_FSC_TYPE: u64 = (0x3C)
-const ESR_ELx_FSC_EXTABT: u64 = (0x10)
 const ESR_ELx_FSC_SERROR: u64 = (0x11)
-const ESR_ELx_FSC_ACCESS: u64 = (0x08)
-const ESR_ELx_FSC_FAULT: u64 = (0x04)
-const ESR_ELx_FSC_PERM: u64 = (0x0C)
 
 /* ISS field definitions for Data Aborts */
 const ESR_ELx_ISV_SHIFT: u64 = (24)
@@ -0,0 +1,261 @@
+/*
+ * Just enough of a flattened device tree (DTB) reader to find the
+ * memory that the boot loader or firmware has set aside: the entries
+ * of the memory reservation block, and the `reg` of each child of
+ * /reserved-memory.  Children that only give a `size` are for the OS
+ * to place, and are not reservations yet.
+ *
+ * Everything in a DTB is big-endian.
+ */
+
+const FDT_MAGIC: u32 = 0xd00d_feed;
+
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_NOP: u32 = 4;
+const FDT_END: u32 = 9;
+
+/* Header fields, as byte offsets */
+const FDT_TOTALSIZE: usize = 4;
+const FDT_OFF_DT_STRUCT: usize = 8;
+const FDT_OFF_DT_STRINGS: usize = 12;
+const FDT_OFF_MEM_RSVMAP: usize = 16;
+const FDT_HEADER_SIZE: usize = 40;
+
+/* The defaults for #address-cells and #size-cells */
+const DEFAULT_ADDRESS_CELLS: usize = 2;
+const DEFAULT_SIZE_CELLS: usize = 1;
+
+pub struct Fdt<'a> {
+    blob: &'a [u8],
+}
+
+impl<'a> Fdt<'a> {
+    /// Returns the DTB in blob, if it is one
+    pub fn new(blob: &'a [u8]) -> Option<Fdt<'a>> {
+        if blob.len() < FDT_HEADER_SIZE || be32(blob, 0) != FDT_MAGIC {
+            return None;
+        }
+
+        if (be32(blob, FDT_TOTALSIZE) as usize) > blob.len() {
+            return None;
+        }
+
+        Some(Fdt { blob: &blob[..be32(blob, FDT_TOTALSIZE) as usize] })
+    }
+
+    /// Returns the DTB at a physical address, if there is one there
+    pub unsafe fn at(address: u64) -> Option<Fdt<'static>> {
+        let header = core::slice::from_raw_parts(address as *const u8, FDT_HEADER_SIZE);
+
+        if be32(header, 0) != FDT_MAGIC {
+            return None;
+        }
+
+        Fdt::new(core::slice::from_raw_parts(address as *const u8,
+                                             be32(header, FDT_TOTALSIZE) as usize))
+    }
+
+    pub fn total_size(&self) -> u64 {
+        self.blob.len() as u64
+    }
+
+    /// Calls f with the address and size of each memory reservation
+    /// block entry
+    pub fn for_each_mem_reservation(&self, f: &mut dyn FnMut(u64, u64)) -> () {
+        let mut offset = be32(self.blob, FDT_OFF_MEM_RSVMAP) as usize;
+
+        loop {
+            let address = be64(self.blob, offset);
+            let size = be64(self.blob, offset + 8);
+
+            if address == 0 && size == 0 {
+                break;
+            }
+
+            f(address, size);
+            offset += 16;
+        }
+    }
+
+    /// Calls f with the address and size of each range a child of
+    /// /reserved-memory reserves
+    pub fn for_each_reserved_memory(&self, f: &mut dyn FnMut(u64, u64)) -> () {
+        let strings = be32(self.blob, FDT_OFF_DT_STRINGS) as usize;
+        let mut offset = be32(self.blob, FDT_OFF_DT_STRUCT) as usize;
+        let mut depth = 0;
+        let mut in_reserved_memory = false;
+        let mut cells = (DEFAULT_ADDRESS_CELLS, DEFAULT_SIZE_CELLS);
+
+        loop {
+            let token = be32(self.blob, offset);
+            offset += 4;
+
+            match token {
+                FDT_BEGIN_NODE => {
+                    let name = cstr(self.blob, offset);
+                    offset = align4(offset + name.len() + 1);
+                    depth += 1;
+
+                    if depth == 2 && (name == b"reserved-memory" || name.starts_with(b"reserved-memory@")) {
+                        in_reserved_memory = true;
+                    }
+                },
+                FDT_END_NODE => {
+                    if depth == 2 {
+                        in_reserved_memory = false;
+                    }
+                    depth -= 1;
+                },
+                FDT_PROP => {
+                    let len = be32(self.blob, offset) as usize;
+                    let name = cstr(self.blob, strings + be32(self.blob, offset + 4) as usize);
+                    let value = &self.blob[offset + 8..offset + 8 + len];
+                    offset = align4(offset + 8 + len);
+
+                    if !in_reserved_memory {
+                        continue;
+                    }
+
+                    /* The node's own properties come before its children */
+                    if depth == 2 && name == b"#address-cells" {
+                        cells.0 = be32(value, 0) as usize;
+                    } else if depth == 2 && name == b"#size-cells" {
+                        cells.1 = be32(value, 0) as usize;
+                    } else if depth == 3 && name == b"reg" {
+                        let entry = (cells.0 + cells.1) * 4;
+
+                        for reg in value.chunks(entry).filter(|reg| reg.len() == entry) {
+                            f(read_cells(reg, 0, cells.0), read_cells(reg, cells.0 * 4, cells.1));
+                        }
+                    }
+                },
+                FDT_NOP => (),
+                FDT_END => break,
+                _ => break,
+            }
+        }
+    }
+}
+
+fn be32(blob: &[u8], offset: usize) -> u32 {
+    (blob[offset] as u32) << 24 | (blob[offset + 1] as u32) << 16 |
+    (blob[offset + 2] as u32) << 8 | blob[offset + 3] as u32
+}
+
+fn be64(blob: &[u8], offset: usize) -> u64 {
+    (be32(blob, offset) as u64) << 32 | be32(blob, offset + 4) as u64
+}
+
+/// A number made of `cells` 32-bit cells
+fn read_cells(blob: &[u8], offset: usize, cells: usize) -> u64 {
+    (0..cells).fold(0, |value, cell| value << 32 | be32(blob, offset + cell * 4) as u64)
+}
+
+/// The NUL-terminated string at offset, without the NUL
+fn cstr(blob: &[u8], offset: usize) -> &[u8] {
+    let len = blob[offset..].iter().position(|&byte| byte == 0).unwrap_or(blob.len() - offset);
+
+    &blob[offset..offset + len]
+}
+
+fn align4(offset: usize) -> usize {
+    (offset + 3) & !3
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn push32(blob: &mut Vec<u8>, value: u32) {
+        blob.extend_from_slice(&[(value >> 24) as u8, (value >> 16) as u8, (value >> 8) as u8, value as u8]);
+    }
+
+    fn begin_node(blob: &mut Vec<u8>, name: &str) {
+        push32(blob, FDT_BEGIN_NODE);
+        blob.extend_from_slice(name.as_bytes());
+        blob.push(0);
+        while blob.len() % 4 != 0 {
+            blob.push(0);
+        }
+    }
+
+    fn prop(blob: &mut Vec<u8>, strings: &mut Vec<u8>, name: &str, cells: &[u32]) {
+        push32(blob, FDT_PROP);
+        push32(blob, (cells.len() * 4) as u32);
+        push32(blob, strings.len() as u32);
+        strings.extend_from_slice(name.as_bytes());
+        strings.push(0);
+        for &cell in cells {
+            push32(blob, cell);
+        }
+    }
+
+    /*
+     * / {
+     *     reserved-memory {
+     *         #address-cells = <2>; #size-cells = <2>;
+     *         secmon@4ff00000 { reg = <0 0x4ff00000 0 0x100000>; };
+     *         pool { size = <0 0x400000>; };
+     *     };
+     *     memory@40000000 { reg = <0 0x40000000 0 0x8000000>; };
+     * };
+     */
+    fn dtb() -> Vec<u8> {
+        let mut structure = Vec::new();
+        let mut strings = Vec::new();
+
+        begin_node(&mut structure, "");
+        begin_node(&mut structure, "reserved-memory");
+        prop(&mut structure, &mut strings, "#address-cells", &[2]);
+        prop(&mut structure, &mut strings, "#size-cells", &[2]);
+        begin_node(&mut structure, "secmon@4ff00000");
+        prop(&mut structure, &mut strings, "reg", &[0, 0x4ff0_0000, 0, 0x10_0000]);
+        push32(&mut structure, FDT_END_NODE);
+        begin_node(&mut structure, "pool");
+        prop(&mut structure, &mut strings, "size", &[0, 0x40_0000]);
+        push32(&mut structure, FDT_END_NODE);
+        push32(&mut structure, FDT_END_NODE);
+        begin_node(&mut structure, "memory@40000000");
+        prop(&mut structure, &mut strings, "reg", &[0, 0x4000_0000, 0, 0x800_0000]);
+        push32(&mut structure, FDT_END_NODE);
+        push32(&mut structure, FDT_END_NODE);
+        push32(&mut structure, FDT_END);
+
+        let rsvmap = FDT_HEADER_SIZE;
+        let struct_offset = rsvmap + 32;
+        let strings_offset = struct_offset + structure.len();
+        let total = strings_offset + strings.len();
+
+        let mut blob = Vec::new();
+        for &field in [FDT_MAGIC, total as u32, struct_offset as u32, strings_offset as u32,
+                       rsvmap as u32, 17, 16, 0, strings.len() as u32, structure.len() as u32].iter() {
+            push32(&mut blob, field);
+        }
+        for &word in [0, 0x4000_0000, 0, 0x1_0000, 0, 0, 0, 0].iter() {
+            push32(&mut blob, word);
+        }
+        blob.extend_from_slice(&structure);
+        blob.extend_from_slice(&strings);
+        blob
+    }
+
+    #[test]
+    fn reservations_are_found() {
+        let blob = dtb();
+        let fdt = Fdt::new(&blob).unwrap();
+        let mut found = Vec::new();
+
+        assert_eq!(fdt.total_size(), blob.len() as u64);
+
+        fdt.for_each_mem_reservation(&mut |address, size| found.push((address, size)));
+        assert_eq!(found, [(0x4000_0000, 0x1_0000)]);
+
+        found.clear();
+        fdt.for_each_reserved_memory(&mut |address, size| found.push((address, size)));
+        assert_eq!(found, [(0x4ff0_0000, 0x10_0000)]);
+
+        assert!(Fdt::new(&blob[4..]).is_none());
+    }
+}
@@ -1,22 +1,429 @@
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicU64, Ordering};
+
 use crate::lpae::PAGE_SIZE;
+use crate::phys::PhysicalMemory;
+use crate::scrub::DirtyFrames;
+use crate::memmap::MemoryMap;
+use crate::spinlock::{SpinLock, SpinLockGuard};
+
+pub const MEMORY_START: u64 = 0x40000000;
+pub const MEMORY_SIZE: u64 =   0x8000000;
+pub const MEMORY_END: u64 =   MEMORY_START + MEMORY_SIZE;
+
+const MAX_BANKS: usize = 8;
+
+/*
+ * RAM comes in up to MAX_BANKS banks, which need not be contiguous.
+ * Each has a bitmap with one bit per 4KB frame, set while the frame is
+ * in use, so any run of frames can be freed and handed out again, at
+ * any alignment: 8KB for a concatenated stage 2 root, 2MB for a block.
+ *
+ * Allocations are first fit, bank by bank.  Frames released by guests
+ * stay in use until they have been scrubbed, see scrub.rs.
+ */
+#[derive(Copy, Clone, Debug)]
+struct Bank {
+    start: u64,
+    frames: usize,
+    free: usize,
+    bitmap: *mut u64,
+}
+
+impl Bank {
+    fn end(&self) -> u64 {
+        self.start + (self.frames * PAGE_SIZE) as u64
+    }
+
+    fn contains(&self, address: u64) -> bool {
+        address >= self.start && address < self.end()
+    }
+
+    fn frame(&self, address: u64) -> usize {
+        ((address - self.start) / PAGE_SIZE as u64) as usize
+    }
+
+    fn is_used(&self, frame: usize) -> bool {
+        unsafe { *self.bitmap.add(frame / 64) & (1 << (frame % 64)) != 0 }
+    }
+
+    /// Mark `count` frames from `first` used or free
+    fn set(&mut self, first: usize, count: usize, used: bool) -> () {
+        for frame in first..first + count {
+            assert!(self.is_used(frame) != used, "frame {:#x} is already {}",
+                    self.start + (frame * PAGE_SIZE) as u64, if used { "used" } else { "free" });
 
-const MEMORY_START: u64 = 0x40000000;
-const MEMORY_SIZE: u64 =   0x8000000;
-const MEMORY_END: u64 =   MEMORY_START + MEMORY_SIZE;
+            unsafe { *self.bitmap.add(frame / 64) ^= 1 << (frame % 64); }
+        }
+
+        if used {
+            self.free -= count;
+        } else {
+            self.free += count;
+        }
+    }
+
+    /// The first run of `count` free frames that starts at an address
+    /// aligned to `align`
+    fn find(&self, count: usize, align: u64) -> Option<usize> {
+        let step = (align / PAGE_SIZE as u64) as usize;
+        let mut first = self.frame((self.start + align - 1) & !(align - 1));
+
+        if count > self.free {
+            return None;
+        }
+
+        while first + count <= self.frames {
+            match (first..first + count).find(|&frame| self.is_used(frame)) {
+                None => return Some(first),
+                /* The next aligned run that starts beyond the used frame */
+                Some(used) => first += ((used - first) / step + 1) * step,
+            }
+        }
+
+        None
+    }
+}
+
+/// What the frame allocator has to hand out, in bytes
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct FrameStats {
+    pub banks: usize,
+    pub total: u64,
+    pub free: u64,
+
+    /// Released by guests and waiting to be scrubbed, see scrub.rs
+    pub dirty: u64,
+}
 
 pub struct FrameAllocator {
-    bottom: u64,
+    banks: [Option<Bank>; MAX_BANKS],
+    dirty: DirtyFrames,
 }
 
 impl FrameAllocator {
-    pub fn new(bottom: u64) -> FrameAllocator {
-        FrameAllocator { bottom: bottom }
+    pub fn new() -> FrameAllocator {
+        FrameAllocator { banks: [None; MAX_BANKS], dirty: DirtyFrames::new() }
+    }
+
+    /// The bytes of bitmap a bank of `size` bytes needs
+    pub fn bitmap_size(size: u64) -> u64 {
+        let frames = size / PAGE_SIZE as u64;
+
+        (frames + 63) / 64 * 8
+    }
+
+    /// Add the RAM at [start, start + size), all of it free, with its
+    /// bitmap at `bitmap`, which must hold bitmap_size(size) bytes.
+    pub fn add_bank_with_bitmap(&mut self, start: u64, size: u64, bitmap: *mut u64) -> () {
+        assert_eq!((start | size) % PAGE_SIZE as u64, 0);
+        assert!(self.banks.iter()
+                .filter_map(|bank| *bank)
+                .all(|bank| start + size <= bank.start || start >= bank.end()));
+
+        let frames = (size / PAGE_SIZE as u64) as usize;
+        unsafe {
+            core::ptr::write_bytes(bitmap, 0, (frames + 63) / 64);
+        }
+
+        let slot = self.banks.iter().position(|bank| bank.is_none()).unwrap();
+        self.banks[slot] = Some(Bank { start: start, frames: frames, free: frames, bitmap: bitmap });
+    }
+
+    /// The bytes of whole frames a bank of `size` bytes needs for its bitmap
+    pub fn bitmap_frames(size: u64) -> u64 {
+        (FrameAllocator::bitmap_size(size) + PAGE_SIZE as u64 - 1) & !(PAGE_SIZE as u64 - 1)
+    }
+
+    /// Add the RAM at [start, start + size).  Its bitmap goes in its last
+    /// frames, away from the DTB and images that boot loaders put at the
+    /// start of RAM, and those frames are never handed out.  Returns
+    /// where the bitmap went, and the bytes it took.
+    pub fn add_bank(&mut self, start: u64, size: u64) -> (u64, u64) {
+        let bitmap = start + size - FrameAllocator::bitmap_frames(size);
+
+        (bitmap, self.add_bank_at(start, size, bitmap))
+    }
+
+    /// Add the RAM at [start, start + size), with its bitmap in the frames
+    /// at `bitmap`, a page in the bank.  Those frames are never handed
+    /// out.  Returns the bytes the bitmap took.
+    pub fn add_bank_at(&mut self, start: u64, size: u64, bitmap: u64) -> u64 {
+        let bitmap_frames = FrameAllocator::bitmap_frames(size);
+
+        assert!(bitmap >= start && bitmap + bitmap_frames <= start + size);
+
+        self.add_bank_with_bitmap(start, size, bitmap as *mut u64);
+        self.reserve(bitmap, bitmap_frames);
+
+        bitmap_frames
+    }
+
+    /// Never hand out the frames in [address, address + size).  Parts of
+    /// the range outside every bank are ignored.
+    pub fn reserve(&mut self, address: u64, size: u64) -> () {
+        let start = address & !(PAGE_SIZE as u64 - 1);
+        let end = (address + size + PAGE_SIZE as u64 - 1) & !(PAGE_SIZE as u64 - 1);
+
+        for bank in self.banks.iter_mut().filter_map(|bank| bank.as_mut()) {
+            let first = if start > bank.start { start } else { bank.start };
+            let last = if end < bank.end() { end } else { bank.end() };
+
+            for frame in (first..last).step_by(PAGE_SIZE) {
+                let index = bank.frame(frame);
+
+                if !bank.is_used(index) {
+                    bank.set(index, 1, true);
+                }
+            }
+        }
+    }
+
+    /// Returns the physical address of a new 4KB frame, or None if
+    /// we have run out of RAM.
+    ///
+    /// The frame is NOT zeroed.
+    pub fn alloc_frame(&mut self) -> Option<u64> {
+        self.alloc_frames(PAGE_SIZE as u64, PAGE_SIZE as u64)
+    }
+
+    /// Returns the physical address of `size` bytes of contiguous frames
+    /// aligned to `align`, which must be a power of two multiple of 4KB.
+    ///
+    /// The frames are NOT zeroed.
+    pub fn alloc_frames(&mut self, size: u64, align: u64) -> Option<u64> {
+        assert!(align.is_power_of_two() && align >= PAGE_SIZE as u64);
+        assert_eq!(size % PAGE_SIZE as u64, 0);
+
+        if let Some(frames) = self.alloc_clean_frames(size, align) {
+            return Some(frames);
+        }
+
+        /*
+         * Out of clean frames, the dirty ones cannot wait for idle time.
+         * Scrub only until the request fits, a request's worth at a time.
+         */
+        while self.dirty.pending() != 0 {
+            self.scrub_dirty_frames(size);
+
+            if let Some(frames) = self.alloc_clean_frames(size, align) {
+                return Some(frames);
+            }
+        }
+
+        None
+    }
+
+    fn alloc_clean_frames(&mut self, size: u64, align: u64) -> Option<u64> {
+        let count = (size / PAGE_SIZE as u64) as usize;
+
+        for bank in self.banks.iter_mut().filter_map(|bank| bank.as_mut()) {
+            if let Some(first) = bank.find(count, align) {
+                bank.set(first, count, true);
+                return Some(bank.start + (first * PAGE_SIZE) as u64);
+            }
+        }
+
+        None
+    }
+
+    /// Give back the frames at [address, address + size), which must
+    /// no longer be mapped anywhere
+    pub fn free_frames(&mut self, address: u64, size: u64) -> () {
+        assert_eq!((address | size) % PAGE_SIZE as u64, 0);
+
+        let bank = self.banks.iter_mut()
+            .filter_map(|bank| bank.as_mut())
+            .find(|bank| bank.contains(address))
+            .expect("freeing frames outside RAM");
+
+        assert!(bank.contains(address + size - 1));
+        let first = bank.frame(address);
+        bank.set(first, (size / PAGE_SIZE as u64) as usize, false);
+    }
+
+    /// Queue frames that held guest data to be scrubbed before reuse
+    pub fn release_frames(&mut self, address: u64, size: u64) -> () {
+        let mut dirty = self.dirty;
+
+        dirty.push(self, address, size);
+        self.dirty = dirty;
+    }
+
+    /// Scrub and free at least `budget` bytes of released frames, if
+    /// there are that many, for idle time.  Returns the bytes scrubbed.
+    pub fn scrub_dirty_frames(&mut self, budget: u64) -> u64 {
+        let mut dirty = self.dirty;
+        let done = dirty.scrub(self, budget);
+
+        self.dirty = dirty;
+        done
+    }
+
+    /// The bytes of released frames still waiting to be scrubbed
+    pub fn dirty_bytes(&self) -> u64 {
+        self.dirty.pending()
+    }
+
+    pub fn stats(&self) -> FrameStats {
+        let banks = self.banks.iter().filter_map(|bank| *bank);
+
+        FrameStats {
+            banks: banks.clone().count(),
+            total: banks.clone().map(|bank| (bank.frames * PAGE_SIZE) as u64).sum(),
+            free: banks.map(|bank| (bank.free * PAGE_SIZE) as u64).sum(),
+            dirty: self.dirty.pending(),
+        }
+    }
+}
+
+/* The bitmaps are only ever reached through the lock */
+unsafe impl Send for FrameAllocator {}
+
+static FRAME_ALLOCATOR: SpinLock<Option<FrameAllocator>> = SpinLock::new(None);
+
+/// Set up the hypervisor's frame allocator to hand out the RAM in map,
+/// bar what it reserves
+pub fn init(map: &mut MemoryMap) -> () {
+    let mut allocator = FrameAllocator::new();
+
+    map.seed(&mut allocator);
+    *FRAME_ALLOCATOR.lock() = Some(allocator);
+}
+
+/// Exclusive use of the hypervisor's frame allocator, see
+/// frame_allocator()
+pub struct FrameAllocatorGuard(SpinLockGuard<'static, Option<FrameAllocator>>);
+
+impl Deref for FrameAllocatorGuard {
+    type Target = FrameAllocator;
+
+    fn deref(&self) -> &FrameAllocator {
+        self.0.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for FrameAllocatorGuard {
+    fn deref_mut(&mut self) -> &mut FrameAllocator {
+        self.0.as_mut().unwrap()
+    }
+}
+
+/// The hypervisor's frame allocator, see init().  Other CPUs wait for
+/// it until the guard is dropped, so keep it no longer than needed, and
+/// never allocate from the heap meanwhile, see spinlock.rs.
+pub fn frame_allocator() -> FrameAllocatorGuard {
+    FrameAllocatorGuard(FRAME_ALLOCATOR.lock())
+}
+
+/*
+ * The hypervisor identity maps all of RAM (and runs with the MMU off
+ * before that), so a physical address can be dereferenced directly.
+ */
+impl PhysicalMemory for FrameAllocator {
+    fn read_u64(&self, address: u64) -> u64 {
+        unsafe { core::ptr::read_volatile(address as *const u64) }
+    }
+
+    fn write_u64(&mut self, address: u64, value: u64) -> () {
+        unsafe { core::ptr::write_volatile(address as *mut u64, value) }
+    }
+
+    fn read_u8(&self, address: u64) -> u8 {
+        unsafe { core::ptr::read_volatile(address as *const u8) }
+    }
+
+    fn write_u8(&mut self, address: u64, value: u8) -> () {
+        unsafe { core::ptr::write_volatile(address as *mut u8, value) }
+    }
+
+    fn compare_exchange_u64(&mut self, address: u64, current: u64, new: u64) -> Result<u64, u64> {
+        let word = unsafe { &*(address as *const AtomicU64) };
+
+        word.compare_exchange(current, new, Ordering::SeqCst, Ordering::SeqCst)
     }
 
-    pub fn alloc_frame(&mut self) -> u64 {
-        let tmp = self.bottom;
-        self.bottom += PAGE_SIZE as u64;
-        
-        tmp
+    fn alloc_frames(&mut self, size: u64, align: u64) -> Option<u64> {
+        FrameAllocator::alloc_frames(self, size, align)
+    }
+
+    fn free_frames(&mut self, address: u64, size: u64) -> () {
+        FrameAllocator::free_frames(self, address, size)
+    }
+
+    fn release_frames(&mut self, address: u64, size: u64) -> () {
+        FrameAllocator::release_frames(self, address, size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PAGE: u64 = PAGE_SIZE as u64;
+    const MB: u64 = 1 << 20;
+
+    /* The frames are never touched, only the bitmaps, which live on the host */
+    fn bank(allocator: &mut FrameAllocator, start: u64, size: u64) -> () {
+        let words = (FrameAllocator::bitmap_size(size) / 8) as usize;
+        let bitmap = Box::leak(vec![!0u64; words].into_boxed_slice());
+
+        allocator.add_bank_with_bitmap(start, size, bitmap.as_mut_ptr());
+    }
+
+    #[test]
+    fn frames_are_aligned_freed_and_reused() {
+        let mut allocator = FrameAllocator::new();
+        bank(&mut allocator, 0x4000_0000, 4 * MB);
+        bank(&mut allocator, 0x8000_0000, 2 * MB);
+        allocator.reserve(0x4000_0000, 0x1_0800);
+
+        assert_eq!(allocator.stats(), FrameStats { banks: 2, total: 6 * MB, free: 6 * MB - 0x1_1000, dirty: 0 });
+
+        assert_eq!(allocator.alloc_frame(), Some(0x4001_1000));
+        assert_eq!(allocator.alloc_frames(2 * PAGE, 2 * PAGE), Some(0x4001_2000));
+        assert_eq!(allocator.alloc_frame(), Some(0x4001_4000));
+
+        /* A 2MB block skips the used frames to the next 2MB boundary */
+        assert_eq!(allocator.alloc_frames(2 * MB, 2 * MB), Some(0x4020_0000));
+
+        /* Nothing left in the first bank that is big enough */
+        assert_eq!(allocator.alloc_frames(2 * MB, 2 * MB), Some(0x8000_0000));
+        assert_eq!(allocator.alloc_frames(2 * MB, PAGE), None);
+
+        allocator.free_frames(0x4020_0000, 2 * MB);
+        allocator.free_frames(0x4001_1000, PAGE);
+        assert_eq!(allocator.alloc_frame(), Some(0x4001_1000));
+        assert_eq!(allocator.alloc_frames(MB, MB), Some(0x4010_0000));
+
+        let free = allocator.stats().free;
+        allocator.free_frames(0x4001_2000, 2 * PAGE);
+        assert_eq!(allocator.stats().free, free + 2 * PAGE);
+    }
+
+    #[test]
+    fn allocations_scrub_only_what_they_need() {
+        /* Scrubbing writes to the frames, so they live on the host */
+        let memory = Box::leak(vec![0u8; 9 * PAGE as usize].into_boxed_slice());
+        let start = (memory.as_ptr() as u64 + PAGE - 1) & !(PAGE - 1);
+        let mut allocator = FrameAllocator::new();
+        bank(&mut allocator, start, 8 * PAGE);
+
+        let frames: Vec<u64> = (0..8).map(|_| allocator.alloc_frame().unwrap()).collect();
+        for &frame in frames.iter() {
+            allocator.write_u64(frame + 0x100, 0x5ec7e7);
+        }
+        for &frame in frames.iter().step_by(2).take(3) {
+            allocator.release_frames(frame, PAGE);
+        }
+
+        /* One run is enough for one frame, and it comes back zeroed */
+        let frame = allocator.alloc_frame().unwrap();
+        assert_eq!(allocator.dirty_bytes(), 2 * PAGE);
+        assert_eq!(allocator.read_u64(frame + 0x100), 0);
+
+        /* No two runs are adjacent, so the rest do not fit two frames */
+        assert_eq!(allocator.alloc_frames(2 * PAGE, PAGE), None);
+        assert_eq!(allocator.dirty_bytes(), 0);
     }
 }
@@ -0,0 +1,309 @@
+/*
+ * Access to guest memory.
+ *
+ * Device emulation and hypercalls need to read and write guest
+ * buffers.  The hypervisor never trusts an address from the guest: an
+ * IPA is looked up in the VM's stage 2 tree, one page or block at a
+ * time since neighbouring guest pages are rarely neighbouring frames,
+ * and the copy only goes ahead where stage 2 lets the guest make the
+ * same access.  A guest virtual address is first translated with the
+ * guest's own stage 1 tables.
+ *
+ * Writes go through the VM as the guest's own would: a page that is
+ * shared by dedup.rs is copied before it is written, and a page that is
+ * logged by dirty.rs is marked dirty.
+ *
+ * The guest may run with its caches off, so the frames are cleaned and
+ * invalidated to the Point of Coherency around every copy.
+ */
+
+use crate::lpae::{PageTableTreeStage2, PageTableEntry, PTE_SW_LOGGED, PTE_SW_SHARED};
+use crate::phys::PhysicalMemory;
+use crate::memory_attrs::{Stage2Access, Stage2MemoryType};
+use crate::walk::translate;
+use crate::vm::Vm;
+use crate::aarch64::{at_s1e1, dcache_clean_invalidate_poc};
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum GuestAccessError {
+    /// The IPA is not mapped at stage 2
+    NotMapped(u64),
+
+    /// Stage 2 does not allow the access, or maps a device there
+    PermissionDenied(u64),
+
+    /// The guest's stage 1 does not map the virtual address
+    TranslationFault(u64),
+
+    /// No frame was left for a private copy of a shared page
+    OutOfMemory(u64),
+}
+
+/* The smallest page a guest's stage 1 can map */
+const GUEST_PAGE_SIZE: u64 = 0x1000;
+
+const S2AP_WRITE: u64 = Stage2Access::WriteOnly as u64;
+
+/// Returns the physical address of ipa, the number of bytes from there
+/// to the end of the page or block mapping it and its descriptor, if
+/// the guest may access it the way we want to.  A page that is only
+/// write-protected to log or share it counts as writable.
+fn guest_frame(tree: &PageTableTreeStage2,
+               mem: &dyn PhysicalMemory,
+               ipa: u64,
+               write: bool) -> Result<(u64, u64, PageTableEntry), GuestAccessError> {
+    let translation = match translate(tree, mem, ipa) {
+        Ok(translation) => translation,
+        Err(_) => return Err(GuestAccessError::NotMapped(ipa)),
+    };
+
+    let descriptor = translation.descriptor;
+    let allowed = if write {
+        descriptor.s2ap() & S2AP_WRITE != 0 ||
+        descriptor.software() & (PTE_SW_LOGGED | PTE_SW_SHARED) != 0
+    } else {
+        descriptor.s2ap() & Stage2Access::ReadOnly as u64 != 0
+    };
+
+    /* Reads from device registers have side effects, never copy them */
+    let device = match Stage2MemoryType::from_mem_attr(descriptor.mem_attr()) {
+        Some(memory_type) => memory_type.is_device(),
+        None => true,
+    };
+
+    if !allowed || device {
+        return Err(GuestAccessError::PermissionDenied(ipa));
+    }
+
+    let left = translation.block_size - (ipa & (translation.block_size - 1));
+    Ok((translation.output_address, left, descriptor))
+}
+
+/// Check that the guest could make the access to all of [ipa, ipa + len)
+fn check_range(tree: &PageTableTreeStage2,
+               mem: &dyn PhysicalMemory,
+               ipa: u64,
+               len: usize,
+               write: bool) -> Result<(), GuestAccessError> {
+    let mut done = 0;
+
+    while done < len {
+        let (_, left, _) = guest_frame(tree, mem, ipa + done as u64, write)?;
+        done += if left < (len - done) as u64 { left as usize } else { len - done };
+    }
+
+    Ok(())
+}
+
+/// Copy guest memory at [ipa, ipa + buf.len()) into buf
+pub fn copy_from_guest(vm: &Vm,
+                       mem: &dyn PhysicalMemory,
+                       ipa: u64,
+                       buf: &mut [u8]) -> Result<(), GuestAccessError> {
+    let mut done = 0;
+
+    while done < buf.len() {
+        let (paddr, left, _) = guest_frame(&vm.stage2, mem, ipa + done as u64, false)?;
+        let chunk = if left < (buf.len() - done) as u64 { left as usize } else { buf.len() - done };
+
+        /* Drop stale lines, in case the guest wrote with its caches off */
+        dcache_clean_invalidate_poc(paddr, chunk as u64);
+
+        for i in 0..chunk {
+            buf[done + i] = mem.read_u8(paddr + i as u64);
+        }
+
+        done += chunk;
+    }
+
+    Ok(())
+}
+
+/// Copy buf into guest memory at [ipa, ipa + buf.len()).  Nothing is
+/// written unless the guest may write to the whole range.
+///
+/// The write is treated as the guest's own: a shared page gets a
+/// private copy first and a logged page is marked dirty.
+pub fn copy_to_guest(vm: &mut Vm,
+                     mem: &mut dyn PhysicalMemory,
+                     ipa: u64,
+                     buf: &[u8]) -> Result<(), GuestAccessError> {
+    let mut done = 0;
+
+    /* Check the whole range first, so a failed copy leaves no trace */
+    check_range(&vm.stage2, mem, ipa, buf.len(), true)?;
+
+    /* Take the write faults the guest would have taken */
+    while done < buf.len() {
+        let address = ipa + done as u64;
+        let (_, left, descriptor) = guest_frame(&vm.stage2, mem, address, true)?;
+
+        if descriptor.s2ap() & S2AP_WRITE == 0 && !vm.handle_write_fault(mem, address) {
+            return Err(GuestAccessError::OutOfMemory(address));
+        }
+
+        done += if left < (buf.len() - done) as u64 { left as usize } else { buf.len() - done };
+    }
+
+    done = 0;
+    while done < buf.len() {
+        let address = ipa + done as u64;
+        let (paddr, left, descriptor) = guest_frame(&vm.stage2, mem, address, true)?;
+        let chunk = if left < (buf.len() - done) as u64 { left as usize } else { buf.len() - done };
+
+        for i in 0..chunk {
+            mem.write_u8(paddr + i as u64, buf[done + i]);
+        }
+
+        dcache_clean_invalidate_poc(paddr, chunk as u64);
+
+        /* Hardware dirty state only tracks the guest's own writes */
+        if descriptor.software() & PTE_SW_LOGGED != 0 {
+            if let Some(log) = vm.dirty_log.as_mut() {
+                log.mark(address, chunk as u64);
+            }
+        }
+
+        done += chunk;
+    }
+
+    Ok(())
+}
+
+/// Calls `f` with the IPA and length of each piece of [va, va + len)
+/// that lies within one guest page
+fn for_each_guest_page(va: u64,
+                       len: usize,
+                       write: bool,
+                       f: &mut dyn FnMut(u64, usize, usize) -> Result<(), GuestAccessError>)
+                       -> Result<(), GuestAccessError> {
+    let mut done = 0;
+
+    while done < len {
+        let address = va + done as u64;
+        let left = (GUEST_PAGE_SIZE - (address & (GUEST_PAGE_SIZE - 1))) as usize;
+        let chunk = if left < len - done { left } else { len - done };
+
+        let ipa = match at_s1e1(address, write) {
+            Some(ipa) => ipa,
+            None => return Err(GuestAccessError::TranslationFault(address)),
+        };
+
+        f(ipa, done, chunk)?;
+        done += chunk;
+    }
+
+    Ok(())
+}
+
+/// Copy guest memory at guest virtual address [va, va + buf.len()) into
+/// buf.  The guest's stage 1 must be the one loaded in the EL1
+/// registers, i.e. the guest must be the one that trapped.
+pub fn copy_from_guest_va(vm: &Vm,
+                          mem: &dyn PhysicalMemory,
+                          va: u64,
+                          buf: &mut [u8]) -> Result<(), GuestAccessError> {
+    for_each_guest_page(va, buf.len(), false, &mut |ipa, offset, len| {
+        copy_from_guest(vm, mem, ipa, &mut buf[offset..offset + len])
+    })
+}
+
+/// Copy buf into guest memory at guest virtual address
+/// [va, va + buf.len()), see copy_from_guest_va().  Every page is
+/// translated and checked before any is written.
+pub fn copy_to_guest_va(vm: &mut Vm,
+                        mem: &mut dyn PhysicalMemory,
+                        va: u64,
+                        buf: &[u8]) -> Result<(), GuestAccessError> {
+    for_each_guest_page(va, buf.len(), true, &mut |ipa, _offset, len| {
+        check_range(&vm.stage2, mem, ipa, len, true)
+    })?;
+
+    for_each_guest_page(va, buf.len(), true, &mut |ipa, offset, len| {
+        copy_to_guest(vm, mem, ipa, &buf[offset..offset + len])
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lpae::{Granule, PhysAddrRange};
+    use crate::memory_attrs::Stage2Flags;
+    use crate::phys::SimulatedMemory;
+    use crate::dirty;
+
+    const PAGE: u64 = 0x1000;
+
+    #[test]
+    fn copies_cross_pages_and_check_permissions() {
+        let granule = Granule::Kb4;
+        let mut mem = SimulatedMemory::new(0x1000_0000, 0x10_0000);
+        let mut tree = PageTableTreeStage2::new(&mut mem, granule,
+                                                PhysAddrRange::new(0b0010, granule)).unwrap();
+
+        /* Two neighbouring guest pages in frames that are far apart */
+        let (low, high) = (0x1008_0000, 0x1004_0000);
+        tree.map_range(&mut mem, 0x4000_0000, low, PAGE, Stage2Flags::normal()).unwrap();
+        tree.map_range(&mut mem, 0x4000_1000, high, PAGE, Stage2Flags::normal()).unwrap();
+        tree.map_range(&mut mem, 0x4000_2000, 0x1009_0000, PAGE,
+                       Stage2Flags::normal().read_only()).unwrap();
+        tree.map_range(&mut mem, 0x0900_0000, 0x0900_0000, PAGE, Stage2Flags::device()).unwrap();
+        let mut vm = Vm::new(tree);
+
+        let data: Vec<u8> = (0..16).collect();
+        copy_to_guest(&mut vm, &mut mem, 0x4000_0ff9, &data).unwrap();
+        assert_eq!(mem.read_u8(low + 0xff9), 0);
+        assert_eq!(mem.read_u8(low + 0xfff), 6);
+        assert_eq!(mem.read_u8(high), 7);
+        assert_eq!(mem.read_u8(high + 8), 15);
+
+        let mut back = [0; 16];
+        copy_from_guest(&vm, &mem, 0x4000_0ff9, &mut back).unwrap();
+        assert_eq!(back[..], data[..]);
+
+        /* Read-only memory can be read, not written, even in part */
+        let before = mem.read_u64(high + 0xff8);
+        assert_eq!(copy_to_guest(&mut vm, &mut mem, 0x4000_1ffc, &data),
+                   Err(GuestAccessError::PermissionDenied(0x4000_2000)));
+        assert_eq!(mem.read_u64(high + 0xff8), before);
+        assert!(copy_from_guest(&vm, &mem, 0x4000_1ffc, &mut back).is_ok());
+
+        assert_eq!(copy_from_guest(&vm, &mem, 0x0900_0000, &mut back),
+                   Err(GuestAccessError::PermissionDenied(0x0900_0000)));
+        assert_eq!(copy_from_guest(&vm, &mem, 0x4000_2ff8, &mut back),
+                   Err(GuestAccessError::NotMapped(0x4000_3000)));
+    }
+
+    #[test]
+    fn writes_to_logged_pages_are_logged() {
+        let granule = Granule::Kb4;
+        let mut mem = SimulatedMemory::new(0x1000_0000, 0x10_0000);
+        let mut tree = PageTableTreeStage2::new(&mut mem, granule,
+                                                PhysAddrRange::new(0b0010, granule)).unwrap();
+
+        tree.map_range(&mut mem, 0x4000_0000, 0x1008_0000, 4 * PAGE, Stage2Flags::normal()).unwrap();
+        let mut vm = Vm::new(tree);
+
+        let bitmap = Box::leak(vec![0; 1].into_boxed_slice());
+        let log = dirty::start_logging(&mut vm.stage2, &mut mem, 0x4000_0000, 4 * PAGE,
+                                       false, bitmap).unwrap();
+        vm.dirty_log = Some(log);
+
+        /* Write-protected for the log, but the guest could write it */
+        let data = [0xa5; 16];
+        copy_to_guest(&mut vm, &mut mem, 0x4000_1ff8, &data).unwrap();
+        assert_eq!(mem.read_u8(0x1008_1ff8), 0xa5);
+        assert_eq!(mem.read_u8(0x1008_2007), 0xa5);
+
+        let log = vm.dirty_log.as_ref().unwrap();
+        assert!(!log.is_dirty(0x4000_0000));
+        assert!(log.is_dirty(0x4000_1000));
+        assert!(log.is_dirty(0x4000_2000));
+        assert!(!log.is_dirty(0x4000_3000));
+
+        /* Reads leave the log alone */
+        let mut back = [0; 16];
+        copy_from_guest(&vm, &mem, 0x4000_2ff8, &mut back).unwrap();
+        assert!(!vm.dirty_log.as_ref().unwrap().is_dirty(0x4000_3000));
+    }
+}
@@ -0,0 +1,227 @@
+/*
+ * The hypervisor's heap, so that Box, Vec and BTreeMap can be used.
+ *
+ * Small objects come from slabs: frames cut up into objects of one
+ * size class, a power of two from 16 to 2048 bytes.  The free objects
+ * of each class are on a list linked through their first word, and an
+ * empty list is refilled with a whole frame from the frame allocator.
+ * Since a frame is page aligned, an object is aligned to its class's
+ * size, so an alignment is met by picking a class at least that big.
+ * Slab frames are kept for reuse by the same class, never given back.
+ *
+ * Anything bigger than the largest class is given whole frames of its
+ * own, which go back to the frame allocator when it is freed.
+ *
+ * The heap is behind a spinlock of its own, so any CPU can use it.  Most
+ * allocations are slab hits that take nothing else; refilling a slab
+ * and big objects take the frame allocator's lock instead, never both.
+ * Code holding the frame allocator must still not allocate or free, in
+ * case that is what it needs, see spinlock.rs, and an exception handler
+ * must not if what it interrupted may hold either lock.
+ */
+
+use core::alloc::{GlobalAlloc, Layout};
+use core::ptr;
+
+use crate::lpae::PAGE_SIZE;
+use crate::frame_alloc::{FrameAllocator, frame_allocator};
+use crate::spinlock::SpinLock;
+
+const SIZE_CLASSES: usize = 8;
+const MIN_OBJECT_SHIFT: usize = 4;
+const MAX_OBJECT_SIZE: usize = 1 << (MIN_OBJECT_SHIFT + SIZE_CLASSES - 1);
+
+pub struct SlabHeap {
+    free: [usize; SIZE_CLASSES],
+}
+
+impl SlabHeap {
+    pub const fn new() -> SlabHeap {
+        SlabHeap { free: [0; SIZE_CLASSES] }
+    }
+
+    /// The smallest size class that fits layout, if any does
+    fn class(layout: Layout) -> Option<usize> {
+        let size = if layout.size() > layout.align() { layout.size() } else { layout.align() };
+
+        if size > MAX_OBJECT_SIZE {
+            return None;
+        }
+
+        (0..SIZE_CLASSES).find(|class| size <= 1 << (MIN_OBJECT_SHIFT + class))
+    }
+
+    /// The whole frames that a layout too big for the slabs gets
+    fn frames(layout: Layout) -> (u64, u64) {
+        let size = (layout.size() + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+        let align = if layout.align() > PAGE_SIZE { layout.align() } else { PAGE_SIZE };
+
+        (size as u64, align as u64)
+    }
+
+    /// Whole frames for a layout too big for the slabs, or null
+    fn alloc_frames(frames: &mut FrameAllocator, layout: Layout) -> *mut u8 {
+        let (size, align) = SlabHeap::frames(layout);
+
+        match frames.alloc_frames(size, align) {
+            Some(address) => address as *mut u8,
+            None => ptr::null_mut(),
+        }
+    }
+
+    /// A free object of class, if the slab has one
+    fn take(&mut self, class: usize) -> Option<*mut u8> {
+        let object = self.free[class];
+
+        if object == 0 {
+            return None;
+        }
+
+        self.free[class] = unsafe { *(object as *const usize) };
+        Some(object as *mut u8)
+    }
+
+    /// Put a free object of class back on its list
+    fn put(&mut self, class: usize, object: *mut u8) -> () {
+        unsafe { *(object as *mut usize) = self.free[class]; }
+        self.free[class] = object as usize;
+    }
+
+    /// Cut frame up into objects of class, for the slab to hand out
+    fn refill(&mut self, class: usize, frame: u64) -> () {
+        let size = 1 << (MIN_OBJECT_SHIFT + class);
+        let frame = frame as usize;
+
+        /* Thread the new objects onto the list, lowest first */
+        for object in (frame..frame + PAGE_SIZE).step_by(size).rev() {
+            self.put(class, object as *mut u8);
+        }
+    }
+
+    /// Returns a new object for layout, or null if memory has run out
+    pub fn alloc(&mut self, frames: &mut FrameAllocator, layout: Layout) -> *mut u8 {
+        let class = match SlabHeap::class(layout) {
+            Some(class) => class,
+            None => return SlabHeap::alloc_frames(frames, layout),
+        };
+
+        if self.free[class] == 0 {
+            match frames.alloc_frame() {
+                Some(frame) => self.refill(class, frame),
+                None => return ptr::null_mut(),
+            }
+        }
+
+        self.take(class).unwrap()
+    }
+
+    /// Give back an object that alloc() returned for layout
+    pub fn dealloc(&mut self, frames: &mut FrameAllocator, object: *mut u8, layout: Layout) -> () {
+        match SlabHeap::class(layout) {
+            Some(class) => self.put(class, object),
+            None => {
+                let (size, _) = SlabHeap::frames(layout);
+                frames.free_frames(object as u64, size);
+            },
+        }
+    }
+}
+
+/// A SlabHeap behind a spinlock, drawing on the hypervisor's frame
+/// allocator.  The two locks are never held together: the frame
+/// allocator is only taken, on its own, to refill a slab or for a big
+/// object.
+pub struct Heap {
+    slabs: SpinLock<SlabHeap>,
+}
+
+impl Heap {
+    pub const fn new() -> Heap {
+        Heap { slabs: SpinLock::new(SlabHeap::new()) }
+    }
+}
+
+unsafe impl GlobalAlloc for Heap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let class = match SlabHeap::class(layout) {
+            Some(class) => class,
+            None => return SlabHeap::alloc_frames(&mut frame_allocator(), layout),
+        };
+
+        if let Some(object) = self.slabs.lock().take(class) {
+            return object;
+        }
+
+        /* Another CPU may refill the slab meanwhile, the spare objects just wait */
+        let frame = match frame_allocator().alloc_frame() {
+            Some(frame) => frame,
+            None => return ptr::null_mut(),
+        };
+
+        let mut slabs = self.slabs.lock();
+        slabs.refill(class, frame);
+        slabs.take(class).unwrap()
+    }
+
+    unsafe fn dealloc(&self, object: *mut u8, layout: Layout) {
+        match SlabHeap::class(layout) {
+            Some(class) => self.slabs.lock().put(class, object),
+            None => {
+                let (size, _) = SlabHeap::frames(layout);
+                frame_allocator().free_frames(object as u64, size);
+            },
+        }
+    }
+}
+
+/* The host's allocator is the one the tests run with */
+#[cfg(not(test))]
+#[global_allocator]
+static HEAP: Heap = Heap::new();
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PAGE: u64 = PAGE_SIZE as u64;
+
+    /* Frames that really are memory, since the slabs are written through */
+    fn frames(size: usize) -> FrameAllocator {
+        let buffer = Box::leak(vec![0u8; size + PAGE_SIZE].into_boxed_slice());
+        let start = (buffer.as_ptr() as u64 + PAGE - 1) & !(PAGE - 1);
+        let mut allocator = FrameAllocator::new();
+
+        allocator.add_bank(start, size as u64);
+        allocator
+    }
+
+    #[test]
+    fn small_objects_share_frames_and_big_ones_get_their_own() {
+        let mut frames = frames(64 * PAGE_SIZE);
+        let mut heap = SlabHeap::new();
+        let free = frames.stats().free;
+
+        let a = heap.alloc(&mut frames, Layout::from_size_align(24, 8).unwrap());
+        let b = heap.alloc(&mut frames, Layout::from_size_align(24, 8).unwrap());
+        assert_eq!(b as usize - a as usize, 32);
+        assert_eq!(frames.stats().free, free - PAGE);
+
+        /* Alignment picks a bigger class, with its own frame */
+        let c = heap.alloc(&mut frames, Layout::from_size_align(8, 256).unwrap());
+        assert_eq!(c as usize % 256, 0);
+        assert_eq!(frames.stats().free, free - 2 * PAGE);
+
+        heap.dealloc(&mut frames, a, Layout::from_size_align(24, 8).unwrap());
+        assert_eq!(heap.alloc(&mut frames, Layout::from_size_align(20, 4).unwrap()), a);
+
+        let big = Layout::from_size_align(3 * PAGE_SIZE + 1, 8).unwrap();
+        let d = heap.alloc(&mut frames, big);
+        assert_eq!(d as u64 % PAGE, 0);
+        assert_eq!(frames.stats().free, free - 6 * PAGE);
+
+        heap.dealloc(&mut frames, d, big);
+        assert_eq!(frames.stats().free, free - 2 * PAGE);
+
+        assert!(heap.alloc(&mut frames, Layout::from_size_align(128 * PAGE_SIZE, 8).unwrap()).is_null());
+    }
+}
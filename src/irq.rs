@@ -1,8 +1,26 @@
 use crate::uart::uart_write;
-use crate::esr::print_exception_syndrome;
-use crate::aarch64::{current_el, ExceptionLevel};
-use crate::mrs;
+use crate::esr::{
+    print_exception_syndrome,
+    esr_elx_ec,
+    esr_elx_fsc_type,
+    ESR_ELx_EC_DABT_LOW,
+    ESR_ELx_EC_DABT_CUR,
+    ESR_ELx_EC_IABT_LOW,
+    ESR_ELx_EC_IABT_CUR,
+    ESR_ELx_EC_WFx,
+    ESR_ELx_EC_SHIFT,
+    ESR_ELx_IL,
+    ESR_ELx_FSC_EXTABT,
+    ESR_ELx_FSC_PERM,
+    ESR_ELx_FSC_FAULT,
+    ESR_ELx_S1PTW,
+    ESR_ELx_WNR,
+};
+use crate::aarch64::{current_el, at_s1e1, hpfar_ipa, ExceptionLevel};
+use crate::{msr, mrs};
 use crate::common::print_hex;
+use crate::vm::vms;
+use crate::frame_alloc::frame_allocator;
 
 fn print_spsr_el2() -> () {
 
@@ -72,3 +90,136 @@ pub extern fn irq_handler() -> ! {
     loop {}
 }
 
+/// The registers saved by the entry macro in head.S
+#[repr(C)]
+pub struct ExceptionFrame {
+    pub esr: u64,
+    pub hcr: u64,
+    pub spsr: u64,
+    pub elr: u64,
+    pub regs: [u64; 31],
+    padding: u64,
+}
+
+const PAGE_OFFSET_MASK: u64 = 0xfff;
+
+/// Returns the IPA of the guest access that caused a stage 2 Data Abort,
+/// or None if the guest's stage 1 translation has changed under us and
+/// the access should just be retried.
+fn fault_ipa(esr: u64) -> Option<u64> {
+    let far: u64;
+
+    mrs!(far, "FAR_EL2");
+
+    /*
+     * HPFAR_EL2 is only valid for permission faults on a stage 1 table
+     * walk, see Linux's __get_fault_info().  Otherwise look the address
+     * up with the guest's own stage 1 translation.
+     */
+    if esr_elx_fsc_type(esr) != ESR_ELx_FSC_PERM || esr & ESR_ELx_S1PTW != 0 {
+        return Some(hpfar_ipa() | (far & PAGE_OFFSET_MASK));
+    }
+
+    at_s1e1(far, false)
+}
+
+/* The most a trapped WFI spends on scrubbing freed frames */
+const IDLE_SCRUB_BUDGET: u64 = 0x10_0000;
+
+/// The guest has nothing to do, so do our own housekeeping
+fn handle_guest_idle() -> () {
+    let mut vms = vms();
+    let mut allocator = frame_allocator();
+
+    vms.idle(&mut *allocator);
+    allocator.scrub_dirty_frames(IDLE_SCRUB_BUDGET);
+}
+
+/// Returns true if a stage 2 abort was one the hypervisor expects, in
+/// which case the guest can retry the access
+fn handle_guest_abort(esr: u64) -> bool {
+    let mut vms = vms();
+    let vm = match vms.current() {
+        Some(vm) => vm,
+        None => return false,
+    };
+
+    let ipa = match fault_ipa(esr) {
+        Some(ipa) => ipa,
+        None => return true,
+    };
+
+    match esr_elx_fsc_type(esr) {
+        ESR_ELx_FSC_FAULT => vm.handle_translation_fault(&mut *frame_allocator(), ipa),
+        ESR_ELx_FSC_PERM if esr & ESR_ELx_WNR != 0 => vm.handle_write_fault(&mut *frame_allocator(), ipa),
+        _ => false,
+    }
+}
+
+/* SPSR_ELx.M[3:0] */
+const SPSR_M_MASK: u64 = 0xf;
+#[allow(non_upper_case_globals)]
+const SPSR_M_EL0t: u64 = 0b0000;
+#[allow(non_upper_case_globals)]
+const SPSR_M_EL1t: u64 = 0b0100;
+#[allow(non_upper_case_globals)]
+const SPSR_M_EL1h: u64 = 0b0101;
+
+/* EL1h, with D, A, I and F masked, as on any exception entry */
+const SPSR_EL1_EXCEPTION_ENTRY: u64 = (0xf << 6) | SPSR_M_EL1h;
+
+/// Make the guest take a synchronous external abort on the access that
+/// caused the current exception, as if its memory system had reported
+/// it.  The guest's exception vector runs once we return.
+fn inject_external_abort(frame: &mut ExceptionFrame, instruction: bool) -> () {
+    let far: u64;
+    let vbar: u64;
+
+    let (ec, vector) = match (instruction, frame.spsr & SPSR_M_MASK) {
+        (true, SPSR_M_EL0t) => (ESR_ELx_EC_IABT_LOW, 0x400),
+        (false, SPSR_M_EL0t) => (ESR_ELx_EC_DABT_LOW, 0x400),
+        (true, SPSR_M_EL1t) => (ESR_ELx_EC_IABT_CUR, 0x000),
+        (false, SPSR_M_EL1t) => (ESR_ELx_EC_DABT_CUR, 0x000),
+        (true, _) => (ESR_ELx_EC_IABT_CUR, 0x200),
+        (false, _) => (ESR_ELx_EC_DABT_CUR, 0x200),
+    };
+
+    mrs!(far, "FAR_EL2");
+    mrs!(vbar, "VBAR_EL1");
+
+    msr!("ESR_EL1", (ec << ESR_ELx_EC_SHIFT) | ESR_ELx_IL | ESR_ELx_FSC_EXTABT);
+    msr!("FAR_EL1", far);
+    msr!("ELR_EL1", frame.elr);
+    msr!("SPSR_EL1", frame.spsr);
+
+    frame.elr = vbar + vector;
+    frame.spsr = SPSR_EL1_EXCEPTION_ENTRY;
+}
+
+/// Synchronous exceptions from the guest.  Returning resumes the
+/// guest at ELR_EL2, see the exit macro in head.S.
+///
+/// Aborts the hypervisor cannot resolve are reflected to the guest:
+/// there is no MMIO emulation yet, so an access outside RAM is the
+/// guest's own problem.
+#[no_mangle]
+pub extern fn sync_lower_handler(frame: &mut ExceptionFrame) -> () {
+    let ec = esr_elx_ec(frame.esr);
+
+    if (ec == ESR_ELx_EC_DABT_LOW || ec == ESR_ELx_EC_IABT_LOW) && vms().current().is_some() {
+        if !handle_guest_abort(frame.esr) {
+            inject_external_abort(frame, ec == ESR_ELx_EC_IABT_LOW);
+        }
+        return;
+    }
+
+    if ec == ESR_ELx_EC_WFx && vms().current().is_some() {
+        handle_guest_idle();
+
+        /* Step over the WFI, it may complete early anyway */
+        frame.elr += 4;
+        return;
+    }
+
+    irq_handler();
+}
@@ -8,53 +8,296 @@
  */
 #![allow(dead_code)]
 
+use core::mem::size_of;
+
 use crate::common::{bit, bitfield};
+use crate::phys::PhysicalMemory;
+use crate::memory_attrs::{
+    MapFlags,
+    MemoryType,
+    AccessPermissions,
+    Shareability,
+    Stage2Access,
+    Stage2Flags,
+    Stage2MemoryType,
+};
+use crate::aarch64::{tlbi_vae2is, tlbi_ipas2e1is, tlbi_vmalle1is, data_barrier, Shareable};
 
 pub type VirtualAddress = u64;
 
+/* The smallest granule, and the unit the frame allocator hands out */
 const PAGE_SHIFT: u64 = 12;
 const PAGE_MASK: u64 = (1 << PAGE_SHIFT) - 1;
 pub const PAGE_SIZE: usize = (1 << PAGE_SHIFT) as usize;
 
-const PAGE_ENTRIES_PER_TABLE: usize = 512;
-pub const ADDRESS_SPACE_PER_TABLE: usize = PAGE_SIZE * PAGE_ENTRIES_PER_TABLE;
-
+/* The number of address bits resolved by each level of lookup */
 const PTE_SHIFT_4K: u64 = 9;
-const PTE_SHIFT_16K: u64 = 1;
+const PTE_SHIFT_16K: u64 = 11;
 const PTE_SHIFT_64K: u64 = 13;
-const PTE_SHIFT: u64 = 9;
-const PTE_MASK: u64 = (1 << PTE_SHIFT) - 1;
 
-const THIRD_SHIFT: u64 =  12;
-const THIRD_SIZE: u64 =   1 << THIRD_SHIFT;
-const THIRD_MASK: u64 =   (!(THIRD_SIZE - 1));
+const ALIGN_4K_MASK: u64 = !PAGE_MASK;
 
-const SECOND_SHIFT: u64 = 21;
-const SECOND_SIZE: u64 =  (1 << SECOND_SHIFT);
-const SECOND_MASK: u64 =  (!(SECOND_SIZE - 1));
+/* The size of the EL2 virtual address space, TCR_EL2.T0SZ == 64 - 48 */
+pub const EL2_VA_BITS: u64 = 48;
+
+/* ID_AA64MMFR0_EL1 granule support fields */
+const ID_AA64MMFR0_TGRAN4_SHIFT: u64 = 28;
+const ID_AA64MMFR0_TGRAN64_SHIFT: u64 = 24;
+const ID_AA64MMFR0_TGRAN16_SHIFT: u64 = 20;
+const ID_AA64MMFR0_TGRAN4_2_SHIFT: u64 = 40;
+const ID_AA64MMFR0_TGRAN64_2_SHIFT: u64 = 36;
+const ID_AA64MMFR0_TGRAN16_2_SHIFT: u64 = 32;
+
+/* TGRANx_2 values: as the stage 1 field says, or not supported */
+const ID_AA64MMFR0_TGRAN_2_AS_STAGE1: u64 = 0b0000;
+const ID_AA64MMFR0_TGRAN_2_NONE: u64 = 0b0001;
+
+/* The granule used when the CPU supports it, see Granule::select() */
+#[cfg(feature="granule_16k")]
+const PREFERRED_GRANULE: Granule = Granule::Kb16;
+#[cfg(feature="granule_64k")]
+const PREFERRED_GRANULE: Granule = Granule::Kb64;
+#[cfg(not(any(feature="granule_16k", feature="granule_64k")))]
+const PREFERRED_GRANULE: Granule = Granule::Kb4;
+
+/// The translation granule: the page size, and so the size of every
+/// translation table and the amount of address space each level of
+/// lookup resolves.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Granule {
+    Kb4,
+    Kb16,
+    Kb64,
+}
 
-const FIRST_SHIFT: u64 =  30;
-const FIRST_SIZE: u64 =   (1 << FIRST_SHIFT);
-const FIRST_MASK: u64 =   (!(FIRST_SIZE - 1));
+impl Granule {
+    pub fn page_shift(self) -> u64 {
+        match self {
+            Granule::Kb4 => 12,
+            Granule::Kb16 => 14,
+            Granule::Kb64 => 16,
+        }
+    }
 
-const ZEROETH_SHIFT: u64 = 39;
-const ZEROETH_SIZE: u64 =  (1 << ZEROETH_SHIFT);
-const ZEROETH_MASK: u64 =  (!(ZEROETH_SIZE - 1));
+    pub fn page_size(self) -> u64 {
+        1 << self.page_shift()
+    }
 
-const ALIGN_4K_MASK: u64 = !PAGE_MASK;
+    pub fn mask(self) -> u64 {
+        !(self.page_size() - 1)
+    }
 
-pub enum Alignment {
-    Kb4,
-}
+    /// The number of address bits resolved by one level of lookup
+    pub fn bits_per_level(self) -> u64 {
+        match self {
+            Granule::Kb4 => PTE_SHIFT_4K,
+            Granule::Kb16 => PTE_SHIFT_16K,
+            Granule::Kb64 => PTE_SHIFT_64K,
+        }
+    }
+
+    pub fn entries_per_table(self) -> usize {
+        1 << self.bits_per_level()
+    }
+
+    /// The lowest address bit resolved by a table at `level`
+    pub fn level_shift(self, level: usize) -> u64 {
+        assert!(level <= 3);
+        self.page_shift() + (3 - level as u64) * self.bits_per_level()
+    }
+
+    /// The size of the address range translated by one entry of a table
+    /// at `level`
+    pub fn level_size(self, level: usize) -> u64 {
+        1 << self.level_shift(level)
+    }
 
-impl Alignment {
-    fn mask(self) -> u64 {
+    /// Returns the index into a (non-concatenated) table at `level` for
+    /// address
+    pub fn index(self, address: u64, level: usize) -> usize {
+        ((address >> self.level_shift(level)) as usize) & (self.entries_per_table() - 1)
+    }
+
+    /// Returns true if a block descriptor may be used at `level`.
+    ///
+    /// Level 3 always holds page descriptors.  Level 1 blocks with the
+    /// 16KB and 64KB granules need 52-bit output addresses.
+    pub fn has_blocks_at(self, level: usize) -> bool {
+        match (self, level) {
+            (Granule::Kb4, 1) | (_, 2) | (_, 3) => true,
+            _ => false,
+        }
+    }
+
+    /// The number of adjacent entries at `level` that make up a run
+    /// which may have the contiguous hint set, see D5.3.3 "The
+    /// Contiguous bit".  The run must map a naturally aligned, physically
+    /// contiguous range with identical attributes.
+    pub fn contiguous_entries(self, level: usize) -> usize {
+        match (self, level) {
+            (Granule::Kb4, _) => 16,
+            (Granule::Kb16, 3) => 128,
+            (Granule::Kb16, _) => 32,
+            (Granule::Kb64, _) => 32,
+        }
+    }
+
+    /// The level at which a lookup of an `input_bits` wide address
+    /// starts, when the initial table is not concatenated.
+    pub fn start_level(self, input_bits: u64) -> usize {
+        let resolved = input_bits - self.page_shift();
+        let levels = (resolved + self.bits_per_level() - 1) / self.bits_per_level();
+
+        (4 - levels) as usize
+    }
+
+    /// The number of entries in the initial lookup table at `level`
+    pub fn root_entries(self, input_bits: u64, level: usize) -> usize {
+        1 << (input_bits - self.level_shift(level))
+    }
+
+    /// Returns the start level for a stage 2 lookup of an `ipa_bits` wide
+    /// IPA.
+    ///
+    /// Stage 2 allows up to 16 tables to be concatenated at the initial
+    /// level, so a level of lookup is skipped whenever that is enough to
+    /// cover the IPA space.
+    pub fn stage2_start_level(self, ipa_bits: u64) -> usize {
+        let level = self.start_level(ipa_bits);
+        let next = level + 1;
+
+        if next <= 2 && ipa_bits - self.level_shift(next) <= self.bits_per_level() + 4 {
+            next
+        } else {
+            level
+        }
+    }
+
+    /// The TG0 encoding of TCR_EL2 and VTCR_EL2
+    pub fn tg0(self) -> u64 {
+        match self {
+            Granule::Kb4 => 0b00,
+            Granule::Kb64 => 0b01,
+            Granule::Kb16 => 0b10,
+        }
+    }
+
+    /// The VTCR_EL2.SL0 encoding of a stage 2 start level
+    pub fn sl0(self, start_level: usize) -> u64 {
         match self {
-            Alignment::Kb4 => ALIGN_4K_MASK,
+            Granule::Kb4 => 2 - start_level as u64,
+            Granule::Kb16 | Granule::Kb64 => 3 - start_level as u64,
+        }
+    }
+
+    /// Returns true if ID_AA64MMFR0_EL1 reports support for this granule
+    pub fn is_supported(self, mmfr0: u64) -> bool {
+        match self {
+            // 0b0000 supported, 0b0001 supported with 52-bit addresses
+            Granule::Kb4 => (mmfr0 >> ID_AA64MMFR0_TGRAN4_SHIFT) & 0xf != 0xf,
+
+            // 0b0000 supported
+            Granule::Kb64 => (mmfr0 >> ID_AA64MMFR0_TGRAN64_SHIFT) & 0xf == 0,
+
+            // 0b0001 supported, 0b0010 supported with 52-bit addresses
+            Granule::Kb16 => (mmfr0 >> ID_AA64MMFR0_TGRAN16_SHIFT) & 0xf != 0,
         }
     }
+
+    /// Returns true if ID_AA64MMFR0_EL1 reports support for this granule
+    /// at stage 2, which may differ from stage 1
+    pub fn is_supported_stage2(self, mmfr0: u64) -> bool {
+        let shift = match self {
+            Granule::Kb4 => ID_AA64MMFR0_TGRAN4_2_SHIFT,
+            Granule::Kb64 => ID_AA64MMFR0_TGRAN64_2_SHIFT,
+            Granule::Kb16 => ID_AA64MMFR0_TGRAN16_2_SHIFT,
+        };
+
+        match (mmfr0 >> shift) & 0xf {
+            ID_AA64MMFR0_TGRAN_2_AS_STAGE1 => self.is_supported(mmfr0),
+            ID_AA64MMFR0_TGRAN_2_NONE => false,
+            // 0b0010 supported, 0b0011 supported with 52-bit addresses
+            _ => true,
+        }
+    }
+
+    /// Choose the granule to run with, given ID_AA64MMFR0_EL1.
+    ///
+    /// The same granule is used for the EL2 stage 1 tables and for every
+    /// VM's stage 2 tables, so it must be supported at both stages.  The
+    /// granule selected by the granule_16k/granule_64k features is used
+    /// if the CPU supports it, otherwise the first supported one of 4KB,
+    /// 16KB and 64KB.
+    pub fn select(mmfr0: u64) -> Granule {
+        let candidates = [PREFERRED_GRANULE, Granule::Kb4, Granule::Kb16, Granule::Kb64];
+
+        *candidates.iter()
+                   .find(|granule| granule.is_supported(mmfr0) && granule.is_supported_stage2(mmfr0))
+                   .unwrap()
+    }
 }
 
+/* ID_AA64MMFR0_EL1.PARange, which TCR_EL2.PS and VTCR_EL2.PS share */
+const PARANGE_48_BITS: u64 = 0b0101;
+const PARANGE_52_BITS: u64 = 0b0110;
+
+/// The physical (output) address size the MMU is configured for
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct PhysAddrRange {
+    ps: u64,
+}
+
+impl PhysAddrRange {
+    /// Decode ID_AA64MMFR0_EL1.PARange for use with `granule`.
+    ///
+    /// 52-bit output addresses are only used with the 64KB granule
+    /// (FEAT_LPA).  The 4KB and 16KB granules need the FEAT_LPA2
+    /// descriptor format for them, so are limited to 48 bits.
+    pub fn new(parange: u64, granule: Granule) -> PhysAddrRange {
+        let mut ps = if parange > PARANGE_52_BITS { PARANGE_52_BITS } else { parange };
+
+        if ps == PARANGE_52_BITS && granule != Granule::Kb64 {
+            ps = PARANGE_48_BITS;
+        }
+
+        PhysAddrRange { ps: ps }
+    }
+
+    /// The PS encoding for TCR_EL2 and VTCR_EL2
+    pub fn ps(self) -> u64 {
+        self.ps
+    }
+
+    pub fn bits(self) -> u64 {
+        match self.ps {
+            0b0000 => 32,
+            0b0001 => 36,
+            0b0010 => 40,
+            0b0011 => 42,
+            0b0100 => 44,
+            0b0101 => 48,
+            _ => 52,
+        }
+    }
+
+    pub fn max_address(self) -> u64 {
+        (1 << self.bits()) - 1
+    }
+}
+
+pub fn align(vaddr: VirtualAddress, granule: Granule) -> VirtualAddress {
+    return vaddr & granule.mask();
+}
+
+pub fn align_up(vaddr: VirtualAddress, granule: Granule) -> VirtualAddress {
+    return align(vaddr + granule.page_size() - 1, granule);
+}
+
+pub fn align_4k(addr: u64) -> u64 {
+    return addr & ALIGN_4K_MASK;
+}
+
+
 /**
  * The LPAE page table entry bit layout is as follows:
  *  format: field name       : starting bit, size (in bits)
@@ -64,9 +307,9 @@ impl Alignment {
  *  Table                    : 1, 1
  *
  *  (Used only for Block Entries, ignored in Table Entries)
- *  Attribute Index          : 2, 3
+ *  Attribute Index          : 2, 3   (stage 2: MemAttr, 2, 4)
  *  Non-Secure               : 5, 1
- *  Unprivileged Access      : 6, 1
+ *  Unprivileged Access      : 6, 1   (stage 2: S2AP, 6, 2)
  *  Read-Only                : 7, 1
  *  Shareability             : 8, 2
  *  Access Flag              : 10, 1
@@ -74,7 +317,7 @@ impl Alignment {
  *
  *  (Used by both block and table entries)
  *  Block / table address    : 12, 36
- *  must be zero             : 48, 4
+ *  must be zero             : 48, 4  (64KB granule: OA[51:48], 12, 4)
  *
  *  (Used only for Block Entries, ignored in Table Entries)
  *  is a continiguous entry  : 52, 1
@@ -91,340 +334,2102 @@ impl Alignment {
 
 /* Valid: bit 0 */
 const PTE_VALID_SHIFT: u64 = 0;
-const PTE_VALID_BITS: u64 = 1;
-const PTE_VALID_MASK: u64 = PTE_VALID_BITS << PTE_VALID_SHIFT;
 
 /* Table: bit 1 */
 const PTE_TABLE_SHIFT: u64 = 1;
-const PTE_TABLE_BITS: u64 = 1;
-const PTE_TABLE_MASK: u64 = PTE_TABLE_BITS << PTE_TABLE_SHIFT;
 
 /* Attribute: bits 2..4 */
-const PTE_ATTR_SHIFT: u64  = 2;
+pub const PTE_ATTR_SHIFT: u64  = 2;
 const PTE_ATTR_BITS: u64  = 3;
-const PTE_ATTR_UNSHIFTED_MASK: u64 = (1 << PTE_ATTR_BITS) - 1;
-const PTE_ATTR_MASK: u64  = PTE_ATTR_UNSHIFTED_MASK << PTE_ATTR_SHIFT;
+
+/* Stage 2 MemAttr: bits 2..5 */
+const PTE_S2_MEMATTR_SHIFT: u64 = 2;
+const PTE_S2_MEMATTR_BITS: u64 = 4;
 
 const PTE_NON_SECURE_SHIFT: u64  = 5;
-const PTE_NON_SECURE_BITS: u64   = 1;
-const PTE_NON_SECURE_UNSHIFTED_MASK: u64   = (1 << PTE_NON_SECURE_BITS) - 1;
-const PTE_NON_SECURE_MASK: u64   =  PTE_ATTR_UNSHIFTED_MASK << PTE_ATTR_SHIFT;
-
-const PTE_UNPRIVILIGED_ACCESS_SHIFT: u64  = 6;
-const PTE_UNPRIVILIGED_ACCESS_BITS: u64  = 1;
-const PTE_UNPRIVILIGED_ACCESS_UNSHIFTED_MASK: u64  =
-                        (1 << PTE_UNPRIVILIGED_ACCESS_BITS) - 1;
-const PTE_UNPRIVILIGED_ACCESS_MASK: u64 =
-        PTE_UNPRIVILIGED_ACCESS_UNSHIFTED_MASK << PTE_UNPRIVILIGED_ACCESS_SHIFT;
-
-const PTE_READ_ONLY_SHIFT: u64  = 7;
-const PTE_SHAREABILITY_SHIFT: u64  = 8;
-const PTE_ACCESS_FLAG_SHIFT: u64  = 10;
-const PTE_NOT_GLOBAL_SHIFT: u64  = 11;
-
-/* Common */
-const PTE_ADDRESS_SHIFT: u64 = 12;
-const PTE_ZERO_SHIFT: u64 = 48;
+
+pub const PTE_UNPRIVILIGED_ACCESS_SHIFT: u64  = 6;
+pub const PTE_READ_ONLY_SHIFT: u64  = 7;
+
+/* Stage 2 S2AP: bits 6..7 */
+const PTE_S2AP_SHIFT: u64 = 6;
+const PTE_S2AP_BITS: u64 = 2;
+
+pub const PTE_SHAREABILITY_SHIFT: u64  = 8;
+const PTE_SHAREABILITY_BITS: u64  = 2;
+pub const PTE_ACCESS_FLAG_SHIFT: u64  = 10;
+pub const PTE_NOT_GLOBAL_SHIFT: u64  = 11;
 
 /* Block Entry Only */
-const PTE_CONTIGUOUS_SHIFT: u64 = 52;
+const PTE_DBM_SHIFT: u64 = 51;
+pub const PTE_CONTIGUOUS_SHIFT: u64 = 52;
 const PTE_PRIV_EX_NEVER_SHIFT: u64 = 53;
-const PTE_EX_NEVER_SHIFT: u64 = 54;
-const PTE_IGNORED_SHIFT: u64 = 55;
+pub const PTE_EX_NEVER_SHIFT: u64 = 54;
+const PTE_SOFTWARE_SHIFT: u64 = 55;
+const PTE_SOFTWARE_BITS: u64 = 4;
+
+/* What the stage 2 manager uses the software bits of a leaf for */
+pub const PTE_SW_LOGGED: u64 = 0b0001;    // Writable, write-protected by dirty.rs
+pub const PTE_SW_SHARED: u64 = 0b0010;    // A read-only frame shared by dedup.rs
 
 /* Used only by Table Entries */
 const PTE_TABLE_PRIV_EX_NEVER_SHIFT: u64 = 59;
 const PTE_TABLE_EX_NEVER_SHIFT: u64 = 60;
 const PTE_TABLE_ACCESS_PERMS_SHIFT: u64 = 61;
+const PTE_TABLE_ACCESS_PERMS_BITS: u64 = 2;
 const PTE_TABLE_NOT_SECURE_SHIFT: u64 = 63;
 
-const PTE_VALID: u64 = 1;
+const PTE_VALID: u64 = 1 << PTE_VALID_SHIFT;
 const PTE_TABLE: u64 = 1 << PTE_TABLE_SHIFT;
-const PTE_NOT_SECURE: u64 = 5;
-const PTE_UNPRIVILEGED_ACCESS: u64 = 6;
-const PTE_NOT_GLOBAL: u64 = 7;
-    
+
+// OA[47:12] of a block, page or table descriptor
+const PTE_ADDRESS_MASK: u64 = ((1 << 48) - 1) & !PAGE_MASK;
+
+// With the 64KB granule, OA[51:48] is held in bits [15:12] (FEAT_LPA)
+const PTE_ADDRESS_HIGH_SHIFT: u64 = 12;
+const PTE_ADDRESS_HIGH_MASK: u64 = 0xf << PTE_ADDRESS_HIGH_SHIFT;
+
+// The APTable and PXNTable bits are RES0 for EL2 w/ no ARMv8.1-VHE
+const TABLE_DESCRIPTOR_RES0: u64 = bitfield(51, 48) |
+                                   bitfield(62, 61) |
+                                   bit(PTE_TABLE_PRIV_EX_NEVER_SHIFT);
+const STAGE2_TABLE_DESCRIPTOR_RES0: u64 = bitfield(51, 48) | bitfield(63, 59);
+
 /// ARM 64-bit LPAE entries
 /// Refer to the ARM Reference Manual, Figure D5-15 for 
 /// the format of these block and table descriptors.
-#[derive(Copy, Clone, Debug)]
+///
+/// The raw value is what the MMU walks.  Use decode() and
+/// Descriptor::encode() rather than building one by hand.
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub struct PageTableEntry(pub u64);
 
-impl PageTableEntry {
-    pub unsafe fn as_pagetable(&self) -> &mut PageTable {
-        let pt_address: u64 = core::mem::transmute(&self);
-        let address = (pt_address & !((1 << 12) - 1)) & CORTEX_A53_MAX_OA;
-        return core::mem::transmute(address); 
+/// A translation table in physical memory.
+///
+/// Tables are only ever accessed through a PhysicalMemory, so the same
+/// code builds the real tables at boot and simulated ones in tests.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct PageTable {
+    address: u64,
+    entries: usize,
+}
+
+impl PageTable {
+    pub fn new(address: u64, entries: usize) -> PageTable {
+        PageTable { address: address, entries: entries }
+    }
+
+    /// The physical address of the table
+    pub fn address(&self) -> u64 {
+        self.address
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries
     }
 
-    pub fn is_valid(&self) -> bool {
-        return (self.0 & PTE_VALID) == 1;
+    /// Returns the index of the entry for `address` in this table at
+    /// `level`.  The index covers every table of a concatenated root.
+    pub fn index(&self, address: u64, level: usize, granule: Granule) -> usize {
+        ((address >> granule.level_shift(level)) as usize) & (self.entries - 1)
+    }
+
+    pub fn read(&self, mem: &dyn PhysicalMemory, index: usize) -> PageTableEntry {
+        assert!(index < self.entries);
+        PageTableEntry(mem.read_u64(self.address + (index * size_of::<PageTableEntry>()) as u64))
+    }
+
+    pub fn write(&self, mem: &mut dyn PhysicalMemory, index: usize, entry: PageTableEntry) -> () {
+        assert!(index < self.entries);
+        mem.write_u64(self.address + (index * size_of::<PageTableEntry>()) as u64, entry.0);
     }
 }
 
-#[repr(align(8192))]
-pub struct PageTableConcat {
-    pub entries: [PageTableEntry; 1024],
+/* Generates a getter and setter for a multi-bit descriptor field */
+macro_rules! descriptor_field {
+    ($get:ident, $set:ident, $shift:expr, $bits:expr) => {
+        pub fn $get(&self) -> u64 {
+            (self.0 >> $shift) & ((1 << $bits) - 1)
+        }
+
+        pub fn $set(&mut self, value: u64) -> () {
+            assert!(value < (1 << $bits));
+            self.0 &= !(((1 << $bits) - 1) << $shift);
+            self.0 |= value << $shift;
+        }
+    }
 }
 
-impl PageTableConcat {
-    pub fn new() -> PageTableConcat {
-        PageTableConcat {
-                entries: [PageTableEntry(0); 1024]
+/* Generates a getter and setter for a single bit descriptor field */
+macro_rules! descriptor_bit {
+    ($get:ident, $set:ident, $shift:expr) => {
+        pub fn $get(&self) -> bool {
+            self.0 & bit($shift) != 0
+        }
+
+        pub fn $set(&mut self, value: bool) -> () {
+            if value {
+                self.0 |= bit($shift);
+            } else {
+                self.0 &= !bit($shift);
+            }
         }
     }
 }
 
+impl PageTableEntry {
+    /// Returns the next level table that this table descriptor points to.
+    pub fn as_pagetable(&self, granule: Granule) -> PageTable {
+        PageTable::new(self.output_address(granule), granule.entries_per_table())
+    }
 
+    descriptor_bit!(is_valid, set_valid, PTE_VALID_SHIFT);
+
+    /* At levels 0 through 2 this distinguishes a table descriptor from a
+     * block descriptor.  At level 3 a valid page descriptor always has
+     * this bit set. */
+    descriptor_bit!(is_table, set_table, PTE_TABLE_SHIFT);
+
+    /* Block and page descriptors, stage 1 */
+    descriptor_field!(attr_index, set_attr_index, PTE_ATTR_SHIFT, PTE_ATTR_BITS);
+    descriptor_bit!(is_non_secure, set_non_secure, PTE_NON_SECURE_SHIFT);
+    descriptor_bit!(is_unprivileged, set_unprivileged, PTE_UNPRIVILIGED_ACCESS_SHIFT);
+    descriptor_bit!(is_read_only, set_read_only, PTE_READ_ONLY_SHIFT);
+    descriptor_bit!(is_not_global, set_not_global, PTE_NOT_GLOBAL_SHIFT);
+    descriptor_bit!(is_privileged_execute_never, set_privileged_execute_never,
+                    PTE_PRIV_EX_NEVER_SHIFT);
+
+    /* Block and page descriptors, stage 2 */
+    descriptor_field!(mem_attr, set_mem_attr, PTE_S2_MEMATTR_SHIFT, PTE_S2_MEMATTR_BITS);
+    descriptor_field!(s2ap, set_s2ap, PTE_S2AP_SHIFT, PTE_S2AP_BITS);
+
+    /* With VTCR_EL2.HD set, a write to a write-protected page with DBM
+     * set makes the page writable instead of faulting, see dirty.rs */
+    descriptor_bit!(is_dirty_bit_modifier, set_dirty_bit_modifier, PTE_DBM_SHIFT);
+
+    /* Block and page descriptors, both stages */
+    descriptor_field!(shareability, set_shareability,
+                      PTE_SHAREABILITY_SHIFT, PTE_SHAREABILITY_BITS);
+    descriptor_bit!(is_access_flag, set_access_flag, PTE_ACCESS_FLAG_SHIFT);
+    descriptor_bit!(is_contiguous, set_contiguous, PTE_CONTIGUOUS_SHIFT);
+    descriptor_bit!(is_execute_never, set_execute_never, PTE_EX_NEVER_SHIFT);
+    descriptor_field!(software, set_software, PTE_SOFTWARE_SHIFT, PTE_SOFTWARE_BITS);
+
+    /* Table descriptors, stage 1 */
+    descriptor_bit!(is_table_privileged_execute_never, set_table_privileged_execute_never,
+                    PTE_TABLE_PRIV_EX_NEVER_SHIFT);
+    descriptor_bit!(is_table_execute_never, set_table_execute_never,
+                    PTE_TABLE_EX_NEVER_SHIFT);
+    descriptor_field!(table_access_perms, set_table_access_perms,
+                      PTE_TABLE_ACCESS_PERMS_SHIFT, PTE_TABLE_ACCESS_PERMS_BITS);
+    descriptor_bit!(is_table_non_secure, set_table_non_secure, PTE_TABLE_NOT_SECURE_SHIFT);
+
+    /// Returns true if this is a valid block (levels 1 and 2) or page
+    /// (level 3) descriptor, i.e. it maps memory instead of pointing to
+    /// another table.
+    pub fn is_leaf(&self, level: usize) -> bool {
+        self.is_valid() && (level == 3 || !self.is_table())
+    }
 
-#[repr(align(4096))]
-pub struct PageTable {
-    pub entries: [PageTableEntry; 512],
-}
+    /// Returns this block or page descriptor with the contiguous hint set
+    pub fn with_contiguous(self) -> PageTableEntry {
+        let mut entry = self;
+        entry.set_contiguous(true);
+        entry
+    }
 
-impl PageTable {
-    pub fn new() -> PageTable {
-        PageTable {
-                entries: [PageTableEntry(0); 512]
+    /// The output address of a block, page or table descriptor
+    pub fn output_address(&self, granule: Granule) -> u64 {
+        let mut address = self.0 & PTE_ADDRESS_MASK & granule.mask();
+
+        if granule == Granule::Kb64 {
+            address |= ((self.0 & PTE_ADDRESS_HIGH_MASK) >> PTE_ADDRESS_HIGH_SHIFT) << 48;
+        }
+
+        address
+    }
+
+    /// Replace the output address, which must be aligned to the granule
+    pub fn set_output_address(&mut self, address: u64, granule: Granule) -> () {
+        self.0 &= !output_address_mask(granule);
+        self.0 |= encode_output_address(address, granule);
+    }
+
+    /// Decode the descriptor found in a table at `level`.
+    ///
+    /// A level 3 descriptor without the table bit is reserved, and is
+    /// decoded as Invalid since the MMU treats it as such.
+    pub fn decode(self, level: usize, granule: Granule, stage2: bool) -> Descriptor {
+        if !self.is_valid() || (level == 3 && !self.is_table()) {
+            return Descriptor::Invalid;
+        }
+
+        let address = self.output_address(granule);
+
+        if level < 3 && self.is_table() {
+            return Descriptor::Table {
+                address: address,
+                non_secure: self.is_table_non_secure(),
+            };
+        }
+
+        let attributes = if stage2 {
+            Attributes::Stage2(Stage2Attributes {
+                mem_attr: self.mem_attr(),
+                access: Stage2Access::from_bits(self.s2ap()),
+                shareability: Shareability::from_bits(self.shareability()),
+                access_flag: self.is_access_flag(),
+                dirty_bit_modifier: self.is_dirty_bit_modifier(),
+                contiguous: self.is_contiguous(),
+                execute_never: self.is_execute_never(),
+                software: self.software(),
+            })
+        } else {
+            Attributes::Stage1(Stage1Attributes {
+                attr_index: self.attr_index(),
+                non_secure: self.is_non_secure(),
+                read_only: self.is_read_only(),
+                shareability: Shareability::from_bits(self.shareability()),
+                access_flag: self.is_access_flag(),
+                non_global: self.is_not_global(),
+                contiguous: self.is_contiguous(),
+                execute_never: self.is_execute_never(),
+                software: self.software(),
+            })
+        };
+
+        if level == 3 {
+            Descriptor::Page { address: address, attributes: attributes }
+        } else {
+            Descriptor::Block { address: address, level: level, attributes: attributes }
         }
     }
 }
 
-//pub type PageTable = [PageTableEntry; 512];
+/// The descriptor bits that hold the output address
+fn output_address_mask(granule: Granule) -> u64 {
+    match granule {
+        Granule::Kb64 => (PTE_ADDRESS_MASK & granule.mask()) | PTE_ADDRESS_HIGH_MASK,
+        _ => PTE_ADDRESS_MASK & granule.mask(),
+    }
+}
 
-// The AP_TABLE_BITS is RES0 for EL2 w/ no ARMv8.1-VHE
-const AP_TABLE_BITS: u64 = bitfield(62, 61);
-const TABLE_DESCRIPTOR_RES0: u64 = bitfield(51, 48) | AP_TABLE_BITS;
-const STAGE2_TABLE_DESCRIPTOR_RES0: u64 = bitfield(51, 48) | AP_TABLE_BITS | bitfield(63, 59);
-const TABLE_NON_SECURE: u64 = bit(63);
+/// Returns the output address field for address, which must be aligned
+/// to the granule
+fn encode_output_address(address: u64, granule: Granule) -> u64 {
+    assert_eq!(address & !granule.mask(), 0);
+
+    match granule {
+        Granule::Kb64 => {
+            assert!(address < (1 << 52));
+            (address & PTE_ADDRESS_MASK) | ((address >> 48) << PTE_ADDRESS_HIGH_SHIFT)
+        },
+        _ => {
+            assert!(address < (1 << 48));
+            address
+        },
+    }
+}
 
-// The maxium output address for the Cortex-A53
-const CORTEX_A53_MAX_OA: u64 = (1 << 40) - 1;
+/// The attributes of a stage 1 (EL2) block or page descriptor
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Stage1Attributes {
+    /// Index of the MAIR_EL2 attribute, see MemoryType
+    pub attr_index: u64,
+    pub non_secure: bool,
+
+    /// AP[2].  AP[1] is RES1 in the EL2 regime and is always set.
+    pub read_only: bool,
+    pub shareability: Shareability,
+    pub access_flag: bool,
+    pub non_global: bool,
+    pub contiguous: bool,
+    pub execute_never: bool,
+
+    /// Bits [58:55], ignored by the MMU
+    pub software: u64,
+}
 
-impl PageTableEntry {
-    pub fn from_table(table: &PageTable) -> PageTableEntry {
-        let address: u64 = (table as *const PageTable) as u64;
-        let mut descriptor: u64 = 0;
+impl Stage1Attributes {
+    pub fn from_flags(flags: MapFlags) -> Stage1Attributes {
+        Stage1Attributes {
+            attr_index: flags.memory_type.attr_index(),
+            // This is hypervisor memory, so it is always Non-Secure
+            non_secure: true,
+            read_only: flags.access == AccessPermissions::ReadOnly,
+            shareability: flags.shareability,
+            /*
+             * In ARMv8, software must manage the access flag.
+             * If it is NOT set to 1, then attempts at loading this
+             * entry into the TLB will cause an Access flag fault.
+             */
+            access_flag: true,
+            non_global: flags.non_global,
+            contiguous: false,
+            execute_never: flags.execute_never,
+            software: 0,
+        }
+    }
 
-        // Set next level table address
-        descriptor |=  (address >> 12) << 12;
+    /// The MemoryType programmed at attr_index, if it is one of ours
+    pub fn memory_type(&self) -> Option<MemoryType> {
+        MemoryType::from_attr_index(self.attr_index)
+    }
+}
 
-        // Clear bits beyond 40th bit because Cortex-A53 only
-        // supports 40-bit OA
-        // descriptor &= !((1 << 40) - 1);
-        
-        // This is hypervisor memory, so set the Non-Secure Table bit to 1
-        descriptor |= TABLE_NON_SECURE;
-        descriptor |= PTE_VALID;
-        descriptor |= PTE_TABLE;
+/// The attributes of a stage 2 block or page descriptor
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Stage2Attributes {
+    /// MemAttr[3:0], the stage 2 memory type
+    pub mem_attr: u64,
+    pub access: Stage2Access,
+    pub shareability: Shareability,
+    pub access_flag: bool,
+    pub dirty_bit_modifier: bool,
+    pub contiguous: bool,
+    pub execute_never: bool,
+
+    /// Bits [58:55], ignored by the MMU
+    pub software: u64,
+}
 
-        assert_eq!(descriptor & TABLE_DESCRIPTOR_RES0, 0);
-        return PageTableEntry(descriptor);
+impl Stage2Attributes {
+    pub fn from_flags(flags: Stage2Flags) -> Stage2Attributes {
+        Stage2Attributes {
+            mem_attr: flags.memory_type as u64,
+            access: flags.access,
+            shareability: flags.shareability,
+            // As at stage 1, a clear access flag faults
+            access_flag: true,
+            dirty_bit_modifier: false,
+            contiguous: false,
+            execute_never: flags.execute_never,
+            software: 0,
+        }
     }
 
-    /// Refer to D5.3 for Stage 2 translation table format descriptors
-    ///
-    /// NOTE: For now, we are using only Normal memory.  This is NOT
-    /// good for device memory.  This will need to be changed.
-    pub fn from_table_stage2(table: &PageTable) -> PageTableEntry {
-        let address: u64 = (table as *const PageTable) as u64;
-
-        // Set next level table address
-        let mut descriptor: u64 =  address & !((1<<12)-1);
-
-        // Clear bits beyond 40th bit because Cortex-A53 only
-        // supports 40-bit OA
-        descriptor &= (1 << 40) - 1;
-        
-        /*
-         * Set valid, table, af, read, sh inner, mem attr to device
-         */
-        descriptor |= PTE_VALID;
-        descriptor |= PTE_TABLE;
+    /// The Stage2MemoryType MemAttr encodes, if it is one we use
+    pub fn memory_type(&self) -> Option<Stage2MemoryType> {
+        Stage2MemoryType::from_mem_attr(self.mem_attr)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Attributes {
+    Stage1(Stage1Attributes),
+    Stage2(Stage2Attributes),
+}
+
+impl Attributes {
+    pub fn contiguous(&self) -> bool {
+        match self {
+            Attributes::Stage1(attributes) => attributes.contiguous,
+            Attributes::Stage2(attributes) => attributes.contiguous,
+        }
+    }
+
+    pub fn with_contiguous(self, contiguous: bool) -> Attributes {
+        match self {
+            Attributes::Stage1(attributes) =>
+                Attributes::Stage1(Stage1Attributes { contiguous: contiguous, ..attributes }),
+            Attributes::Stage2(attributes) =>
+                Attributes::Stage2(Stage2Attributes { contiguous: contiguous, ..attributes }),
+        }
+    }
+}
 
-        // AF[10] = 1
-        descriptor |= 1 << 10;
+/// A decoded translation table descriptor, see PageTableEntry::decode()
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Descriptor {
+    Invalid,
 
-        // SH[9:8] == Normal, 
-        descriptor |= 0b10 << 8;
+    /// Points to the next level table at address.  non_secure is
+    /// NSTable, and must be false in a stage 2 tree.
+    Table { address: u64, non_secure: bool },
 
-        // Use memory attr 000, which is inner-shareable, WBWA
-        descriptor &= !(bit(4) | bit(3) | bit(2));
+    /// A level 1 or 2 block
+    Block { address: u64, level: usize, attributes: Attributes },
 
-        // This is a Non-Secure block, NS == 1
-        descriptor |= bit(5);
+    /// A level 3 page
+    Page { address: u64, attributes: Attributes },
+}
 
-        // read/write 
-        descriptor |= bit(7) | bit(6);
+impl Descriptor {
+    /// Returns the raw descriptor.  Every field of the descriptor is
+    /// encoded, so decode() gives back the same Descriptor.
+    pub fn encode(self, granule: Granule) -> PageTableEntry {
+        let mut entry = PageTableEntry(0);
+
+        match self {
+            Descriptor::Invalid => (),
+            Descriptor::Table { address, non_secure } => {
+                entry.set_valid(true);
+                entry.set_table(true);
+                entry.set_output_address(address, granule);
+                entry.set_table_non_secure(non_secure);
+                assert_eq!(entry.0 & TABLE_DESCRIPTOR_RES0, 0);
+            },
+            Descriptor::Block { address, level, attributes } => {
+                assert!(level < 3 && granule.has_blocks_at(level));
+                assert_eq!(address & (granule.level_size(level) - 1), 0);
+                entry.set_valid(true);
+                entry.set_output_address(address, granule);
+                encode_attributes(&mut entry, attributes);
+            },
+            Descriptor::Page { address, attributes } => {
+                /* For page mappings, PTE_TABLE is set too. */
+                entry.set_valid(true);
+                entry.set_table(true);
+                entry.set_output_address(address, granule);
+                encode_attributes(&mut entry, attributes);
+            },
+        }
 
-        assert_eq!(descriptor & STAGE2_TABLE_DESCRIPTOR_RES0, 0);
-        return PageTableEntry(descriptor);
+        entry
     }
 
-    pub fn from_block_stage2(address: u64) -> PageTableEntry {
-        assert!(address < CORTEX_A53_MAX_OA);
+    pub fn output_address(&self) -> Option<u64> {
+        match *self {
+            Descriptor::Invalid => None,
+            Descriptor::Table { address, .. } => Some(address),
+            Descriptor::Block { address, .. } => Some(address),
+            Descriptor::Page { address, .. } => Some(address),
+        }
+    }
 
-        // Align address to 1GB
-        let mut descriptor = address & !((1<<30) - 1);
+    /// The attributes of a block or page
+    pub fn attributes(&self) -> Option<Attributes> {
+        match *self {
+            Descriptor::Block { attributes, .. } => Some(attributes),
+            Descriptor::Page { attributes, .. } => Some(attributes),
+            _ => None,
+        }
+    }
+}
 
-        // Valid
-        descriptor |= 1;
+fn encode_attributes(entry: &mut PageTableEntry, attributes: Attributes) -> () {
+    match attributes {
+        Attributes::Stage1(attributes) => {
+            entry.set_attr_index(attributes.attr_index);
+            entry.set_non_secure(attributes.non_secure);
+            entry.set_unprivileged(true);
+            entry.set_read_only(attributes.read_only);
+            entry.set_shareability(attributes.shareability as u64);
+            entry.set_access_flag(attributes.access_flag);
+            entry.set_not_global(attributes.non_global);
+            entry.set_contiguous(attributes.contiguous);
+            // The EL2 regime has a single XN bit, in the UXN position
+            entry.set_execute_never(attributes.execute_never);
+            entry.set_software(attributes.software);
+        },
+        Attributes::Stage2(attributes) => {
+            entry.set_mem_attr(attributes.mem_attr);
+            entry.set_s2ap(attributes.access as u64);
+            entry.set_shareability(attributes.shareability as u64);
+            entry.set_access_flag(attributes.access_flag);
+            entry.set_dirty_bit_modifier(attributes.dirty_bit_modifier);
+            entry.set_contiguous(attributes.contiguous);
+            entry.set_execute_never(attributes.execute_never);
+            entry.set_software(attributes.software);
+        },
+    }
+}
 
-        // Not a table, so bit 1 is 0
-        assert_eq!(descriptor & 0b10, 0);
+impl PageTableEntry {
+    pub fn from_table(address: u64, granule: Granule) -> PageTableEntry {
+        // This is hypervisor memory, so set the Non-Secure Table bit to 1
+        Descriptor::Table { address: address, non_secure: true }.encode(granule)
+    }
 
-        // S2AP
-        descriptor |= bit(7);
-        descriptor |= bit(6);
+    /// Refer to D5.3 for Stage 2 translation table format descriptors.
+    ///
+    /// Stage 2 table descriptors only hold the next level table address,
+    /// the attributes of a mapping all live in its block or page
+    /// descriptor.
+    pub fn from_table_stage2(address: u64, granule: Granule) -> PageTableEntry {
+        let entry = Descriptor::Table { address: address, non_secure: false }.encode(granule);
+
+        assert_eq!(entry.0 & STAGE2_TABLE_DESCRIPTOR_RES0, 0);
+        entry
+    }
 
-        // Access Flag
-        descriptor |= bit(10);
-        return PageTableEntry(descriptor);
+    /// Returns a stage 2 block descriptor for the block at `level`
+    /// containing address, or a page descriptor at level 3
+    pub fn from_block_stage2(address: u64,
+                             level: usize,
+                             granule: Granule,
+                             flags: Stage2Flags) -> PageTableEntry {
+        // Align address to the block size
+        let address = address & !(granule.level_size(level) - 1);
+        let attributes = Attributes::Stage2(Stage2Attributes::from_flags(flags));
+
+        if level == 3 {
+            Descriptor::Page { address: address, attributes: attributes }.encode(granule)
+        } else {
+            Descriptor::Block { address: address, level: level, attributes: attributes }.encode(granule)
+        }
     }
 
-    /// Currently we only support level-3, 4KB blocks
+    /// Returns a level-3 page descriptor
     ///
     /// The documentation for these blocks can be found
     /// in "Figure D5-17 VMSAv8-64 level 3 descriptor format"
     /// of the ARMv8 reference manual.
-    pub fn from_block(address: u64) -> PageTableEntry {
-        assert!(address < CORTEX_A53_MAX_OA);
-        let mut descriptor = 0;
+    pub fn from_block(address: u64, granule: Granule, flags: MapFlags) -> PageTableEntry {
+        PageTableEntry::from_block_at_level(address, 3, granule, flags)
+    }
 
-        descriptor |= address & !((1 << 12) - 1);
+    /// Returns a block descriptor for a level 1 or 2 block, or a page
+    /// descriptor for level 3.
+    pub fn from_block_at_level(address: u64,
+                               level: usize,
+                               granule: Granule,
+                               flags: MapFlags) -> PageTableEntry {
+        assert!(granule.has_blocks_at(level));
+        let attributes = Attributes::Stage1(Stage1Attributes::from_flags(flags));
+
+        if level == 3 {
+            Descriptor::Page { address: address, attributes: attributes }.encode(granule)
+        } else {
+            Descriptor::Block { address: address, level: level, attributes: attributes }.encode(granule)
+        }
+    }
+}
 
-        /* For 4K mappings, PTE_TABLE is set too */
-        descriptor |= PTE_TABLE;
-        descriptor |= PTE_VALID;
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum MapError {
+    /// The virtual address already has a valid page or block mapping,
+    /// or a block was requested where a next level table already exists
+    AlreadyMapped(VirtualAddress),
 
-        // Use memory attr 000, which is inner-shareable, WBWA
-        descriptor &= !(bit(4) | bit(3) | bit(2));
+    /// A block descriptor covers the virtual address, so there is no
+    /// next level table to walk into
+    BlockInTheWay(VirtualAddress),
 
-        // This is a Non-Secure block, NS == 1
-        descriptor |= bit(5);
+    /// No frames are left to allocate a new table from
+    OutOfMemory,
 
-        // Access Permissions == EL2 Read/Write, no EL1 or EL0 access
-        // (i.e., AP[1:0]  == 00)
-        descriptor &= !(bit(7) | bit(6));
+    /// The output address is beyond the physical address range
+    AddressTooLarge(u64),
 
-        // Hypervisor pages are inner-shareable, so as to be
-        // coherent across PEs, (i.e, SH == 11)
-        descriptor |= bit(9) | bit(8);
+    /// No free range is left in the vmap area
+    NoVirtualSpace,
+}
 
-        /*
-         * In ARMv8, software must manage the access flag.
-         * If it is NOT set to 1, then attempts at loading this
-         * entry into the TLB will cause an Access flag fault.
-         */
-        descriptor |= bit(10);
+/// Allocates and zeroes a table of `entries` descriptors, aligned to its
+/// size (or the granule, if it is smaller than a page).
+fn alloc_table(mem: &mut dyn PhysicalMemory,
+               granule: Granule,
+               entries: usize) -> Result<PageTable, MapError> {
+    let size = (entries * size_of::<PageTableEntry>()) as u64;
+    let size = align_up(size, granule);
+
+    let address = match mem.alloc_frames(size, size) {
+        Some(address) => address,
+        None => return Err(MapError::OutOfMemory),
+    };
+
+    let table = PageTable::new(address, entries);
+    for index in 0..entries {
+        table.write(mem, index, PageTableEntry(0));
+    }
 
-        /* Set to non-Global = 0, because we don't support ASIDs yet.
-         * TODO: Support ASIDs
-         */
-        descriptor &= !bit(11);
+    Ok(table)
+}
 
-        /* TODO: implement Upper attributes */
+/// Returns a table descriptor for `table` in a stage 1 or stage 2 tree
+fn table_descriptor(table: PageTable, granule: Granule, stage2: bool) -> PageTableEntry {
+    if stage2 {
+        PageTableEntry::from_table_stage2(table.address(), granule)
+    } else {
+        PageTableEntry::from_table(table.address(), granule)
+    }
+}
 
-        return PageTableEntry(descriptor);
+/* The longest contiguous run, 128 16KB pages */
+const MAX_CONTIGUOUS_ENTRIES: usize = 128;
+
+/// Invalidate the TLB entries for the page or block at `address`
+fn invalidate(address: u64, stage2: bool) -> () {
+    if stage2 {
+        tlbi_ipas2e1is(address);
+    } else {
+        tlbi_vae2is(address);
     }
 }
 
-pub fn align(vaddr: VirtualAddress, alignment: Alignment) -> VirtualAddress {
-    return vaddr & alignment.mask();
+/// Where Walk::table_for() hooked the first table it allocated into the
+/// tree: parent[index], translating at `level`
+#[derive(Copy, Clone)]
+struct NewTables {
+    parent: PageTable,
+    index: usize,
+    level: usize,
 }
 
-/* Helpers for navigating the page tables */
-pub fn pagetable_zeroeth_index(vaddr: VirtualAddress) -> usize {
-    ((vaddr >> ZEROETH_SHIFT) & PTE_MASK) as usize
+/// A translation table tree being walked or changed in software: the
+/// memory its tables live in, and what is needed to read them
+struct Walk<'a> {
+    mem: &'a mut dyn PhysicalMemory,
+    granule: Granule,
+    stage2: bool,
+    root: PageTable,
+    start_level: usize,
 }
 
-pub fn pagetable_concat_table(vaddr: VirtualAddress) -> usize {
-    let bit = (vaddr & (1 << 39)) >> 39;
+impl<'a> Walk<'a> {
+    fn new(tree: &dyn TranslationTree, mem: &'a mut dyn PhysicalMemory) -> Walk<'a> {
+        Walk {
+            mem: mem,
+            granule: tree.granule(),
+            stage2: tree.is_stage2(),
+            root: tree.root(),
+            start_level: tree.start_level(),
+        }
+    }
+
+    /// Replace the level 1 or 2 block descriptor at table[index], which
+    /// maps `address`, with a table of next level descriptors that map
+    /// the same output addresses with the same attributes.
+    fn split_block(&mut self,
+                   table: PageTable,
+                   index: usize,
+                   address: u64,
+                   level: usize) -> Result<PageTable, MapError> {
+        assert!(level < 3);
+
+        let granule = self.granule;
+        let entry = table.read(self.mem, index);
+        let (output_address, attributes) = match entry.decode(level, granule, self.stage2) {
+            Descriptor::Block { address, attributes, .. } => (address, attributes),
+            _ => unreachable!(),
+        };
+
+        let next_table = alloc_table(self.mem, granule, granule.entries_per_table())?;
+        let next = level + 1;
+        let size = granule.level_size(next);
+
+        for i in 0..next_table.len() {
+            let address = output_address + (i as u64) * size;
+
+            let descriptor = if next == 3 {
+                Descriptor::Page { address: address, attributes: attributes }
+            } else {
+                Descriptor::Block { address: address, level: next, attributes: attributes }
+            };
+            next_table.write(self.mem, i, descriptor.encode(granule));
+        }
+
+        let descriptor = table_descriptor(next_table, granule, self.stage2);
+        self.replace_entry(table, index, address, descriptor);
+
+        Ok(next_table)
+    }
+
+    /// Replace table[index], which translates `address`, with `entry`
+    /// and drop the TLB entries for the old one.
+    ///
+    /// A valid stage 2 entry is replaced with break-before-make, so that
+    /// no CPU running the guest can ever hold TLB entries for both the
+    /// old and the new entry, which can cause TLB conflict aborts: the
+    /// entry is invalidated, the TLB entries for it are dropped on every
+    /// CPU in the Inner Shareable domain, and only then is the new entry
+    /// written.  A vCPU that touches the address in between takes a
+    /// stage 2 translation fault and retries, see
+    /// Vm::handle_translation_fault().
+    ///
+    /// The EL2 stage 1 entries are replaced in place, since breaking them
+    /// could unmap the code and stack doing the update.  An entry that
+    /// does not change is left alone, TLB entries and all.
+    fn replace_entry(&mut self,
+                     table: PageTable,
+                     index: usize,
+                     address: u64,
+                     entry: PageTableEntry) -> () {
+        let old = table.read(self.mem, index);
+
+        if old == entry {
+            return;
+        }
+
+        if !self.stage2 || !old.is_valid() {
+            table.write(self.mem, index, entry);
+            invalidate(address, self.stage2);
+            return;
+        }
+
+        table.write(self.mem, index, PageTableEntry(0));
+
+        /* tlbi_ipas2e1is() orders the write before the invalidation */
+        tlbi_ipas2e1is(address);
+        data_barrier(Shareable::Inner);
+
+        /* Combined stage 1 + 2 entries are not tagged by IPA */
+        tlbi_vmalle1is();
+
+        if entry.is_valid() {
+            table.write(self.mem, index, entry);
+            data_barrier(Shareable::Inner);
+        }
+    }
+
+    /// Clear the contiguous hint on the run of
+    /// `granule.contiguous_entries()` entries at `level` that includes
+    /// table[index], which translates `address`.
+    ///
+    /// The run may be cached as a single TLB entry, so changing the hint
+    /// on only part of it needs break-before-make: every entry of the run
+    /// is invalidated and its TLB entries dropped before the entries are
+    /// rewritten without the hint.  The run must not map the code or
+    /// stack doing the update.
+    fn clear_contiguous(&mut self,
+                        table: PageTable,
+                        index: usize,
+                        level: usize,
+                        address: u64) -> () {
+        let count = self.granule.contiguous_entries(level);
+        let first = index & !(count - 1);
+        let size = self.granule.level_size(level);
+        let run_start = address & !(size * count as u64 - 1);
+
+        let mut saved = [PageTableEntry(0); MAX_CONTIGUOUS_ENTRIES];
+        for (i, entry) in saved[..count].iter_mut().enumerate() {
+            *entry = table.read(self.mem, first + i);
+            table.write(self.mem, first + i, PageTableEntry(0));
+            invalidate(run_start + (i as u64) * size, self.stage2);
+        }
+
+        /* The stage 2 invalidation does not wait for completion */
+        data_barrier(Shareable::Inner);
 
-    bit as usize
+        if self.stage2 {
+            tlbi_vmalle1is();
+        }
+
+        for (i, entry) in saved[..count].iter().enumerate() {
+            let mut entry = *entry;
+            entry.set_contiguous(false);
+            table.write(self.mem, first + i, entry);
+        }
+    }
+
+    /// replace_entry() for a whole contiguous run: calls `f` with each of
+    /// the `granule.contiguous_entries()` entries at `level` starting at
+    /// table[index], which translates `address`, and replaces them with
+    /// the entries `f` returns.
+    ///
+    /// The run may be cached as a single TLB entry, and rewriting it one
+    /// entry at a time would leave it with mismatched attributes in
+    /// between.  So if anything changes, every entry of the run is
+    /// invalidated and its TLB entries dropped before any new one is
+    /// written.  The run must not map the code or stack doing the update.
+    fn replace_run(&mut self,
+                   table: PageTable,
+                   index: usize,
+                   level: usize,
+                   address: u64,
+                   f: &mut dyn FnMut(PageTableEntry, u64, usize) -> PageTableEntry) -> () {
+        let count = self.granule.contiguous_entries(level);
+        let size = self.granule.level_size(level);
+        let mut new = [PageTableEntry(0); MAX_CONTIGUOUS_ENTRIES];
+        let mut changed = false;
+
+        for (i, entry) in new[..count].iter_mut().enumerate() {
+            let old = table.read(self.mem, index + i);
+            *entry = f(old, address + (i as u64) * size, level);
+            changed |= *entry != old;
+        }
+
+        if !changed {
+            return;
+        }
+
+        for i in 0..count {
+            table.write(self.mem, index + i, PageTableEntry(0));
+            invalidate(address + (i as u64) * size, self.stage2);
+        }
+
+        /* The stage 2 invalidation does not wait for completion */
+        data_barrier(Shareable::Inner);
+
+        if self.stage2 {
+            tlbi_vmalle1is();
+        }
+
+        for (i, entry) in new[..count].iter().enumerate() {
+            if entry.is_valid() {
+                table.write(self.mem, index + i, *entry);
+            }
+        }
+
+        data_barrier(Shareable::Inner);
+    }
+
+    /// Calls `f` with every leaf descriptor mapping [start, end), along
+    /// with the address and level it maps, and replaces the descriptor
+    /// with the one `f` returns, see replace_entry() and replace_run().
+    ///
+    /// `table` translates addresses at `level`.  Blocks that are only
+    /// partly inside the range are split first, so that `f` only ever
+    /// sees leaves that lie entirely inside it.  Likewise the contiguous
+    /// hint is cleared from any run that is only partly inside the
+    /// range.  Unmapped holes are skipped.
+    fn walk_range(&mut self,
+                  table: PageTable,
+                  level: usize,
+                  start: u64,
+                  end: u64,
+                  f: &mut dyn FnMut(PageTableEntry, u64, usize) -> PageTableEntry) -> Result<(), MapError> {
+        let granule = self.granule;
+        let size = granule.level_size(level);
+        let run_size = size * granule.contiguous_entries(level) as u64;
+        let mut address = start;
+
+        while address < end {
+            let index = table.index(address, level, granule);
+            let block_start = address & !(size - 1);
+            let block_end = block_start + size;
+            let chunk_end = if end < block_end { end } else { block_end };
+            let entry = table.read(self.mem, index);
+
+            if entry.is_leaf(level) && entry.is_contiguous() {
+                let run_start = address & !(run_size - 1);
+
+                if start > run_start || end < run_start + run_size {
+                    self.clear_contiguous(table, index, level, address);
+                }
+            }
+
+            let entry = table.read(self.mem, index);
+
+            /* Only runs that lie entirely inside the range keep the hint */
+            if entry.is_leaf(level) && entry.is_contiguous() {
+                self.replace_run(table, index, level, address, f);
+                address += run_size;
+                continue;
+            }
+
+            if entry.is_leaf(level) && address == block_start && chunk_end == block_end {
+                let new = f(entry, block_start, level);
+                self.replace_entry(table, index, block_start, new);
+            } else if entry.is_valid() {
+                let next = if entry.is_table() {
+                    entry.as_pagetable(granule)
+                } else {
+                    self.split_block(table, index, block_start, level)?
+                };
+
+                self.walk_range(next, level + 1, address, chunk_end, f)?;
+            }
+
+            address = chunk_end;
+        }
+
+        Ok(())
+    }
+
+    /// Calls `f` with every leaf descriptor mapping part of [start, end):
+    /// the memory, the physical address of the descriptor, the descriptor
+    /// itself, and the address and level it maps.  Nothing is split or
+    /// written, `f` is left to do that.
+    fn for_each_leaf(&mut self,
+                     table: PageTable,
+                     level: usize,
+                     start: u64,
+                     end: u64,
+                     f: &mut dyn FnMut(&mut dyn PhysicalMemory, u64, PageTableEntry, u64, usize)) -> () {
+        let granule = self.granule;
+        let size = granule.level_size(level);
+        let mut address = start;
+
+        while address < end {
+            let index = table.index(address, level, granule);
+            let block_start = address & !(size - 1);
+            let block_end = block_start + size;
+            let chunk_end = if end < block_end { end } else { block_end };
+            let entry = table.read(self.mem, index);
+
+            if entry.is_leaf(level) {
+                let descriptor = table.address() + (index * size_of::<PageTableEntry>()) as u64;
+                f(self.mem, descriptor, entry, block_start, level);
+            } else if entry.is_valid() {
+                self.for_each_leaf(entry.as_pagetable(granule), level + 1, address, chunk_end, f);
+            }
+
+            address = chunk_end;
+        }
+    }
+
+    /// Split every block mapping any part of [start, end) down to pages,
+    /// keeping the output addresses and attributes.  `table` translates
+    /// addresses at `level`, as for walk_range().
+    fn split_range(&mut self,
+                   table: PageTable,
+                   level: usize,
+                   start: u64,
+                   end: u64) -> Result<(), MapError> {
+        let granule = self.granule;
+        let size = granule.level_size(level);
+        let mut address = start;
+
+        while address < end {
+            let index = table.index(address, level, granule);
+            let block_start = address & !(size - 1);
+            let block_end = block_start + size;
+            let chunk_end = if end < block_end { end } else { block_end };
+            let entry = table.read(self.mem, index);
+
+            if level < 3 && entry.is_valid() {
+                /* A run of blocks cannot stay contiguous once one is split */
+                if !entry.is_table() && entry.is_contiguous() {
+                    self.clear_contiguous(table, index, level, address);
+                }
+
+                let next = if entry.is_table() {
+                    entry.as_pagetable(granule)
+                } else {
+                    self.split_block(table, index, block_start, level)?
+                };
+
+                self.split_range(next, level + 1, address, chunk_end)?;
+            }
+
+            address = chunk_end;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the table at `level` that translates `address`, allocating
+    /// any missing tables between the root and `level`, along with where
+    /// the first table it allocated was hooked in, if it allocated any.
+    ///
+    /// If an allocation fails, the tables already allocated are unhooked
+    /// and freed again, see discard().
+    fn table_for(&mut self, address: u64, level: usize) -> Result<(PageTable, Option<NewTables>), MapError> {
+        let granule = self.granule;
+        let mut table = self.root;
+        let mut new = None;
+
+        for current in self.start_level..level {
+            let index = table.index(address, current, granule);
+            let entry = table.read(self.mem, index);
+
+            table = if !entry.is_valid() {
+                let next = match alloc_table(self.mem, granule, granule.entries_per_table()) {
+                    Ok(next) => next,
+                    Err(error) => {
+                        self.discard(new, address);
+                        return Err(error);
+                    },
+                };
+
+                table.write(self.mem, index, table_descriptor(next, granule, self.stage2));
+                if new.is_none() {
+                    new = Some(NewTables { parent: table, index: index, level: current + 1 });
+                }
+                next
+            } else if entry.is_table() {
+                entry.as_pagetable(granule)
+            } else {
+                return Err(MapError::BlockInTheWay(address));
+            };
+        }
+
+        Ok((table, new))
+    }
+
+    /// Unhook the tables that table_for() allocated to translate
+    /// `address`, and free them
+    fn discard(&mut self, new: Option<NewTables>, address: u64) -> () {
+        let new = match new {
+            Some(new) => new,
+            None => return,
+        };
+
+        let first = new.parent.read(self.mem, new.index).as_pagetable(self.granule);
+        new.parent.write(self.mem, new.index, PageTableEntry(0));
+
+        /* Walks through the tables may be cached, even with nothing mapped */
+        invalidate(address, self.stage2);
+        data_barrier(Shareable::Inner);
+
+        free_tables(self.mem, first, new.level, self.granule);
+        self.mem.free_frames(first.address(), self.granule.page_size());
+    }
+
+    /// Install `count` adjacent descriptors at `level`, the first of
+    /// which maps `address`.  `descriptor(i)` returns the i'th one.
+    ///
+    /// The entries must all fall in the same table, which a naturally
+    /// aligned contiguous run always does.  Nothing is written unless
+    /// every entry is free: installing over an existing page, block or
+    /// table is an error, and leaves behind no tables allocated for it.
+    fn install(&mut self,
+               address: u64,
+               level: usize,
+               count: usize,
+               descriptor: &dyn Fn(usize) -> PageTableEntry) -> Result<(), MapError> {
+        let (table, new) = self.table_for(address, level)?;
+        let first = table.index(address, level, self.granule);
+        let size = self.granule.level_size(level);
+
+        assert!(first + count <= table.len());
+
+        for i in 0..count {
+            if table.read(self.mem, first + i).is_valid() {
+                self.discard(new, address);
+                return Err(MapError::AlreadyMapped(address + (i as u64) * size));
+            }
+        }
+
+        for i in 0..count {
+            table.write(self.mem, first + i, descriptor(i));
+        }
+
+        Ok(())
+    }
+
+    /// Map `count` adjacent blocks (level 1 or 2) or pages (level 3) from
+    /// vaddr to paddr, with `descriptor(paddr, level)` for each.  A count
+    /// above one must be a whole contiguous run, which is mapped with the
+    /// contiguous hint.
+    ///
+    /// Missing intermediate tables are allocated, existing ones are
+    /// reused.  Mapping over an existing page, block or table is an
+    /// error.
+    fn map_run(&mut self,
+               pa_range: PhysAddrRange,
+               vaddr: u64,
+               paddr: u64,
+               level: usize,
+               count: usize,
+               descriptor: &dyn Fn(u64, usize) -> PageTableEntry) -> Result<(), MapError> {
+        let size = self.granule.level_size(level);
+        assert!(count == 1 || count == self.granule.contiguous_entries(level));
+
+        let last = paddr + size * count as u64 - 1;
+        if last > pa_range.max_address() {
+            return Err(MapError::AddressTooLarge(last));
+        }
+
+        self.install(vaddr, level, count,
+                     &|i| {
+                         let entry = descriptor(paddr + (i as u64) * size, level);
+                         if count > 1 { entry.with_contiguous() } else { entry }
+                     })
+    }
+
+    /// Map [vaddr, vaddr + size) to [paddr, paddr + size).
+    ///
+    /// Level 1 and level 2 blocks are used wherever the granule allows
+    /// them, both addresses are suitably aligned and enough of the range
+    /// remains.  Pages are used for the rest.
+    ///
+    /// Aligned runs of Granule::contiguous_entries() blocks or pages are
+    /// mapped with the contiguous hint, so the TLB can cache each run as
+    /// one entry.
+    fn map_range(&mut self,
+                 pa_range: PhysAddrRange,
+                 vaddr: u64,
+                 paddr: u64,
+                 size: u64,
+                 descriptor: &dyn Fn(u64, usize) -> PageTableEntry) -> Result<(), MapError> {
+        let granule = self.granule;
+        let start_level = self.start_level;
+        assert_eq!((vaddr | paddr | size) & !granule.mask(), 0);
+
+        let mut offset = 0;
+        while offset < size {
+            let va = vaddr + offset;
+            let pa = paddr + offset;
+            let remaining = size - offset;
+
+            let level = (1..=3)
+                .find(|&level| {
+                    let block = granule.level_size(level);
+                    level >= start_level && granule.has_blocks_at(level) &&
+                        (va | pa) & (block - 1) == 0 && remaining >= block
+                })
+                .unwrap();
+
+            let run = granule.level_size(level) * granule.contiguous_entries(level) as u64;
+            let count = if (va | pa) & (run - 1) == 0 && remaining >= run {
+                granule.contiguous_entries(level)
+            } else {
+                1
+            };
+
+            self.map_run(pa_range, va, pa, level, count, descriptor)?;
+            offset += granule.level_size(level) * count as u64;
+        }
+
+        Ok(())
+    }
 }
 
-pub fn pagetable_first_index(vaddr: VirtualAddress) -> usize {
-    ((vaddr >> FIRST_SHIFT) & PTE_MASK) as usize
+/// The parts of a translation table tree needed to walk it in software
+pub trait TranslationTree {
+    /// The initial lookup table, which may be concatenated
+    fn root(&self) -> PageTable;
+
+    /// The level the initial lookup starts at
+    fn start_level(&self) -> usize;
+
+    fn granule(&self) -> Granule;
+
+    /// Stage 2 descriptors have a different attribute layout
+    fn is_stage2(&self) -> bool;
 }
 
-pub fn pagetable_second_index(vaddr: VirtualAddress) -> usize {
-    ((vaddr >> SECOND_SHIFT) & PTE_MASK) as usize
+/// A stage 1 translation table tree for the EL2 translation regime.
+///
+/// Only the initial lookup table exists up front, every other table is
+/// allocated from physical memory the first time a mapping needs it.
+pub struct PageTableTree {
+    granule: Granule,
+    pa_range: PhysAddrRange,
+    start_level: usize,
+    root: PageTable,
 }
 
-pub fn pagetable_third_index(vaddr: VirtualAddress) -> usize {
-    return ((vaddr >> THIRD_SHIFT) & PTE_MASK) as usize;
+impl PageTableTree {
+    pub fn new(mem: &mut dyn PhysicalMemory,
+               granule: Granule,
+               pa_range: PhysAddrRange) -> Result<PageTableTree, MapError> {
+        let start_level = granule.start_level(EL2_VA_BITS);
+        let entries = granule.root_entries(EL2_VA_BITS, start_level);
+
+        Ok(PageTableTree {
+            granule: granule,
+            pa_range: pa_range,
+            start_level: start_level,
+            root: alloc_table(mem, granule, entries)?,
+        })
+    }
+
+    /// The physical address of the initial lookup table, as written to
+    /// TTBR0_EL2
+    pub fn root_address(&self) -> u64 {
+        self.root.address()
+    }
+
+    /// Map the page at vaddr to the frame at paddr.
+    pub fn map(&mut self,
+               mem: &mut dyn PhysicalMemory,
+               vaddr: u64,
+               paddr: u64,
+               flags: MapFlags) -> Result<(), MapError> {
+        let granule = self.granule;
+
+        let descriptor = |address, level| PageTableEntry::from_block_at_level(address, level, granule, flags);
+
+        Walk::new(self, mem).map_run(self.pa_range, vaddr, paddr, 3, 1, &descriptor)
+    }
+
+    /// Map [vaddr, vaddr + size) to [paddr, paddr + size), with the
+    /// largest blocks the alignment allows, see Walk::map_range().
+    pub fn map_range(&mut self,
+                     mem: &mut dyn PhysicalMemory,
+                     vaddr: u64,
+                     paddr: u64,
+                     size: u64,
+                     flags: MapFlags) -> Result<(), MapError> {
+        let granule = self.granule;
+
+        let descriptor = |address, level| PageTableEntry::from_block_at_level(address, level, granule, flags);
+
+        Walk::new(self, mem).map_range(self.pa_range, vaddr, paddr, size, &descriptor)
+    }
+
+    /// Remove every mapping in [vaddr, vaddr + size).
+    ///
+    /// Blocks straddling either end of the range are split, which may
+    /// need to allocate tables.  Tables left empty are not freed.
+    pub fn unmap_range(&mut self,
+                       mem: &mut dyn PhysicalMemory,
+                       vaddr: u64,
+                       size: u64) -> Result<(), MapError> {
+        assert_eq!((vaddr | size) & !self.granule.mask(), 0);
+
+        Walk::new(self, mem).walk_range(self.root, self.start_level, vaddr, vaddr + size,
+                                        &mut |_entry, _address, _level| PageTableEntry(0))
+    }
+
+    /// Change the attributes of every mapping in [vaddr, vaddr + size)
+    /// to `flags`, keeping their output addresses.
+    pub fn protect_range(&mut self,
+                         mem: &mut dyn PhysicalMemory,
+                         vaddr: u64,
+                         size: u64,
+                         flags: MapFlags) -> Result<(), MapError> {
+        let granule = self.granule;
+        assert_eq!((vaddr | size) & !granule.mask(), 0);
+
+        let mut protect = |entry: PageTableEntry, _address, level| {
+            let mut new = PageTableEntry::from_block_at_level(entry.output_address(granule),
+                                                              level, granule, flags);
+
+            /* walk_range() leaves the hint only on runs wholly in the range */
+            new.set_contiguous(entry.is_contiguous());
+            new
+        };
+
+        Walk::new(self, mem).walk_range(self.root, self.start_level, vaddr, vaddr + size, &mut protect)
+    }
 }
 
-pub fn align_4k(addr: u64) -> u64 {
-    return addr & ALIGN_4K_MASK;
+impl TranslationTree for PageTableTree {
+    fn root(&self) -> PageTable {
+        self.root
+    }
+
+    fn start_level(&self) -> usize {
+        self.start_level
+    }
+
+    fn granule(&self) -> Granule {
+        self.granule
+    }
+
+    fn is_stage2(&self) -> bool {
+        false
+    }
 }
 
-pub struct PageTableTree {
-    pub zeroeth: PageTable,
-    first: PageTable,
-    second: PageTable,
-    third: PageTable,
+/// A stage 2 translation table tree, translating a guest's IPAs.
+///
+/// The IPA space is as large as the physical address space, and the
+/// initial lookup table may be several concatenated tables, see
+/// Granule::stage2_start_level().
+pub struct PageTableTreeStage2 {
+    granule: Granule,
+    pa_range: PhysAddrRange,
+    ipa_bits: u64,
+    start_level: usize,
+    root: PageTable,
 }
 
-impl PageTableTree {
-    pub fn new() -> PageTableTree {
-        PageTableTree {
-            zeroeth: PageTable::new(),
-            first: PageTable::new(),
-            second: PageTable::new(),
-            third: PageTable::new(),
+impl PageTableTreeStage2 {
+    pub fn new(mem: &mut dyn PhysicalMemory,
+               granule: Granule,
+               pa_range: PhysAddrRange) -> Result<PageTableTreeStage2, MapError> {
+        let ipa_bits = pa_range.bits();
+        let start_level = granule.stage2_start_level(ipa_bits);
+        let entries = granule.root_entries(ipa_bits, start_level);
+
+        Ok(PageTableTreeStage2 {
+            granule: granule,
+            pa_range: pa_range,
+            ipa_bits: ipa_bits,
+            start_level: start_level,
+            root: alloc_table(mem, granule, entries)?,
+        })
+    }
+
+    /// The physical address of the initial lookup table, as written to
+    /// VTTBR_EL2
+    pub fn root_address(&self) -> u64 {
+        self.root.address()
+    }
+
+    /// The size of the IPA space, VTCR_EL2.T0SZ == 64 - ipa_bits
+    pub fn ipa_bits(&self) -> u64 {
+        self.ipa_bits
+    }
+
+    /// Map [ipa, ipa + size) to [paddr, paddr + size) with `flags`.
+    ///
+    /// Both addresses and the size only need to be aligned to the
+    /// granule.  1GB (4KB granule only) and level 2 blocks are used
+    /// where the alignment allows, pages elsewhere, see Walk::map_range().
+    pub fn map_range(&mut self,
+                     mem: &mut dyn PhysicalMemory,
+                     ipa: u64,
+                     paddr: u64,
+                     size: u64,
+                     flags: Stage2Flags) -> Result<(), MapError> {
+        let granule = self.granule;
+
+        if ipa + size > (1 << self.ipa_bits) {
+            return Err(MapError::AddressTooLarge(ipa + size - 1));
         }
+
+        let descriptor = |address, level| PageTableEntry::from_block_stage2(address, level, granule, flags);
+
+        Walk::new(self, mem).map_range(self.pa_range, ipa, paddr, size, &descriptor)
+    }
+
+    /// Remove every mapping in [ipa, ipa + size).
+    ///
+    /// This must be called with this tree's VM loaded in VTTBR_EL2,
+    /// since the TLB invalidations are scoped to the current VMID.
+    pub fn unmap_range(&mut self,
+                       mem: &mut dyn PhysicalMemory,
+                       ipa: u64,
+                       size: u64) -> Result<(), MapError> {
+        self.update_range(mem, ipa, size, &mut |_entry, _address, _level| PageTableEntry(0))
+    }
+
+    /// Change the attributes of every mapping in [ipa, ipa + size) to
+    /// `flags`, keeping their output addresses.  See unmap_range() for
+    /// the VTTBR_EL2 requirement.
+    ///
+    /// Pages that dirty.rs or dedup.rs have write-protected stay so until
+    /// the guest's next write, and a page that `flags` makes read-only is
+    /// no longer logged.
+    pub fn protect_range(&mut self,
+                         mem: &mut dyn PhysicalMemory,
+                         ipa: u64,
+                         size: u64,
+                         flags: Stage2Flags) -> Result<(), MapError> {
+        let granule = self.granule;
+        let write = Stage2Access::WriteOnly as u64;
+
+        self.update_range(mem, ipa, size, &mut |entry, _address, level| {
+            let mut new = PageTableEntry::from_block_stage2(entry.output_address(granule),
+                                                            level, granule, flags);
+
+            /* walk_range() leaves the hint only on runs wholly in the range */
+            new.set_contiguous(entry.is_contiguous());
+
+            let mut software = entry.software();
+            if new.s2ap() & write == 0 {
+                software &= !PTE_SW_LOGGED;
+            }
+
+            if software != 0 {
+                new.set_software(software);
+                new.set_dirty_bit_modifier(entry.is_dirty_bit_modifier());
+                new.set_s2ap(new.s2ap() & !write);
+            }
+            new
+        })
+    }
+
+    /// Replace every block and page descriptor mapping [ipa, ipa + size)
+    /// with the one `f` returns, given the descriptor and the IPA and
+    /// level it maps.  Blocks that are only partly inside the range are
+    /// split first.  See unmap_range() for the VTTBR_EL2 requirement.
+    pub fn update_range(&mut self,
+                        mem: &mut dyn PhysicalMemory,
+                        ipa: u64,
+                        size: u64,
+                        f: &mut dyn FnMut(PageTableEntry, u64, usize) -> PageTableEntry)
+                        -> Result<(), MapError> {
+        assert_eq!((ipa | size) & !self.granule.mask(), 0);
+
+        Walk::new(self, mem).walk_range(self.root, self.start_level, ipa, ipa + size, f)?;
+
+        tlbi_vmalle1is();
+        Ok(())
+    }
+
+    /// Calls `f` with every block and page descriptor mapping part of
+    /// [ipa, ipa + size), along with its physical address and the IPA
+    /// and level it maps.  For updates that must not lose a change the
+    /// MMU makes to a descriptor in the meantime, with
+    /// PhysicalMemory::compare_exchange_u64(); the TLB is left to `f`.
+    pub fn for_each_leaf(&self,
+                         mem: &mut dyn PhysicalMemory,
+                         ipa: u64,
+                         size: u64,
+                         f: &mut dyn FnMut(&mut dyn PhysicalMemory, u64, PageTableEntry, u64, usize)) -> () {
+        Walk::new(self, mem).for_each_leaf(self.root, self.start_level, ipa, ipa + size, f)
+    }
+
+    /// Split every block mapping part of [ipa, ipa + size) into pages,
+    /// for when a mapping has to be tracked page by page.  See
+    /// unmap_range() for the VTTBR_EL2 requirement.
+    pub fn split_range(&mut self,
+                       mem: &mut dyn PhysicalMemory,
+                       ipa: u64,
+                       size: u64) -> Result<(), MapError> {
+        assert_eq!((ipa | size) & !self.granule.mask(), 0);
+
+        Walk::new(self, mem).split_range(self.root, self.start_level, ipa, ipa + size)?;
+
+        tlbi_vmalle1is();
+        Ok(())
     }
 
-    pub fn map(&mut self, vaddr: u64, paddr: u64) -> () {
-        let index0 = pagetable_zeroeth_index(vaddr);
-        if self.zeroeth.entries[index0].is_valid() {
-            //loop {}
+    /// Undo a split: if the entry at `level` translating ipa points to a
+    /// table whose leaves map one naturally aligned, physically
+    /// contiguous block with identical attributes, replace it with a
+    /// block descriptor and free the table.  Returns whether it did.
+    ///
+    /// Leaves with software bits set, logged or shared pages, are
+    /// tracked page by page and are never collapsed.
+    pub fn collapse(&mut self,
+                    mem: &mut dyn PhysicalMemory,
+                    ipa: u64,
+                    level: usize) -> Result<bool, MapError> {
+        let granule = self.granule;
+        let size = granule.level_size(level);
+        let ipa = ipa & !(size - 1);
+
+        if level < self.start_level || level >= 3 || !granule.has_blocks_at(level) {
+            return Ok(false);
+        }
+
+        let mut table = self.root;
+        for current in self.start_level..level {
+            let entry = table.read(mem, table.index(ipa, current, granule));
+
+            if !entry.is_valid() || !entry.is_table() {
+                return Ok(false);
+            }
+            table = entry.as_pagetable(granule);
         }
 
-        let index1 = pagetable_first_index(vaddr);
-        if self.first.entries[index1].is_valid() {
-            //loop {}
+        let index = table.index(ipa, level, granule);
+        let entry = table.read(mem, index);
+        if !entry.is_valid() || !entry.is_table() {
+            return Ok(false);
         }
 
-        let index2 = pagetable_second_index(vaddr);
-        if self.second.entries[index2].is_valid() {
-            //loop {}
+        let next = entry.as_pagetable(granule);
+        let next_size = granule.level_size(level + 1);
+        let first = next.read(mem, 0).decode(level + 1, granule, true);
+
+        let (address, attributes) = match (first.output_address(), first.attributes()) {
+            (Some(address), Some(attributes)) => (address, attributes.with_contiguous(false)),
+            _ => return Ok(false),
+        };
+
+        if address & (size - 1) != 0 {
+            return Ok(false);
+        }
+
+        for i in 0..next.len() {
+            let raw = next.read(mem, i);
+            let descriptor = raw.decode(level + 1, granule, true);
+
+            if raw.software() != 0 {
+                return Ok(false);
+            }
+
+            match descriptor.attributes() {
+                Some(other) if other.with_contiguous(false) == attributes => (),
+                _ => return Ok(false),
+            }
+
+            if descriptor.output_address() != Some(address + (i as u64) * next_size) {
+                return Ok(false);
+            }
         }
 
-        let index3 = pagetable_third_index(vaddr);
-        if self.third.entries[index3].is_valid() {
-            loop {}
+        /*
+         * Break-before-make, for the whole block: each of the old leaves
+         * may be in the TLB on its own, so every one of them is
+         * invalidated before the block is written.  Walks cached from
+         * the old table go with the combined entries.
+         */
+        table.write(mem, index, PageTableEntry(0));
+
+        for i in 0..next.len() {
+            tlbi_ipas2e1is(ipa + (i as u64) * next_size);
         }
+        data_barrier(Shareable::Inner);
+        tlbi_vmalle1is();
+
+        let block = Descriptor::Block { address: address, level: level, attributes: attributes };
+        table.write(mem, index, block.encode(granule));
+        data_barrier(Shareable::Inner);
 
-        self.zeroeth.entries[index0] = PageTableEntry::from_table(&self.first);
-        self.first.entries[index1] = PageTableEntry::from_table(&self.second);
-        self.second.entries[index2] = PageTableEntry::from_table(&self.third);
-        self.third.entries[index3] = PageTableEntry::from_block(paddr);
+        mem.free_frames(next.address(), granule.page_size());
+
+        Ok(true)
     }
-}
 
-pub struct PageTableTreeStage2 {
-    pub zeroeth: PageTableConcat,
-    first: PageTable,
+    /// Free every table of the tree, the root included.  The frames it
+    /// maps are left alone, and nothing may use the tree afterwards.
+    pub fn destroy(self, mem: &mut dyn PhysicalMemory) -> () {
+        free_tables(mem, self.root, self.start_level, self.granule);
+
+        let size = align_up((self.root.len() * size_of::<PageTableEntry>()) as u64, self.granule);
+        mem.free_frames(self.root.address(), size);
+    }
 }
 
-impl PageTableTreeStage2 {
-    pub fn new() -> PageTableTreeStage2 {
-        PageTableTreeStage2 {
-            zeroeth: PageTableConcat::new(),
-            first: PageTable::new(),
+/// Free the next level tables that `table`, at `level`, points to, and
+/// any they point to in turn
+fn free_tables(mem: &mut dyn PhysicalMemory, table: PageTable, level: usize, granule: Granule) -> () {
+    if level == 3 {
+        return;
+    }
+
+    for index in 0..table.len() {
+        let entry = table.read(mem, index);
+
+        if entry.is_valid() && entry.is_table() {
+            let next = entry.as_pagetable(granule);
+
+            free_tables(mem, next, level + 1, granule);
+            mem.free_frames(next.address(), granule.page_size());
         }
     }
+}
+
+impl TranslationTree for PageTableTreeStage2 {
+    fn root(&self) -> PageTable {
+        self.root
+    }
 
-    pub fn map(&mut self, vaddr: u64, paddr: u64) -> () {
-        let table_start = pagetable_concat_table(vaddr) * 512;
-        let index1 = pagetable_first_index(vaddr);
-        let index2 = pagetable_second_index(vaddr);
+    fn start_level(&self) -> usize {
+        self.start_level
+    }
 
-        self.zeroeth.entries[table_start + index1] = PageTableEntry::from_table_stage2(&self.first);
-        self.first.entries[index2] = PageTableEntry::from_block_stage2(paddr);
+    fn granule(&self) -> Granule {
+        self.granule
+    }
+
+    fn is_stage2(&self) -> bool {
+        true
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::phys::SimulatedMemory;
+    use crate::walk::{translate, WalkFault};
+    use crate::aarch64::{Tlbi, tlbi_log};
+
+    const RAM_BASE: u64 = 0x4000_0000;
+    const GRANULES: [Granule; 3] = [Granule::Kb4, Granule::Kb16, Granule::Kb64];
+
+    fn memory() -> SimulatedMemory {
+        SimulatedMemory::new(RAM_BASE, 16 << 20)
+    }
+
+    fn stage1(mem: &mut SimulatedMemory, granule: Granule) -> PageTableTree {
+        PageTableTree::new(mem, granule, PhysAddrRange::new(PARANGE_48_BITS, granule)).unwrap()
+    }
+
+    /* A 40-bit IPA space, as on the Cortex-A53 */
+    fn stage2(mem: &mut SimulatedMemory, granule: Granule) -> PageTableTreeStage2 {
+        PageTableTreeStage2::new(mem, granule, PhysAddrRange::new(0b0010, granule)).unwrap()
+    }
+
+    #[test]
+    fn descriptors_round_trip() {
+        let stage1 = Attributes::Stage1(Stage1Attributes {
+            attr_index: 3,
+            non_secure: true,
+            read_only: true,
+            shareability: Shareability::OuterShareable,
+            access_flag: true,
+            non_global: true,
+            contiguous: true,
+            execute_never: true,
+            software: 0b1010,
+        });
+        let stage2 = Attributes::Stage2(Stage2Attributes {
+            mem_attr: 0b1111,
+            access: Stage2Access::WriteOnly,
+            shareability: Shareability::InnerShareable,
+            access_flag: false,
+            dirty_bit_modifier: true,
+            contiguous: false,
+            execute_never: true,
+            software: 0b0101,
+        });
+
+        for &granule in GRANULES.iter() {
+            let address = 0x1234 << granule.level_shift(2);
+
+            let descriptors = [
+                (Descriptor::Table { address: address, non_secure: true }, 1, false),
+                (Descriptor::Block { address: address, level: 2, attributes: stage1 }, 2, false),
+                (Descriptor::Page { address: address, attributes: stage1 }, 3, false),
+                (Descriptor::Block { address: address, level: 2, attributes: stage2 }, 2, true),
+                (Descriptor::Page { address: address, attributes: stage2 }, 3, true),
+                (Descriptor::Invalid, 3, false),
+            ];
+
+            for &(descriptor, level, is_stage2) in descriptors.iter() {
+                let entry = descriptor.encode(granule);
+                assert_eq!(entry.decode(level, granule, is_stage2), descriptor);
+            }
+        }
+    }
+
+    #[test]
+    fn stage2_granule_support_falls_back_to_stage1() {
+        /* 4KB and 64KB at stage 1, no 16KB */
+        let stage1 = 0;
+        assert!(Granule::Kb4.is_supported_stage2(stage1));
+        assert!(!Granule::Kb16.is_supported_stage2(stage1));
+        assert!(Granule::Kb64.is_supported_stage2(stage1));
+
+        /* TGRAN4_2 says no 4KB at stage 2, TGRAN16_2 says 16KB */
+        let mmfr0 = stage1 | (0b0001 << ID_AA64MMFR0_TGRAN4_2_SHIFT) | (0b0010 << ID_AA64MMFR0_TGRAN16_2_SHIFT);
+        assert!(Granule::Kb4.is_supported(mmfr0));
+        assert!(!Granule::Kb4.is_supported_stage2(mmfr0));
+        assert!(Granule::Kb16.is_supported_stage2(mmfr0));
+        assert_eq!(Granule::select(mmfr0), Granule::Kb64);
+    }
+
+    #[test]
+    fn high_output_address_with_64k_granule() {
+        let address = 0xf_0000_0001_0000;
+        let entry = Descriptor::Table { address: address, non_secure: false }.encode(Granule::Kb64);
+
+        assert_eq!(entry.output_address(Granule::Kb64), address);
+        assert_eq!(entry.0 & bitfield(51, 48), 0);
+    }
+
+    #[test]
+    fn level_3_descriptor_without_table_bit_is_invalid() {
+        let entry = PageTableEntry::from_block_at_level(0x20_0000, 2, Granule::Kb4, MapFlags::normal());
+
+        assert!(entry.is_leaf(2));
+        assert_eq!(entry.decode(3, Granule::Kb4, false), Descriptor::Invalid);
+    }
+
+    #[test]
+    fn map_page_and_translate() {
+        for &granule in GRANULES.iter() {
+            let mut mem = memory();
+            let mut tree = stage1(&mut mem, granule);
+            let vaddr = 0x8000_0000 + 5 * granule.page_size();
+            let paddr = 0x9000_0000;
+
+            tree.map(&mut mem, vaddr, paddr, MapFlags::device().read_only()).unwrap();
+
+            let translation = translate(&tree, &mem, vaddr + 8).unwrap();
+            assert_eq!(translation.output_address, paddr + 8);
+            assert_eq!(translation.level, 3);
+            assert_eq!(translation.block_size, granule.page_size());
+
+            match translation.descriptor.decode(3, granule, false).attributes() {
+                Some(Attributes::Stage1(attributes)) => {
+                    assert_eq!(attributes.memory_type(), Some(MemoryType::Device_nGnRE));
+                    assert!(attributes.read_only);
+                    assert!(attributes.execute_never);
+                    assert!(attributes.access_flag);
+                },
+                other => panic!("unexpected attributes {:?}", other),
+            }
+
+            let next = vaddr + granule.page_size();
+            assert_eq!(translate(&tree, &mem, next).unwrap_err().fault, WalkFault::Invalid);
+        }
+    }
+
+    #[test]
+    fn map_range_uses_largest_blocks() {
+        let granule = Granule::Kb4;
+        let mut mem = memory();
+        let mut tree = stage1(&mut mem, granule);
+
+        /* A 1GB block, then a 2MB block, then a page */
+        let size = (1 << 30) + (2 << 20) + 0x1000;
+        tree.map_range(&mut mem, 0x4000_0000, 0x4000_0000, size, MapFlags::normal()).unwrap();
+
+        assert_eq!(translate(&tree, &mem, 0x4000_0000).unwrap().level, 1);
+        assert_eq!(translate(&tree, &mem, 0x8000_0000).unwrap().level, 2);
+        assert_eq!(translate(&tree, &mem, 0x8020_0000).unwrap().level, 3);
+        assert!(translate(&tree, &mem, 0x8020_1000).is_err());
+    }
+
+    #[test]
+    fn map_range_sets_contiguous_hint_on_aligned_runs() {
+        for &granule in GRANULES.iter() {
+            let mut mem = memory();
+            let mut tree = stage1(&mut mem, granule);
+            let run = granule.page_size() * granule.contiguous_entries(3) as u64;
+            let vaddr = 0x10_0000_0000;
+
+            /* One page, then a whole run, then one more page */
+            tree.map_range(&mut mem, vaddr + run - granule.page_size(), 0x4000_0000 + run - granule.page_size(),
+                           run + 2 * granule.page_size(), MapFlags::normal()).unwrap();
+
+            let contiguous = |address: u64| {
+                translate(&tree, &mem, address).unwrap().descriptor.is_contiguous()
+            };
+
+            assert!(!contiguous(vaddr + run - granule.page_size()));
+            assert!(contiguous(vaddr + run));
+            assert!(contiguous(vaddr + 2 * run - granule.page_size()));
+            assert!(!contiguous(vaddr + 2 * run));
+        }
+    }
+
+    #[test]
+    fn unmap_splits_blocks() {
+        for &granule in GRANULES.iter() {
+            let mut mem = memory();
+            let mut tree = stage1(&mut mem, granule);
+            let block = granule.level_size(2);
+            let page = granule.page_size();
+            let vaddr = 0x20_0000_0000;
+            let paddr = 0x8000_0000;
+
+            tree.map_range(&mut mem, vaddr, paddr, block, MapFlags::normal()).unwrap();
+            assert_eq!(translate(&tree, &mem, vaddr).unwrap().level, 2);
+
+            tree.unmap_range(&mut mem, vaddr + page, page).unwrap();
+
+            let before = translate(&tree, &mem, vaddr).unwrap();
+            assert_eq!(before.level, 3);
+            assert_eq!(before.output_address, paddr);
+            assert!(translate(&tree, &mem, vaddr + page).is_err());
+            assert_eq!(translate(&tree, &mem, vaddr + 2 * page).unwrap().output_address,
+                       paddr + 2 * page);
+            assert_eq!(translate(&tree, &mem, vaddr + block - page).unwrap().output_address,
+                       paddr + block - page);
+        }
+    }
+
+    #[test]
+    fn partial_unmap_clears_contiguous_hint_on_whole_run() {
+        let granule = Granule::Kb4;
+        let mut mem = memory();
+        let mut tree = stage1(&mut mem, granule);
+        let vaddr = 0x30_0000_0000;
+
+        tree.map_range(&mut mem, vaddr, 0x4001_0000, 0x1_0000, MapFlags::normal()).unwrap();
+        assert!(translate(&tree, &mem, vaddr).unwrap().descriptor.is_contiguous());
+
+        tree.unmap_range(&mut mem, vaddr + 0x3000, 0x1000).unwrap();
+
+        for page in 0..16 {
+            let address = vaddr + page * 0x1000;
+            match translate(&tree, &mem, address) {
+                Ok(translation) => {
+                    assert!(!translation.descriptor.is_contiguous());
+                    assert_eq!(translation.output_address, 0x4001_0000 + page * 0x1000);
+                },
+                Err(_) => assert_eq!(page, 3),
+            }
+        }
+    }
+
+    #[test]
+    fn protect_changes_attributes_only() {
+        let granule = Granule::Kb16;
+        let mut mem = memory();
+        let mut tree = stage1(&mut mem, granule);
+        let vaddr = 0x4000_0000;
+        let size = 4 * granule.page_size();
+
+        tree.map_range(&mut mem, vaddr, 0x5000_0000, size, MapFlags::normal()).unwrap();
+        tree.protect_range(&mut mem, vaddr + granule.page_size(), granule.page_size(),
+                           MapFlags::normal().read_only().execute_never()).unwrap();
+
+        let protected = translate(&tree, &mem, vaddr + granule.page_size()).unwrap();
+        assert_eq!(protected.output_address, 0x5000_0000 + granule.page_size());
+        assert!(protected.descriptor.is_read_only());
+        assert!(protected.descriptor.is_execute_never());
+
+        let untouched = translate(&tree, &mem, vaddr).unwrap();
+        assert!(!untouched.descriptor.is_read_only());
+    }
+
+    #[test]
+    fn map_conflicts() {
+        let granule = Granule::Kb4;
+        let mut mem = memory();
+        let mut tree = stage1(&mut mem, granule);
+
+        tree.map_range(&mut mem, 0x4000_0000, 0x4000_0000, 2 << 20, MapFlags::normal()).unwrap();
+
+        assert_eq!(tree.map(&mut mem, 0x4000_1000, 0x9000_0000, MapFlags::normal()),
+                   Err(MapError::BlockInTheWay(0x4000_1000)));
+
+        tree.map(&mut mem, 0x5000_0000, 0x9000_0000, MapFlags::normal()).unwrap();
+        assert_eq!(tree.map(&mut mem, 0x5000_0000, 0x9000_1000, MapFlags::normal()),
+                   Err(MapError::AlreadyMapped(0x5000_0000)));
+    }
+
+    #[test]
+    fn failed_map_frees_the_tables_it_allocated() {
+        let granule = Granule::Kb4;
+
+        /* The root and two more tables, one short of mapping a page */
+        let mut mem = SimulatedMemory::new(RAM_BASE, 3 * 0x1000);
+        let mut tree = stage1(&mut mem, granule);
+
+        assert_eq!(tree.map(&mut mem, 0x4000_0000, 0x4000_0000, MapFlags::normal()),
+                   Err(MapError::OutOfMemory));
+        assert_eq!(mem.allocated(), 0x1000);
+        assert!(!tree.root.read(&mem, tree.root.index(0x4000_0000, 0, granule)).is_valid());
+    }
+
+    #[test]
+    fn output_address_beyond_pa_range() {
+        let granule = Granule::Kb4;
+        let mut mem = memory();
+        let mut tree = PageTableTree::new(&mut mem, granule, PhysAddrRange::new(0b0000, granule)).unwrap();
+
+        assert_eq!(tree.map(&mut mem, 0x1000, 1 << 32, MapFlags::normal()),
+                   Err(MapError::AddressTooLarge((1 << 32) + 0xfff)));
+    }
+
+    #[test]
+    fn stage2_concatenated_root() {
+        /* 2 concatenated level 1 tables, 16 concatenated level 2 tables, one level 2 table */
+        let shapes = [(Granule::Kb4, 1, 1024), (Granule::Kb16, 2, 32768), (Granule::Kb64, 2, 2048)];
+
+        for &(granule, start_level, entries) in shapes.iter() {
+            let mut mem = memory();
+            let mut tree = stage2(&mut mem, granule);
+
+            assert_eq!(tree.start_level(), start_level);
+            assert_eq!(tree.root().len(), entries);
+
+            /* The top of the IPA space is in the last concatenated table */
+            let ipa = (1 << 40) - granule.level_size(2);
+            tree.map_range(&mut mem, ipa, 0x8000_0000, granule.level_size(2), Stage2Flags::normal()).unwrap();
+
+            assert_eq!(tree.ipa_bits(), 40);
+
+            let translation = translate(&tree, &mem, ipa + 0x10).unwrap();
+            assert_eq!(translation.output_address, 0x8000_0010);
+            assert_eq!(translation.level, 2);
+
+            tree.protect_range(&mut mem, ipa, granule.page_size(), Stage2Flags::normal().read_only()).unwrap();
+            let translation = translate(&tree, &mem, ipa).unwrap();
+            assert_eq!(translation.level, 3);
+            assert_eq!(translation.descriptor.s2ap(), Stage2Access::ReadOnly as u64);
+            assert_eq!(translate(&tree, &mem, ipa + granule.page_size()).unwrap().descriptor.s2ap(),
+                       Stage2Access::ReadWrite as u64);
+        }
+    }
+
+    #[test]
+    fn stage2_map_range_at_any_granule_alignment() {
+        let granule = Granule::Kb4;
+        let mut mem = memory();
+        let mut tree = stage2(&mut mem, granule);
+
+        /* Pages up to the first 2MB boundary, 2MB blocks, a 1GB block, then pages again */
+        let ipa = 0x3fa0_3000;
+        let paddr = 0x1_3fa0_3000;
+        let size = (0x4000_0000 - ipa) + (1 << 30) + 0x5000;
+        tree.map_range(&mut mem, ipa, paddr, size, Stage2Flags::normal()).unwrap();
+
+        let expected = [
+            (ipa, 3),
+            (0x3fbf_f000, 3),
+            (0x3fc0_0000, 2),
+            (0x3fe0_0000, 2),
+            (0x4000_0000, 1),
+            (0x8000_4000, 3),
+        ];
+
+        for &(address, level) in expected.iter() {
+            let translation = translate(&tree, &mem, address).unwrap();
+            assert_eq!(translation.level, level);
+            assert_eq!(translation.output_address, address - ipa + paddr);
+        }
+
+        assert!(translate(&tree, &mem, ipa - 0x1000).is_err());
+        assert!(translate(&tree, &mem, 0x8000_5000).is_err());
+
+        assert_eq!(tree.map_range(&mut mem, 0x8000_4000, 0x1000, 0x1000, Stage2Flags::normal()),
+                   Err(MapError::AlreadyMapped(0x8000_4000)));
+        assert_eq!(tree.map_range(&mut mem, (1 << 40) - 0x1000, 0x1000, 0x2000,
+                                  Stage2Flags::normal()),
+                   Err(MapError::AddressTooLarge((1 << 40) + 0xfff)));
+    }
+
+    #[test]
+    fn stage2_attributes() {
+        let granule = Granule::Kb4;
+        let mut mem = memory();
+        let mut tree = stage2(&mut mem, granule);
+
+        tree.map_range(&mut mem, 0x0, 0x4000_0000, 0x1000, Stage2Flags::normal().read_only()).unwrap();
+        tree.map_range(&mut mem, 0x0900_0000, 0x0900_0000, 0x1000, Stage2Flags::device()).unwrap();
+
+        let firmware = translate(&tree, &mem, 0x0).unwrap().descriptor;
+        match firmware.decode(3, granule, true).attributes() {
+            Some(Attributes::Stage2(attributes)) => {
+                assert_eq!(attributes.memory_type(), Some(Stage2MemoryType::NormalWriteBack));
+                assert_eq!(attributes.access, Stage2Access::ReadOnly);
+                assert!(!attributes.execute_never);
+            },
+            other => panic!("unexpected attributes {:?}", other),
+        }
+
+        let mmio = translate(&tree, &mem, 0x0900_0000).unwrap().descriptor;
+        match mmio.decode(3, granule, true).attributes() {
+            Some(Attributes::Stage2(attributes)) => {
+                assert_eq!(attributes.memory_type(), Some(Stage2MemoryType::Device_nGnRE));
+                assert_eq!(attributes.access, Stage2Access::ReadWrite);
+                assert!(attributes.execute_never);
+            },
+            other => panic!("unexpected attributes {:?}", other),
+        }
+
+        /* Protecting rewrites every attribute, not just the access */
+        tree.protect_range(&mut mem, 0x0, 0x1000, Stage2Flags::normal().execute_never()).unwrap();
+        let firmware = translate(&tree, &mem, 0x0).unwrap();
+        assert_eq!(firmware.output_address, 0x4000_0000);
+        match firmware.descriptor.decode(3, granule, true).attributes() {
+            Some(Attributes::Stage2(attributes)) => {
+                assert_eq!(attributes.memory_type(), Some(Stage2MemoryType::NormalWriteBack));
+                assert_eq!(attributes.access, Stage2Access::ReadWrite);
+                assert!(attributes.execute_never);
+            },
+            other => panic!("unexpected attributes {:?}", other),
+        }
+
+        /* The tables on the way carry nothing but their address */
+        let root_entry = tree.root().read(&mem, 0);
+        assert_eq!(root_entry, Descriptor::Table {
+            address: root_entry.output_address(granule),
+            non_secure: false,
+        }.encode(granule));
+    }
+
+    #[test]
+    fn stage2_root_follows_pa_range() {
+        /* A 44-bit PA range, as on the Cortex-A57: one 32 entry level 0 table */
+        let mut mem = memory();
+        let granule = Granule::Kb4;
+        let tree = PageTableTreeStage2::new(&mut mem, granule, PhysAddrRange::new(0b0100, granule)).unwrap();
+
+        assert_eq!(tree.ipa_bits(), 44);
+        assert_eq!(tree.start_level(), 0);
+        assert_eq!(tree.root().len(), 32);
+    }
+
+    /* Records the values written to one address, and the invalidations
+     * done before each write */
+    struct WatchedMemory {
+        mem: SimulatedMemory,
+        watch: u64,
+        writes: Vec<u64>,
+        tlbis: Vec<Vec<Tlbi>>,
+    }
+
+    impl PhysicalMemory for WatchedMemory {
+        fn read_u64(&self, address: u64) -> u64 {
+            self.mem.read_u64(address)
+        }
+
+        fn write_u64(&mut self, address: u64, value: u64) -> () {
+            if address == self.watch {
+                self.writes.push(value);
+                self.tlbis.push(tlbi_log());
+            }
+            self.mem.write_u64(address, value)
+        }
+
+        fn alloc_frames(&mut self, size: u64, align: u64) -> Option<u64> {
+            self.mem.alloc_frames(size, align)
+        }
+
+        fn free_frames(&mut self, address: u64, size: u64) -> () {
+            self.mem.free_frames(address, size)
+        }
+    }
+
+    #[test]
+    fn stage2_updates_break_before_make() {
+        let granule = Granule::Kb4;
+        let mut mem = memory();
+        let mut tree = stage2(&mut mem, granule);
+
+        tree.map_range(&mut mem, 0x4000_0000, 0x4000_0000, 0x20_0000, Stage2Flags::normal()).unwrap();
+        let block = translate(&tree, &mem, 0x4000_0000).unwrap();
+        assert_eq!(block.level, 2);
+
+        /* The root is at level 1, its entry 1 points to the block's table */
+        let level2 = tree.root().read(&mem, 1).as_pagetable(granule);
+        let watch = level2.address() + 8 * level2.index(0x4000_0000, 2, granule) as u64;
+        assert_eq!(mem.read_u64(watch), block.descriptor.0);
+
+        let mut watched = WatchedMemory { mem: mem, watch: watch, writes: Vec::new(), tlbis: Vec::new() };
+
+        /* Splitting the block: invalid first, then the new table */
+        tree.protect_range(&mut watched, 0x4000_0000, 0x1000, Stage2Flags::normal().read_only()).unwrap();
+        assert_eq!(watched.writes.len(), 2);
+        assert_eq!(watched.writes[0], 0);
+        assert!(PageTableEntry(watched.writes[1]).is_table());
+
+        /* Unmapping a page needs only the break */
+        let table = PageTableEntry(watched.writes[1]).as_pagetable(granule);
+        watched.watch = table.address() + 8;
+        watched.writes.clear();
+        tree.unmap_range(&mut watched, 0x4000_1000, 0x1000).unwrap();
+        assert_eq!(watched.writes, [0]);
+
+        /* An update that changes nothing writes nothing */
+        watched.watch = table.address();
+        watched.writes.clear();
+        tree.update_range(&mut watched, 0x4000_0000, 0x2000, &mut |entry, _address, _level| entry).unwrap();
+        assert!(watched.writes.is_empty());
+    }
+
+    #[test]
+    fn stage2_runs_break_as_a_whole() {
+        let granule = Granule::Kb4;
+        let mut mem = memory();
+        let mut tree = stage2(&mut mem, granule);
+
+        tree.map_range(&mut mem, 0x4000_0000, 0x4000_0000, 0x1_0000, Stage2Flags::normal()).unwrap();
+        assert!(translate(&tree, &mem, 0x4000_0000).unwrap().descriptor.is_contiguous());
+
+        let level2 = tree.root().read(&mem, 1).as_pagetable(granule);
+        let level3 = level2.read(&mem, level2.index(0x4000_0000, 2, granule)).as_pagetable(granule);
+        let mut watched = WatchedMemory { mem: mem, watch: level3.address(), writes: Vec::new(), tlbis: Vec::new() };
+
+        /* Every page of the run is invalidated before the first one is rewritten */
+        tlbi_log();
+        tree.protect_range(&mut watched, 0x4000_0000, 0x1_0000, Stage2Flags::normal().read_only()).unwrap();
+        let pages: Vec<Tlbi> = (0..16).map(|i| Tlbi::Ipas2e1is(0x4000_0000 + i * 0x1000)).collect();
+        assert_eq!(watched.writes.len(), 2);
+        assert_eq!(watched.writes[0], 0);
+        assert_eq!(watched.tlbis[1][..16], pages[..]);
+        assert_eq!(watched.tlbis[1][16..], [Tlbi::Vmalle1is]);
+
+        let mem = watched.mem;
+        for page in 0..16 {
+            let translation = translate(&tree, &mem, 0x4000_0000 + page * 0x1000).unwrap();
+            assert!(translation.descriptor.is_contiguous());
+            assert_eq!(translation.descriptor.0, watched.writes[1] + page * 0x1000);
+        }
+    }
+
+    #[test]
+    fn stage2_blocks_split_and_collapse() {
+        let granule = Granule::Kb4;
+        let mut mem = memory();
+        let mut tree = stage2(&mut mem, granule);
+
+        tree.map_range(&mut mem, 0x4000_0000, 0x4020_0000, 0x20_0000, Stage2Flags::normal()).unwrap();
+        let block = translate(&tree, &mem, 0x4000_0000).unwrap().descriptor;
+
+        tree.split_range(&mut mem, 0x4000_3000, 0x1000).unwrap();
+        let page = translate(&tree, &mem, 0x4001_2345).unwrap();
+        assert_eq!((page.level, page.output_address), (3, 0x4021_2345));
+
+        let allocated = mem.allocated();
+        let level2 = tree.root().read(&mem, 1).as_pagetable(granule);
+        let mut watched = WatchedMemory { mem: mem, watch: level2.address(), writes: Vec::new(), tlbis: Vec::new() };
+
+        tlbi_log();
+        assert!(tree.collapse(&mut watched, 0x4000_0000, 2).unwrap());
+
+        /* Every page of the block is invalidated between the break and the make */
+        let pages: Vec<Tlbi> = (0..512).map(|i| Tlbi::Ipas2e1is(0x4000_0000 + i * 0x1000)).collect();
+        assert_eq!(watched.writes, [0, block.0]);
+        assert_eq!(watched.tlbis[1][..512], pages[..]);
+        assert_eq!(watched.tlbis[1][512..], [Tlbi::Vmalle1is]);
+
+        let mut mem = watched.mem;
+        assert_eq!(translate(&tree, &mem, 0x4000_0000).unwrap().descriptor, block);
+        assert_eq!(mem.allocated(), allocated - 0x1000);
+
+        /* Already a block, and pages that differ, stay as they are */
+        assert!(!tree.collapse(&mut mem, 0x4000_0000, 2).unwrap());
+        tree.protect_range(&mut mem, 0x4000_5000, 0x1000, Stage2Flags::normal().execute_never()).unwrap();
+        assert!(!tree.collapse(&mut mem, 0x4000_0000, 2).unwrap());
+        assert_eq!(translate(&tree, &mem, 0x4000_0000).unwrap().level, 3);
+    }
+}
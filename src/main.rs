@@ -1,12 +1,19 @@
-#![no_std]
-#![no_main]
-#![feature(custom_test_frameworks)]
+/*
+ * Under `cargo test` the crate is built for the host with std, so the
+ * translation table code can be tested against simulated memory.
+ * Anything that only makes sense on the target is left out.
+ */
+#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(test), no_main)]
 #![feature(lang_items)]
 #![feature(asm)]
 #![feature(trace_macros)]
 #![feature(const_fn)]
+#![feature(alloc_error_handler)]
 #![allow(dead_code)]
 
+extern crate alloc;
+
 mod lpae;
 mod common;
 mod frame_alloc;
@@ -15,15 +22,29 @@ mod memory_attrs;
 mod uart;
 mod aarch64;
 mod vm;
+mod walk;
+mod phys;
+mod vmap;
+mod vmid;
+mod dirty;
+mod guest_mem;
+mod dedup;
+mod scrub;
+mod heap;
+mod fdt;
+mod memmap;
+mod spinlock;
 
 
+#[cfg(not(test))]
 use core::panic::PanicInfo;
 
+#[cfg(not(test))]
 mod irq;
 
-#[cfg(not(feature="hypervisor_test"))]
+#[cfg(not(any(test, feature="hypervisor_test")))]
 mod start;
-#[cfg(not(feature="hypervisor_test"))]
+#[cfg(not(any(test, feature="hypervisor_test")))]
 pub use start::start_hypervisor;
 
 #[cfg(feature="hypervisor_test")]
@@ -33,22 +54,22 @@ mod test;
 pub use test::start_hypervisor;
 
 
+#[cfg(not(test))]
 #[panic_handler]
 fn handler(_x: &PanicInfo) -> ! {
     loop {}
 }
 
+#[cfg(not(test))]
+#[alloc_error_handler]
+fn alloc_error(_layout: core::alloc::Layout) -> ! {
+    loop {}
+}
+
+#[cfg(not(test))]
 #[lang = "eh_unwind_resume"]
 extern "C" fn rust_eh_unwind_resume() {}
 
-#[cfg(test)]
-fn test_runner(tests: &[&dyn Fn()]) {
-        println!("Running {} tests", tests.len());
-            for test in tests {
-                        test();
-                            }
-}
-
 #[cfg(test)]
 mod tests {
     #[test]
@@ -0,0 +1,373 @@
+/*
+ * The physical memory map: the banks of RAM, and the ranges in them
+ * that must never be handed out, each with the owner it is kept for.
+ *
+ * start_hypervisor() records the hypervisor image, its stack, the DTB,
+ * the guest image the QEMU loader device placed, and whatever the DTB
+ * reserves.  The frame allocator is then seeded from the map, see
+ * seed(), and report() prints where the memory went.
+ *
+ * Reserved ranges may overlap, a DTB often reserves itself, say.  The
+ * map is filled in before the UART is up, so running out of slots is
+ * not fatal: a bank that does not fit is left out, a reservation that
+ * does not fit is merged into its nearest neighbour, and report() owns
+ * up to both.
+ */
+
+use alloc::boxed::Box;
+use core::ops::{Deref, DerefMut};
+
+use crate::uart::uart_write;
+use crate::common::print_hex;
+use crate::frame_alloc::{FrameAllocator, FrameStats};
+use crate::lpae::PAGE_SIZE;
+use crate::spinlock::{SpinLock, SpinLockGuard};
+
+const MAX_RAM_BANKS: usize = 8;
+const MAX_RESERVED: usize = 32;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Owner {
+    /// The hypervisor's code and data, `vectors` up to its stack
+    Hypervisor,
+    Stack,
+    Dtb,
+
+    /// A guest image placed in RAM before boot
+    GuestImage,
+
+    /// Reserved by the DTB, for firmware or a secure monitor say
+    Firmware,
+
+    /// The frame allocator's own bitmaps
+    FrameBitmap,
+}
+
+impl Owner {
+    pub fn name(self) -> &'static str {
+        match self {
+            Owner::Hypervisor => "hypervisor",
+            Owner::Stack => "stack",
+            Owner::Dtb => "dtb",
+            Owner::GuestImage => "guest image",
+            Owner::Firmware => "firmware",
+            Owner::FrameBitmap => "frame bitmap",
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Region {
+    pub start: u64,
+    pub size: u64,
+    pub owner: Option<Owner>,
+}
+
+impl Region {
+    pub fn end(&self) -> u64 {
+        self.start + self.size
+    }
+}
+
+pub struct MemoryMap {
+    ram: [Option<Region>; MAX_RAM_BANKS],
+    reserved: [Option<Region>; MAX_RESERVED],
+
+    /* The bytes the allocator started out without, see seed() */
+    reserved_bytes: u64,
+
+    /* Banks and reservations that did not fit, see report() */
+    dropped_banks: u64,
+    merged_reservations: u64,
+}
+
+impl MemoryMap {
+    pub fn new() -> MemoryMap {
+        MemoryMap {
+            ram: [None; MAX_RAM_BANKS],
+            reserved: [None; MAX_RESERVED],
+            reserved_bytes: 0,
+            dropped_banks: 0,
+            merged_reservations: 0,
+        }
+    }
+
+    pub fn add_ram(&mut self, start: u64, size: u64) -> () {
+        let slot = match self.ram.iter().position(|bank| bank.is_none()) {
+            Some(slot) => slot,
+            None => {
+                self.dropped_banks += 1;
+                return;
+            },
+        };
+
+        self.ram[slot] = Some(Region { start: start, size: size, owner: None });
+    }
+
+    /// Keep [start, start + size) for owner
+    pub fn reserve(&mut self, start: u64, size: u64, owner: Owner) -> () {
+        let slot = match self.reserved.iter().position(|region| region.is_none()) {
+            Some(slot) => slot,
+            None => {
+                self.merge_reservation(start, size);
+                return;
+            },
+        };
+
+        self.reserved[slot] = Some(Region { start: start, size: size, owner: Some(owner) });
+    }
+
+    /// Grow the reserved range nearest [start, start + size) to cover it
+    /// too.  The frames in between are lost, but nothing that must be
+    /// kept is handed out.
+    fn merge_reservation(&mut self, start: u64, size: u64) -> () {
+        let end = start + size;
+        let distance = |region: &Region| {
+            if region.end() < start {
+                start - region.end()
+            } else if region.start > end {
+                region.start - end
+            } else {
+                0
+            }
+        };
+
+        let nearest = self.reserved.iter_mut()
+            .filter_map(|region| region.as_mut())
+            .min_by_key(|region| distance(region))
+            .unwrap();
+
+        let merged_start = if nearest.start < start { nearest.start } else { start };
+        let merged_end = if nearest.end() > end { nearest.end() } else { end };
+
+        nearest.start = merged_start;
+        nearest.size = merged_end - merged_start;
+        self.merged_reservations += 1;
+    }
+
+    pub fn ram(&self) -> impl Iterator<Item = Region> + '_ {
+        self.ram.iter().filter_map(|bank| *bank)
+    }
+
+    pub fn reserved(&self) -> impl Iterator<Item = Region> + '_ {
+        self.reserved.iter().filter_map(|region| *region)
+    }
+
+    /// The owner of the first reserved range containing address, if any
+    pub fn owner(&self, address: u64) -> Option<Owner> {
+        self.reserved()
+            .find(|region| address >= region.start && address < region.end())
+            .and_then(|region| region.owner)
+    }
+
+    /// The highest page in bank from which `size` bytes are clear of
+    /// every reserved range, if there is one
+    fn bitmap_place(&self, bank: Region, size: u64) -> Option<u64> {
+        let mut end = bank.end();
+
+        while end >= bank.start + size {
+            let start = end - size;
+            let blocked = self.reserved()
+                .filter(|region| region.start < end && start < region.end())
+                .map(|region| region.start)
+                .min();
+
+            match blocked {
+                Some(blocked) => end = blocked & !(PAGE_SIZE as u64 - 1),
+                None => return Some(start),
+            }
+        }
+
+        None
+    }
+
+    /// Give allocator every bank of RAM, less the reserved ranges.  Each
+    /// bank's bitmap goes as high in it as the reserved ranges allow,
+    /// away from the DTB and images boot loaders put at the start of RAM.
+    pub fn seed(&mut self, allocator: &mut FrameAllocator) -> () {
+        let ram = self.ram;
+
+        for (slot, bank) in ram.iter().enumerate() {
+            let bank = match bank {
+                Some(bank) => *bank,
+                None => continue,
+            };
+
+            let bitmap = match self.bitmap_place(bank, FrameAllocator::bitmap_frames(bank.size)) {
+                Some(bitmap) => bitmap,
+                None => {
+                    self.ram[slot] = None;
+                    self.dropped_banks += 1;
+                    continue;
+                },
+            };
+
+            let size = allocator.add_bank_at(bank.start, bank.size, bitmap);
+            self.reserve(bitmap, size, Owner::FrameBitmap);
+        }
+
+        for region in self.reserved.iter().filter_map(|region| *region) {
+            allocator.reserve(region.start, region.size);
+        }
+
+        let stats = allocator.stats();
+        self.reserved_bytes = stats.total - stats.free;
+    }
+
+    /// Print the map, and how much RAM is used, reserved and free
+    pub fn report(&self, stats: FrameStats) -> () {
+        uart_write("Memory map:\n");
+
+        for bank in self.ram() {
+            uart_write("  RAM ");
+            print_hex(bank.start);
+            uart_write(" - ");
+            print_hex(bank.end());
+            uart_write("\n");
+        }
+
+        for region in self.reserved() {
+            uart_write("  reserved ");
+            print_hex(region.start);
+            uart_write(" - ");
+            print_hex(region.end());
+            uart_write(" ");
+            uart_write(region.owner.map(|owner| owner.name()).unwrap_or("?"));
+            uart_write("\n");
+        }
+
+        if self.dropped_banks != 0 {
+            uart_write("  banks left out: ");
+            print_hex(self.dropped_banks);
+            uart_write("\n");
+        }
+
+        if self.merged_reservations != 0 {
+            uart_write("  reservations merged for lack of slots: ");
+            print_hex(self.merged_reservations);
+            uart_write("\n");
+        }
+
+        uart_write("  used ");
+        print_hex(stats.total - stats.free - self.reserved_bytes);
+        uart_write(", reserved ");
+        print_hex(self.reserved_bytes);
+        uart_write(", free ");
+        print_hex(stats.free);
+        uart_write("\n");
+    }
+}
+
+static MEMORY_MAP: SpinLock<Option<Box<MemoryMap>>> = SpinLock::new(None);
+
+/// Keep the boot memory map, once the frame allocator is seeded from it
+pub fn init(map: MemoryMap) -> () {
+    /* Allocated before the lock is taken, see spinlock.rs */
+    let map = Box::new(map);
+
+    *MEMORY_MAP.lock() = Some(map);
+}
+
+/// Exclusive use of the boot memory map, see memory_map()
+pub struct MemoryMapGuard(SpinLockGuard<'static, Option<Box<MemoryMap>>>);
+
+impl Deref for MemoryMapGuard {
+    type Target = MemoryMap;
+
+    fn deref(&self) -> &MemoryMap {
+        self.0.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for MemoryMapGuard {
+    fn deref_mut(&mut self) -> &mut MemoryMap {
+        self.0.as_mut().unwrap()
+    }
+}
+
+/// The boot memory map, see init(), locked until the guard is dropped
+pub fn memory_map() -> MemoryMapGuard {
+    MemoryMapGuard(MEMORY_MAP.lock())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PAGE: u64 = 0x1000;
+
+    #[test]
+    fn allocator_is_seeded_around_reservations() {
+        let size = 0x40_0000;
+        let buffer = Box::leak(vec![0u8; (size + PAGE) as usize].into_boxed_slice());
+        let start = (buffer.as_ptr() as u64 + PAGE - 1) & !(PAGE - 1);
+
+        let mut map = MemoryMap::new();
+        map.add_ram(start, size);
+        map.reserve(start, 0x8_0000, Owner::Dtb);
+        map.reserve(start + 0x4_0000, 0x1_0800, Owner::Firmware);
+        map.reserve(start + 0x10_0000, 0x20_0000, Owner::GuestImage);
+
+        let mut allocator = FrameAllocator::new();
+        map.seed(&mut allocator);
+
+        /* The bitmap goes in the bank's last frame */
+        assert_eq!(map.owner(start + size - PAGE), Some(Owner::FrameBitmap));
+        assert_eq!(map.owner(start + 0x5_0000), Some(Owner::Dtb));
+        assert_eq!(map.owner(start + 0x8_0000), None);
+
+        let stats = allocator.stats();
+        assert_eq!(stats.free, size - 0x28_0000 - PAGE);
+        assert_eq!(map.reserved_bytes, 0x28_0000 + PAGE);
+
+        /* Nothing is handed out from a reserved range */
+        assert_eq!(allocator.alloc_frames(0x8_0000, PAGE), Some(start + 0x8_0000));
+        assert_eq!(allocator.alloc_frames(PAGE, PAGE), Some(start + 0x30_0000));
+    }
+
+    #[test]
+    fn bitmaps_avoid_reservations() {
+        let size = 0x40_0000;
+        let buffer = Box::leak(vec![0u8; (size + PAGE) as usize].into_boxed_slice());
+        let start = (buffer.as_ptr() as u64 + PAGE - 1) & !(PAGE - 1);
+
+        let mut map = MemoryMap::new();
+        map.add_ram(start, size);
+        map.reserve(start + size - 0x1800, 0x1800, Owner::Firmware);
+        map.reserve(start + size - 0x4000, 0x1000, Owner::Dtb);
+
+        let mut allocator = FrameAllocator::new();
+        map.seed(&mut allocator);
+
+        /* In the gap between the reservations at the top of the bank */
+        assert_eq!(map.owner(start + size - 0x3000), Some(Owner::FrameBitmap));
+        assert_eq!(map.owner(start + size - 0x1800), Some(Owner::Firmware));
+        assert_eq!(map.owner(start + size - 0x4000), Some(Owner::Dtb));
+        assert_eq!(map.owner(start + size - 0x5000), None);
+        assert_eq!(allocator.stats().free, size - 0x4000);
+    }
+
+    #[test]
+    fn full_maps_degrade() {
+        let mut map = MemoryMap::new();
+
+        /* Too many banks: the extra ones are left out */
+        for i in 0..MAX_RAM_BANKS + 2 {
+            map.add_ram(0x4000_0000 * (i + 1) as u64, 0x1000_0000);
+        }
+        assert_eq!(map.ram().count(), MAX_RAM_BANKS);
+        assert_eq!(map.dropped_banks, 2);
+
+        /* Too many reservations: the extra ones stretch their nearest */
+        for i in 0..MAX_RESERVED {
+            map.reserve(0x4000_0000 + 0x10_0000 * i as u64, PAGE, Owner::Firmware);
+        }
+        map.reserve(0x4000_0000 + 0x30_8000, PAGE, Owner::Dtb);
+
+        assert_eq!(map.reserved().count(), MAX_RESERVED);
+        assert_eq!(map.merged_reservations, 1);
+        assert_eq!(map.owner(0x4000_0000 + 0x30_8000), Some(Owner::Firmware));
+        assert_eq!(map.owner(0x4000_0000 + 0x30_4000), Some(Owner::Firmware));
+        assert_eq!(map.owner(0x4000_0000 + 0x20_8000), None);
+    }
+}
@@ -6,25 +6,282 @@
  *
  * Refer to MAIR0/MAIR1 documentation in the ARM Reference Manual
  * or the ARM Cortex-A Programmer's Guide.
- */ 
+ */
 
 use crate::msr;
 
+/* MAIR attribute encodings, see D13.2.94 MAIR_EL2 */
+const MAIR_NORMAL_WRITE_BACK: u64 = 0xff;
+const MAIR_NORMAL_NON_CACHEABLE: u64 = 0x44;
+#[allow(non_upper_case_globals)]
+const MAIR_DEVICE_nGnRnE: u64 = 0x00;
+#[allow(non_upper_case_globals)]
+const MAIR_DEVICE_nGnRE: u64 = 0x04;
+
+/// The memory types the hypervisor maps with.
+///
+/// Each discriminant is the AttrIndx used in a stage 1 descriptor,
+/// and the index of the matching attribute programmed into MAIR_EL2.
+#[allow(non_camel_case_types)]
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum MemoryType {
+    /// Normal, Inner/Outer Write-Back Write-Allocate
+    NormalWriteBack = 0,
+
+    /// Normal, Inner/Outer Non-Cacheable
+    NormalNonCacheable = 1,
+
+    /// Device, non-Gathering, non-Reordering, no Early write acknowledgement
+    Device_nGnRnE = 2,
+
+    /// Device, non-Gathering, non-Reordering, Early write acknowledgement
+    Device_nGnRE = 3,
+}
+
+impl MemoryType {
+    pub fn attr_index(self) -> u64 {
+        self as u64
+    }
+
+    fn mair_attr(self) -> u64 {
+        match self {
+            MemoryType::NormalWriteBack => MAIR_NORMAL_WRITE_BACK,
+            MemoryType::NormalNonCacheable => MAIR_NORMAL_NON_CACHEABLE,
+            MemoryType::Device_nGnRnE => MAIR_DEVICE_nGnRnE,
+            MemoryType::Device_nGnRE => MAIR_DEVICE_nGnRE,
+        }
+    }
+
+    /// The MemoryType at `index` in MAIR_EL2, None for unused indices
+    pub fn from_attr_index(index: u64) -> Option<MemoryType> {
+        match index {
+            0 => Some(MemoryType::NormalWriteBack),
+            1 => Some(MemoryType::NormalNonCacheable),
+            2 => Some(MemoryType::Device_nGnRnE),
+            3 => Some(MemoryType::Device_nGnRE),
+            _ => None,
+        }
+    }
+
+    pub fn is_device(self) -> bool {
+        match self {
+            MemoryType::Device_nGnRnE | MemoryType::Device_nGnRE => true,
+            _ => false,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum AccessPermissions {
+    ReadWrite,
+    ReadOnly,
+}
+
+/// The SH[1:0] field of a block or page descriptor
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Shareability {
+    NonShareable = 0b00,
+    OuterShareable = 0b10,
+    InnerShareable = 0b11,
+}
+
+impl Shareability {
+    /// Decode an SH[1:0] field.  0b01 is reserved, and is decoded as
+    /// Non-shareable.
+    pub fn from_bits(bits: u64) -> Shareability {
+        match bits & 0b11 {
+            0b10 => Shareability::OuterShareable,
+            0b11 => Shareability::InnerShareable,
+            _ => Shareability::NonShareable,
+        }
+    }
+}
+
+/// Attributes for a hypervisor (stage 1, EL2) mapping
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct MapFlags {
+    pub memory_type: MemoryType,
+    pub access: AccessPermissions,
+    pub execute_never: bool,
+    pub shareability: Shareability,
+
+    /// nG is only used by the EL2 regime when HCR_EL2.E2H == 1, but
+    /// is encoded here so the flags are complete.
+    pub non_global: bool,
+}
+
+impl MapFlags {
+    /// Read/write, executable, inner-shareable Write-Back memory.
+    /// This is what the hypervisor image and its RAM are mapped with.
+    pub const fn normal() -> MapFlags {
+        MapFlags {
+            memory_type: MemoryType::NormalWriteBack,
+            access: AccessPermissions::ReadWrite,
+            execute_never: false,
+            shareability: Shareability::InnerShareable,
+            non_global: false,
+        }
+    }
+
+    /// Read/write, execute-never Device-nGnRE memory for MMIO.
+    ///
+    /// Device memory is always treated as Outer Shareable by the
+    /// hardware, regardless of the SH field.
+    pub const fn device() -> MapFlags {
+        MapFlags {
+            memory_type: MemoryType::Device_nGnRE,
+            access: AccessPermissions::ReadWrite,
+            execute_never: true,
+            shareability: Shareability::OuterShareable,
+            non_global: false,
+        }
+    }
+
+    pub const fn read_only(self) -> MapFlags {
+        MapFlags { access: AccessPermissions::ReadOnly, ..self }
+    }
+
+    pub const fn execute_never(self) -> MapFlags {
+        MapFlags { execute_never: true, ..self }
+    }
+
+    pub const fn memory_type(self, memory_type: MemoryType) -> MapFlags {
+        MapFlags { memory_type: memory_type, ..self }
+    }
+}
+
+/// Stage 2 access permissions, encoded as the S2AP[1:0] field of a
+/// stage 2 block or page descriptor
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Stage2Access {
+    None = 0b00,
+    ReadOnly = 0b01,
+    WriteOnly = 0b10,
+    ReadWrite = 0b11,
+}
+
+impl Stage2Access {
+    pub fn from_bits(bits: u64) -> Stage2Access {
+        match bits & 0b11 {
+            0b00 => Stage2Access::None,
+            0b01 => Stage2Access::ReadOnly,
+            0b10 => Stage2Access::WriteOnly,
+            _ => Stage2Access::ReadWrite,
+        }
+    }
+}
+
+/// Stage 2 memory types, encoded as the MemAttr[3:0] field of a stage 2
+/// block or page descriptor (with HCR_EL2.FWB == 0).
+///
+/// The stage 2 type is combined with the guest's stage 1 type, and the
+/// more restrictive of the two is used.
+#[allow(non_camel_case_types)]
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Stage2MemoryType {
+    Device_nGnRnE = 0b0000,
+    Device_nGnRE = 0b0001,
+    Device_nGRE = 0b0010,
+    Device_GRE = 0b0011,
+
+    /// Normal, Inner/Outer Non-Cacheable
+    NormalNonCacheable = 0b0101,
+
+    /// Normal, Inner/Outer Write-Through
+    NormalWriteThrough = 0b1010,
+
+    /// Normal, Inner/Outer Write-Back
+    NormalWriteBack = 0b1111,
+}
+
+impl Stage2MemoryType {
+    /// Decode MemAttr[3:0], None for the combinations not listed above
+    pub fn from_mem_attr(mem_attr: u64) -> Option<Stage2MemoryType> {
+        match mem_attr {
+            0b0000 => Some(Stage2MemoryType::Device_nGnRnE),
+            0b0001 => Some(Stage2MemoryType::Device_nGnRE),
+            0b0010 => Some(Stage2MemoryType::Device_nGRE),
+            0b0011 => Some(Stage2MemoryType::Device_GRE),
+            0b0101 => Some(Stage2MemoryType::NormalNonCacheable),
+            0b1010 => Some(Stage2MemoryType::NormalWriteThrough),
+            0b1111 => Some(Stage2MemoryType::NormalWriteBack),
+            _ => None,
+        }
+    }
+
+    pub fn is_device(self) -> bool {
+        (self as u64) & 0b1100 == 0
+    }
+}
+
+/// Attributes for a guest (stage 2) mapping
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Stage2Flags {
+    pub memory_type: Stage2MemoryType,
+    pub access: Stage2Access,
+    pub execute_never: bool,
+    pub shareability: Shareability,
+}
+
+impl Stage2Flags {
+    /// Read/write, executable, inner-shareable Write-Back memory, for
+    /// guest RAM.  The guest's own stage 1 attributes still apply.
+    pub const fn normal() -> Stage2Flags {
+        Stage2Flags {
+            memory_type: Stage2MemoryType::NormalWriteBack,
+            access: Stage2Access::ReadWrite,
+            execute_never: false,
+            shareability: Shareability::InnerShareable,
+        }
+    }
+
+    /// Read/write, execute-never Device-nGnRE memory, for MMIO passed
+    /// through to the guest.
+    pub const fn device() -> Stage2Flags {
+        Stage2Flags {
+            memory_type: Stage2MemoryType::Device_nGnRE,
+            access: Stage2Access::ReadWrite,
+            execute_never: true,
+            shareability: Shareability::OuterShareable,
+        }
+    }
+
+    pub const fn access(self, access: Stage2Access) -> Stage2Flags {
+        Stage2Flags { access: access, ..self }
+    }
+
+    pub const fn read_only(self) -> Stage2Flags {
+        self.access(Stage2Access::ReadOnly)
+    }
+
+    pub const fn execute_never(self) -> Stage2Flags {
+        Stage2Flags { execute_never: true, ..self }
+    }
+
+    pub const fn memory_type(self, memory_type: Stage2MemoryType) -> Stage2Flags {
+        Stage2Flags { memory_type: memory_type, ..self }
+    }
+}
+
+/// Returns the MAIR_EL2 value with every MemoryType at its AttrIndx
+pub fn mair_el2() -> u64 {
+    let types = [
+        MemoryType::NormalWriteBack,
+        MemoryType::NormalNonCacheable,
+        MemoryType::Device_nGnRnE,
+        MemoryType::Device_nGnRE,
+    ];
+
+    types.iter()
+         .fold(0, |mair, t| mair | (t.mair_attr() << (t.attr_index() * 8)))
+}
+
 /**
- * For right now we only configure "Normal, Write-back, Write-allocate"
- * memory.  This is what will be used by the hypervisor.  Once we
- * support I/O, obviously Device memory will need to be supported.
- * Once we support guests and pinning, other shareability/cacheability
- * attributes will probably need support.
+ * Program MAIR_EL2 with every memory type in MemoryType.  The
+ * AttrIndx written into descriptors by lpae.rs is the MemoryType
+ * discriminant.
  */
 pub fn init() -> () {
-    /*
-     * For the sake of simplicity, let's just use use AttrIndex 0 for
-     * Normal Write-Back Write-Allocate memory:
-     *
-     * Attr @ Index 0 of MAIR0 is Normal Write-back Write-Allocate memory
-     */
-
     /* Write to MAIR_EL2 */
-    msr!("mair_el2", 0xff);
+    msr!("mair_el2", mair_el2());
 }
@@ -0,0 +1,145 @@
+/*
+ * Access to physical memory.
+ *
+ * The translation table code never dereferences a physical address
+ * itself.  It reads, writes and allocates through a PhysicalMemory, so
+ * the same code can build the real tables at boot and simulated ones
+ * when the tests run on the host.
+ */
+
+use crate::scrub::scrub_frames;
+
+pub trait PhysicalMemory {
+    /// Read the 64-bit word at the 8-byte aligned physical address
+    fn read_u64(&self, address: u64) -> u64;
+
+    /// Write the 64-bit word at the 8-byte aligned physical address
+    fn write_u64(&mut self, address: u64, value: u64) -> ();
+
+    /// Read the byte at a physical address.  By default this reads the
+    /// 64-bit word holding it, which is little-endian.
+    fn read_u8(&self, address: u64) -> u8 {
+        (self.read_u64(address & !7) >> ((address & 7) * 8)) as u8
+    }
+
+    /// Write the byte at a physical address.  By default this rewrites
+    /// the 64-bit word holding it, so must not race with other writers.
+    fn write_u8(&mut self, address: u64, value: u8) -> () {
+        let shift = (address & 7) * 8;
+        let word = self.read_u64(address & !7) & !(0xff << shift);
+
+        self.write_u64(address & !7, word | ((value as u64) << shift));
+    }
+
+    /// Replace the 64-bit word at an 8-byte aligned physical address with
+    /// `new` if it holds `current`.  Returns what it held, as Ok if it
+    /// was replaced.  By default this reads and then writes the word, so
+    /// must not race with other writers; the real thing is atomic.
+    fn compare_exchange_u64(&mut self, address: u64, current: u64, new: u64) -> Result<u64, u64> {
+        let old = self.read_u64(address);
+
+        if old != current {
+            return Err(old);
+        }
+
+        self.write_u64(address, new);
+        Ok(old)
+    }
+
+    /// Returns the physical address of `size` bytes of contiguous frames
+    /// aligned to `align`, or None if memory has run out.  The frames
+    /// are NOT zeroed.
+    fn alloc_frames(&mut self, size: u64, align: u64) -> Option<u64>;
+
+    /// Give back `size` bytes of frames at `address`, which came from
+    /// alloc_frames() and are no longer mapped anywhere
+    fn free_frames(&mut self, address: u64, size: u64) -> ();
+
+    /// Give back frames that held guest data, which must be scrubbed
+    /// before they are allocated again, see scrub.rs.  By default they
+    /// are scrubbed and freed right away.
+    fn release_frames(&mut self, address: u64, size: u64) -> () {
+        scrub_frames(self, address, size);
+        self.free_frames(address, size)
+    }
+}
+
+#[cfg(test)]
+const FRAME_SIZE: u64 = 0x1000;
+
+/// A block of simulated RAM at a fake physical address, for tests.
+/// Freed 4KB frames are handed out again, most recently freed first.
+#[cfg(test)]
+pub struct SimulatedMemory {
+    base: u64,
+    words: Vec<u64>,
+    next: u64,
+    free: Vec<u64>,
+}
+
+#[cfg(test)]
+impl SimulatedMemory {
+    pub fn new(base: u64, size: u64) -> SimulatedMemory {
+        SimulatedMemory {
+            base: base,
+            words: vec![0; (size / 8) as usize],
+            next: base,
+            free: Vec::new(),
+        }
+    }
+
+    /// The bytes allocated so far, and not freed
+    pub fn allocated(&self) -> u64 {
+        self.next - self.base - self.free.len() as u64 * FRAME_SIZE
+    }
+
+    fn word_index(&self, address: u64) -> usize {
+        assert_eq!(address % 8, 0);
+        assert!(address >= self.base, "{:#x} is below simulated RAM", address);
+
+        let index = ((address - self.base) / 8) as usize;
+        assert!(index < self.words.len(), "{:#x} is above simulated RAM", address);
+        index
+    }
+}
+
+#[cfg(test)]
+impl PhysicalMemory for SimulatedMemory {
+    fn read_u64(&self, address: u64) -> u64 {
+        self.words[self.word_index(address)]
+    }
+
+    fn write_u64(&mut self, address: u64, value: u64) -> () {
+        let index = self.word_index(address);
+        self.words[index] = value;
+    }
+
+    fn alloc_frames(&mut self, size: u64, align: u64) -> Option<u64> {
+        let start = if size == FRAME_SIZE && align == FRAME_SIZE && !self.free.is_empty() {
+            self.free.pop().unwrap()
+        } else {
+            let start = (self.next + align - 1) & !(align - 1);
+            if start + size > self.base + (self.words.len() * 8) as u64 {
+                return None;
+            }
+
+            self.next = start + size;
+            start
+        };
+
+        /* Poison the frames, the caller must not rely on them being zero */
+        for address in (start..start + size).step_by(8) {
+            self.write_u64(address, 0xdead_beef_dead_beef);
+        }
+
+        Some(start)
+    }
+
+    fn free_frames(&mut self, address: u64, size: u64) -> () {
+        for frame in (address..address + size).step_by(FRAME_SIZE as usize) {
+            assert!(frame >= self.base && frame < self.next);
+            assert!(!self.free.contains(&frame), "{:#x} freed twice", frame);
+            self.free.push(frame);
+        }
+    }
+}
@@ -0,0 +1,123 @@
+/*
+ * Scrubbing frames that held guest data.
+ *
+ * A frame that a VM gives up, because the VM is destroyed or because
+ * its page was merged or moved, still holds the guest's data, in RAM
+ * and possibly in the caches.  Before the frame can be handed to
+ * anyone else it is zeroed and cleaned and invalidated to the Point of
+ * Coherency, so that not even a guest running with its caches off can
+ * see what was there.  Such frames are given back with
+ * PhysicalMemory::release_frames() rather than free_frames().
+ *
+ * Zeroing a whole guest's RAM at teardown takes a while, so the frame
+ * allocator only puts released frames on a list of dirty frames, to be
+ * scrubbed from idle time, see FrameAllocator::scrub_dirty_frames().
+ * Dirty frames are never allocated: when the clean ones run out the
+ * allocator scrubs the whole list there and then.
+ */
+
+use crate::phys::PhysicalMemory;
+use crate::aarch64::dcache_clean_invalidate_poc;
+
+/// Zero [address, address + size) and push the zeroes out to the Point
+/// of Coherency
+pub fn scrub_frames<M: PhysicalMemory + ?Sized>(mem: &mut M, address: u64, size: u64) -> () {
+    for word in (address..address + size).step_by(8) {
+        mem.write_u64(word, 0);
+    }
+
+    dcache_clean_invalidate_poc(address, size);
+}
+
+/*
+ * The runs of frames waiting to be scrubbed, linked through their first
+ * two words: the address of the next run, and the size of this one.
+ */
+#[derive(Copy, Clone, Debug)]
+pub struct DirtyFrames {
+    head: u64,
+    pending: u64,
+}
+
+impl DirtyFrames {
+    pub fn new() -> DirtyFrames {
+        DirtyFrames { head: 0, pending: 0 }
+    }
+
+    /// The number of bytes waiting to be scrubbed
+    pub fn pending(&self) -> u64 {
+        self.pending
+    }
+
+    /// Add the frames at [address, address + size) to the list
+    pub fn push(&mut self, mem: &mut dyn PhysicalMemory, address: u64, size: u64) -> () {
+        assert!(size >= 16);
+
+        mem.write_u64(address, self.head);
+        mem.write_u64(address + 8, size);
+        self.head = address;
+        self.pending += size;
+    }
+
+    /// Scrub and free runs until at least `budget` bytes have been done,
+    /// or the list is empty.  Returns the number of bytes scrubbed.
+    pub fn scrub(&mut self, mem: &mut dyn PhysicalMemory, budget: u64) -> u64 {
+        let mut done = 0;
+
+        while self.head != 0 && done < budget {
+            let run = self.head;
+            let size = mem.read_u64(run + 8);
+
+            self.head = mem.read_u64(run);
+            self.pending -= size;
+
+            scrub_frames(mem, run, size);
+            mem.free_frames(run, size);
+            done += size;
+        }
+
+        done
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::phys::SimulatedMemory;
+
+    const PAGE: u64 = 0x1000;
+
+    #[test]
+    fn dirty_frames_are_scrubbed_before_reuse() {
+        let mut mem = SimulatedMemory::new(0x1000_0000, 0x10_0000);
+        let mut dirty = DirtyFrames::new();
+
+        let a = mem.alloc_frames(PAGE, PAGE).unwrap();
+        let b = mem.alloc_frames(2 * PAGE, PAGE).unwrap();
+        mem.write_u64(a + 0x100, 0x5ec7e7);
+        mem.write_u64(b + PAGE, 0x5ec7e7);
+        let allocated = mem.allocated();
+
+        dirty.push(&mut mem, a, PAGE);
+        dirty.push(&mut mem, b, 2 * PAGE);
+        assert_eq!(dirty.pending(), 3 * PAGE);
+        assert_eq!(mem.allocated(), allocated);
+
+        /* Whole runs at a time, most recently released first */
+        assert_eq!(dirty.scrub(&mut mem, PAGE), 2 * PAGE);
+        assert_eq!(mem.read_u64(b + PAGE), 0);
+        assert_eq!(mem.read_u64(a + 0x100), 0x5ec7e7);
+        assert_eq!(mem.allocated(), allocated - 2 * PAGE);
+
+        assert_eq!(dirty.scrub(&mut mem, !0), PAGE);
+        assert_eq!(mem.read_u64(a + 0x100), 0);
+        assert_eq!(dirty.pending(), 0);
+        assert_eq!(dirty.scrub(&mut mem, !0), 0);
+
+        /* Released straight away, memory without a list is scrubbed on the spot */
+        let c = mem.alloc_frames(PAGE, PAGE).unwrap();
+        mem.write_u64(c, 0x5ec7e7);
+        mem.release_frames(c, PAGE);
+        assert_eq!(mem.read_u64(c), 0);
+    }
+}
@@ -0,0 +1,83 @@
+/*
+ * A spinlock around a value, for state that any CPU may use.
+ *
+ * lock() spins until the lock is free and returns a guard that derefs
+ * to the value, and dropping the guard frees the lock again.  Interrupts
+ * are left alone, so an exception handler must not take a lock that
+ * whatever it interrupted may hold.
+ *
+ * The locks nest in one order only: a VM, then the heap, then the frame
+ * allocator, then the dedup scanner.
+ */
+
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, Ordering, spin_loop_hint};
+
+pub struct SpinLock<T> {
+    locked: AtomicBool,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Sync for SpinLock<T> {}
+
+impl<T> SpinLock<T> {
+    pub const fn new(value: T) -> SpinLock<T> {
+        SpinLock {
+            locked: AtomicBool::new(false),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn lock(&self) -> SpinLockGuard<T> {
+        while self.locked.compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed).is_err() {
+            spin_loop_hint();
+        }
+
+        SpinLockGuard { lock: self }
+    }
+}
+
+/// Exclusive use of a SpinLock's value, until dropped
+pub struct SpinLockGuard<'a, T> {
+    lock: &'a SpinLock<T>,
+}
+
+impl<'a, T> Deref for SpinLockGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<'a, T> DerefMut for SpinLockGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+impl<'a, T> Drop for SpinLockGuard<'a, T> {
+    fn drop(&mut self) {
+        self.lock.locked.store(false, Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn guard_holds_the_lock_until_dropped() {
+        let lock = SpinLock::new(1);
+
+        {
+            let mut value = lock.lock();
+            *value += 1;
+            assert!(lock.locked.load(Ordering::Relaxed));
+        }
+
+        assert!(!lock.locked.load(Ordering::Relaxed));
+        assert_eq!(*lock.lock(), 2);
+    }
+}
@@ -1,22 +1,35 @@
 use crate::lpae::{
     PageTableTree,
     PageTableTreeStage2,
-    Alignment,
+    TranslationTree,
+    MapError,
+    Granule,
+    PhysAddrRange,
     align,
-    ADDRESS_SPACE_PER_TABLE,
-    PAGE_SIZE,
+    align_up,
+    EL2_VA_BITS,
 };
-
-use crate::memory_attrs;
-use crate::aarch64::{current_el, Shareable, data_barrier, isb};
+use crate::frame_alloc::{self, FrameAllocator, MEMORY_START, MEMORY_SIZE, MEMORY_END};
+use crate::memmap::{self, MemoryMap, Owner};
+use crate::fdt::Fdt;
+use crate::vmap;
+use crate::vmid;
+use crate::dedup;
+
+use crate::memory_attrs::{self, MapFlags, Stage2Flags};
+use crate::aarch64::{current_el, Shareable, data_barrier, isb, id_aa64mmfr0};
 use crate::{msr, mrs};
 use crate::common::bit;
 use crate::uart::{uart_write, uart_init};
-use crate::vm::{init_vtcr, get_phys_addr_range};
+use crate::vm::{self, Vm, init_vtcr, get_phys_addr_range};
 
 const UART_BASE: u64 = 0x09000000;
 const UART_SIZE: u64 = 0x00001000;
 
+/* Where the QEMU loader device puts the guest, see scripts/run.sh */
+const GUEST_ADDRESS: u64 = 0x40400000;
+const GUEST_IMAGE_SIZE: u64 = 0x200000;
+
 const SCTLR_EL2_RES1: u64 = (bit(4) | bit(5) | bit(11) |
                              bit(16) | bit(18) | bit(22) |
                              bit(23) | bit(28) | bit(29));
@@ -37,41 +50,34 @@ fn init_guest_cpsr() -> () {
 }
 
 fn map_address_range(boot_table_tree: &mut PageTableTree,
+                     allocator: &mut FrameAllocator,
                      virt_start: u64,
                      virt_end: u64,
-                     phys_start: u64) -> () {
+                     phys_start: u64) -> Result<(), MapError> {
+    let granule = boot_table_tree.granule();
 
-    let start = align(virt_start, Alignment::Kb4);
-    let end = align(virt_end, Alignment::Kb4);
-    let mut paddr = align(phys_start, Alignment::Kb4);
+    let start = align(virt_start, granule);
+    let end = align_up(virt_end, granule);
+    let paddr = align(phys_start, granule);
 
-    for vaddr in (start..end).step_by(PAGE_SIZE) {
-        boot_table_tree.map(vaddr, paddr);
-        paddr += PAGE_SIZE as u64;
-    }
+    boot_table_tree.map_range(allocator, start, paddr, end - start, MapFlags::normal())
 }
 
 fn setup_boot_pagetables(boot_table_tree: &mut PageTableTree,
+                         allocator: &mut FrameAllocator,
                          start: u64,
                          end: u64,
                          offset: u64) -> () {
-    /*
-     * This pagetable code assumes that we can fit the entire hypervisor
-     * into a single stage1, 4-level table mapping, which is 2MB.
-     *
-     * Panic if the hypervisor is larger than 2MB.
-     */
-    assert!(!(end - start > (ADDRESS_SPACE_PER_TABLE as u64)));
-
-    /* The offset MUST be exactly 1GB from identity map, this way the
-     * level one entries for the identity map and the offset map both
-     * point to the same level two entry, which then maps down to the same level three
-     * and so forth...
-     */
+    /* The offset MUST be a multiple of 1GB from the identity map */
     assert!((offset % (1 << 30)) == 0);
 
-    /* Identity map the hypervisor (virtual address == physical address) */
-    map_address_range(boot_table_tree, start, end, start);
+    /*
+     * Identity map all of RAM (virtual address == physical address).
+     * This covers the hypervisor image, and every frame the allocator
+     * hands out, including the ones translation tables live in.
+     */
+    assert!(start >= MEMORY_START && end <= MEMORY_END);
+    map_address_range(boot_table_tree, allocator, MEMORY_START, MEMORY_END, MEMORY_START).unwrap();
 
     /* Map the hypervisor load address space to its real physical address space */
     /*map_address_range(boot_table_tree, start + offset,
@@ -95,6 +101,7 @@ const HCR_VM: u64 = bit(0);
 const HCR_IMO: u64 = bit(3);
 const HCR_FMO: u64 = bit(4);
 const HCR_AMO: u64 = bit(5);
+const HCR_TWI: u64 = bit(13);
 const HCR_RW: u64 = bit(31);
 const HCR_TGE: u64 = bit(27);
 
@@ -124,6 +131,9 @@ pub fn trap_lower_el_into_el2() -> () {
     /* Lower exception levels are Aarch64 */
     hcr_el2 |=  HCR_RW;
 
+    /* A guest WFI is idle time for us, see VmList::idle() */
+    hcr_el2 |= HCR_TWI;
+
     msr!("HCR_EL2", hcr_el2);
 }
 
@@ -137,11 +147,6 @@ fn switch_ttbr(pagetable_address: u64) -> () {
     isb();
 }
 
-fn switch_vttbr(pagetable_address: u64) -> () {
-    msr!("vttbr_el2", pagetable_address);
-    isb();
-}
-
 fn disable_el2_host() -> () {
     // D13.2.46 HCR_EL2, Hypervisor Configuration Register
     let mut hcr_el2: u64;
@@ -150,7 +155,7 @@ fn disable_el2_host() -> () {
     msr!("hcr_el2", hcr_el2);
 }
 
-fn init_tcr() -> () {
+fn init_tcr(granule: Granule, pa_range: PhysAddrRange) -> () {
     let mut tcr_el2: u64 = 0;
 
     tcr_el2 |= TCR_EL2_RES1;
@@ -159,11 +164,13 @@ fn init_tcr() -> () {
     tcr_el2 |= TCR_EL2_INNER_SHAREABLE;
 
     // TCR_EL2.PS[18:16]
-    let pa_range = get_phys_addr_range();
-    tcr_el2 |= (pa_range & 0x3) << 16;
+    tcr_el2 |= pa_range.ps() << 16;
+
+    // TCR_EL2.TG0[15:14]
+    tcr_el2 |= granule.tg0() << 14;
 
     // 48-bit virtual address space
-    tcr_el2 |= 64 - 48;
+    tcr_el2 |= 64 - EL2_VA_BITS;
     msr!("tcr_el2", tcr_el2);
 }
 
@@ -262,24 +269,44 @@ const SPSR_EL2t: u64 = 0b1000;
 #[allow(non_upper_case_globals)]
 const SPSR_EL2h: u64 = 0b1001;
 
-pub fn load_guest() -> () {
-    let guest_address: u64 = 0x40400000;
+/* Guest RAM, starting with the image the loader places at 0x40400000 */
+const GUEST_RAM_SIZE: u64 = 64 << 20;
+
+/* Merge identical guest pages from idle time, see dedup.rs */
+const DEDUP_ENABLED: bool = false;
 
-    let mut stage2_table = PageTableTreeStage2::new();
-    stage2_table.map(guest_address, guest_address);
+pub fn load_guest(granule: Granule, pa_range: PhysAddrRange) -> () {
+    let guest_address: u64 = GUEST_ADDRESS;
+    let guest_size: u64 = GUEST_IMAGE_SIZE;
+
+    /* The guest's faults need the frame allocator, so it is not kept */
+    let stage2_table = {
+        let mut allocator = frame_alloc::frame_allocator();
+        let mut stage2_table = PageTableTreeStage2::new(&mut *allocator, granule, pa_range).unwrap();
+
+        stage2_table.map_range(&mut *allocator, guest_address, guest_address, guest_size,
+                               Stage2Flags::normal()).unwrap();
+        stage2_table
+    };
+    let mut vm = Vm::new(stage2_table);
+
+    /* The rest of the guest's RAM is backed as the guest touches it */
+    vm.declare_ram(guest_address + guest_size, GUEST_RAM_SIZE - guest_size);
+
+    if DEDUP_ENABLED {
+        dedup::init(granule);
+    }
 
     // DEBUG: irq vector
-    //stage2_table.map(0x40000000, 0x40000000);
+    //stage2_table.map_range(allocator, 0x40000000, 0x40000000, 0x1000, Stage2Flags::normal());
 
     /* Initialize VTCR_EL2 */
-    init_vtcr();
-
-    /* Initialize VTTBR_EL2 */
-    let vttbr_el2 = &stage2_table as *const _ as u64;
-    // We mask out bits [13:0] because we are using a concatenated table
-    // of 8KB for stage 2, meaning that the table address requires one less
-    // bit than a 4KB table
-    switch_vttbr(vttbr_el2 & !((1<<13)-1));
+    init_vtcr(granule, vm.stage2.start_level(), vm.stage2.ipa_bits(), pa_range);
+
+    /* Initialize VTTBR_EL2, with a VMID of the guest's own */
+    vmid::init();
+    vm.switch_to();
+    vm::set_current_vm(vm);
     
     unsafe { asm!("msr SCTLR_EL1, XZR"); }
     
@@ -332,11 +359,29 @@ fn data_abort() -> () {
     }
 }
 
+/// Reserve the DTB at dtb, and whatever it reserves itself
+fn reserve_dtb(map: &mut MemoryMap, dtb: u64) -> () {
+    if dtb == 0 {
+        return;
+    }
+
+    let fdt = match unsafe { Fdt::at(dtb) } {
+        Some(fdt) => fdt,
+        None => return,
+    };
+
+    map.reserve(dtb, fdt.total_size(), Owner::Dtb);
+    fdt.for_each_mem_reservation(&mut |address, size| map.reserve(address, size, Owner::Firmware));
+    fdt.for_each_reserved_memory(&mut |address, size| map.reserve(address, size, Owner::Firmware));
+}
+
 #[no_mangle]
 pub extern fn start_hypervisor(start: u64,
                                end: u64,
                                offset: u64,
-                               irq_vector_addr: u64) -> ! {
+                               irq_vector_addr: u64,
+                               stack_start: u64,
+                               dtb: u64) -> ! {
     assert_eq!(current_el(), 2);
     disable_interrupts();
 
@@ -346,7 +391,10 @@ pub extern fn start_hypervisor(start: u64,
      */
     disable_el2_host();
 
-    init_tcr();
+    let granule = Granule::select(id_aa64mmfr0());
+    let pa_range = PhysAddrRange::new(get_phys_addr_range(), granule);
+
+    init_tcr(granule, pa_range);
     init_sctlr();
     unsafe { asm!("msr spsel, #1") }
 
@@ -354,27 +402,45 @@ pub extern fn start_hypervisor(start: u64,
     init_el1_interrupts(irq_vector_addr);
 
 
-    let mut boot_table_tree = PageTableTree::new();
-    setup_boot_pagetables(&mut boot_table_tree, start, end, offset);
+    /* Nothing that is already in RAM may be handed out */
+    let mut map = MemoryMap::new();
+    map.add_ram(MEMORY_START, MEMORY_SIZE);
+    map.reserve(start, stack_start - start, Owner::Hypervisor);
+    map.reserve(stack_start, end - stack_start, Owner::Stack);
+    map.reserve(GUEST_ADDRESS, GUEST_IMAGE_SIZE, Owner::GuestImage);
+    reserve_dtb(&mut map, dtb);
+
+    /* The heap draws on the frame allocator, so the map goes on it after */
+    frame_alloc::init(&mut map);
+    memmap::init(map);
+
+    let boot_table_tree = {
+        let mut allocator = frame_alloc::frame_allocator();
+        let mut tree = PageTableTree::new(&mut *allocator, granule, pa_range).unwrap();
+
+        setup_boot_pagetables(&mut tree, &mut allocator, start, end, offset);
+        tree
+    };
 
-    let uart_virt = align(end + PAGE_SIZE as u64, Alignment::Kb4);
-    boot_table_tree.map(uart_virt, UART_BASE);
-    //boot_table_tree.map(0x40400000, 0x40400000);
+    let ttbr0_el2 = boot_table_tree.root_address();
+    vmap::init(boot_table_tree);
 
+    let uart_virt = vmap::ioremap(UART_BASE, UART_SIZE).unwrap();
 
     /* Flush the tlb just in case there is stale state */
     flush_hypervisor_tlb();
-    let ttbr0_el2 = &boot_table_tree.zeroeth as *const _ as u64;
     switch_ttbr(ttbr0_el2);
 
     enable_mmu();
 
-    uart_init(uart_virt);
+    uart_init(uart_virt as u64);
     uart_write("UART mapped\n");
+    let stats = frame_alloc::frame_allocator().stats();
+    memmap::memory_map().report(stats);
 
 
     enable_virt();
-    load_guest();
+    load_guest(granule, pa_range);
 
     loop {}
 }
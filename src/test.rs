@@ -3,7 +3,9 @@
 pub extern fn start_hypervisor(_start: u64,
                                _end: u64,
                                _offset: u64,
-                               _irq_vector_addr: u64) -> ! {
+                               _irq_vector_addr: u64,
+                               _stack_start: u64,
+                               _dtb: u64) -> ! {
 
 
     loop {}
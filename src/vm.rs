@@ -1,9 +1,37 @@
 #![allow(dead_code)]
 
+use alloc::boxed::Box;
+
+use crate::spinlock::{SpinLock, SpinLockGuard};
+
 use crate::uart::uart_write;
 use crate::common::bit;
 use crate::common::print_hex;
 use crate::{msr, mrs};
+use crate::lpae::{
+    Granule,
+    PhysAddrRange,
+    PageTableTreeStage2,
+    TranslationTree,
+    MapError,
+    PTE_SW_SHARED,
+    align,
+    align_up,
+};
+use crate::aarch64::{
+    id_aa64mmfr1,
+    isb,
+    data_barrier,
+    dcache_clean_invalidate_poc,
+    Shareable,
+};
+use crate::memory_attrs::Stage2Flags;
+use crate::phys::PhysicalMemory;
+use crate::vmid::{self, Vmid};
+use crate::dirty::{self, DirtyLog};
+use crate::dedup::{self, ScanRange};
+use crate::frame_alloc::frame_allocator;
+use crate::walk::translate;
 
 
 pub fn get_phys_addr_range() -> u64 {
@@ -38,12 +66,29 @@ fn map_guest_page() -> () {
     alloc_stage2_entry();
 }
 
-// VTCR_EL2.TOSZ == VTCR_EL2[5:0]
-const VTCR_EL2_T0SZ_MASK: u64 = 0b11111;
-
-// VTCR_EL2.SL0 == VTCR_EL2[7:6]
-const VTCR_EL2_SL0_SHIFT: u64 =  5;
-const VTCR_EL2_SL0_MASK: u64 =  0b11 << VTCR_EL2_SL0_SHIFT;
+/* ID_AA64MMFR1_EL1 fields */
+const ID_AA64MMFR1_HAFDBS_MASK: u64 = 0xf;
+const ID_AA64MMFR1_HAFDBS_AF: u64 = 0b0001;
+const ID_AA64MMFR1_HAFDBS_AF_DBS: u64 = 0b0010;
+const ID_AA64MMFR1_VMIDBITS_SHIFT: u64 = 4;
+const ID_AA64MMFR1_VMIDBITS_16: u64 = 0b0010;
+
+/* VTCR_EL2 fields, see D13.2.140 */
+const VTCR_EL2_T0SZ_SHIFT: u64 = 0;
+const VTCR_EL2_SL0_SHIFT: u64 = 6;
+const VTCR_EL2_IRGN0_SHIFT: u64 = 8;
+const VTCR_EL2_ORGN0_SHIFT: u64 = 10;
+const VTCR_EL2_SH0_SHIFT: u64 = 12;
+const VTCR_EL2_TG0_SHIFT: u64 = 14;
+const VTCR_EL2_PS_SHIFT: u64 = 16;
+const VTCR_EL2_VS: u64 = bit(19);
+const VTCR_EL2_HA: u64 = bit(21);
+const VTCR_EL2_HD: u64 = bit(22);
+const VTCR_EL2_RES1: u64 = bit(31);
+
+/* Table walks are Inner Shareable, Normal Write-Back Write-Allocate */
+const VTCR_EL2_RGN_WBWA: u64 = 0b01;
+const VTCR_EL2_SH_INNER: u64 = 0b11;
 
 #[allow(non_camel_case_types)]
 struct VTCR_EL2 {}
@@ -69,138 +114,777 @@ impl VTCR_EL2 {
 
         return VTCR_EL2::get() & MASK;
     }
+}
 
-    #[inline]
-    pub fn set_t0sz(val: u64) {
-        const SHIFT: u64 = 0;
-        const MASK: u64 =  0b11111 << SHIFT;
 
-        let mut reg = VTCR_EL2::get();
+pub fn show_vtcr_el2() -> () {
+    uart_write("VTCR_EL2: ");
+    print_hex(VTCR_EL2::get());
+    uart_write("\n");
+    uart_write("VTCR_EL2.T0SZ: ");
 
-        reg &= !MASK;
-        reg |= (val << SHIFT) & MASK;
+    let val = VTCR_EL2::t0sz();
+    print_hex(val);
+    uart_write("\n");
+}
 
-        msr!("VTCR_EL2", reg);
+/// The number of VMID bits ID_AA64MMFR1_EL1 reports, 8 or 16
+pub fn vmid_bits(mmfr1: u64) -> u64 {
+    match (mmfr1 >> ID_AA64MMFR1_VMIDBITS_SHIFT) & 0xf {
+        ID_AA64MMFR1_VMIDBITS_16 => 16,
+        _ => 8,
     }
+}
 
-    #[inline]
-    pub fn set_sh0(val: u64) -> () {
-        const SHIFT: u64 = 12;
-        const MASK: u64 =  0b11 << SHIFT;
+/// Whether the MMU can track dirty state, see dirty.rs.  vtcr_el2()
+/// sets VTCR_EL2.HD whenever it can.
+pub fn hardware_dirty_state(mmfr1: u64) -> bool {
+    (mmfr1 & ID_AA64MMFR1_HAFDBS_MASK) >= ID_AA64MMFR1_HAFDBS_AF_DBS
+}
+
+/// Returns the VTCR_EL2 value for stage 2 trees with the given shape,
+/// using whatever optional features ID_AA64MMFR1_EL1 reports.
+///
+/// `start_level` and `ipa_bits` must come from the stage 2 tree, see
+/// PageTableTreeStage2, so that the root table matches SL0 and T0SZ.
+pub fn vtcr_el2(granule: Granule,
+                start_level: usize,
+                ipa_bits: u64,
+                pa_range: PhysAddrRange,
+                mmfr1: u64) -> u64 {
+    let mut vtcr = VTCR_EL2_RES1;
+
+    vtcr |= (64 - ipa_bits) << VTCR_EL2_T0SZ_SHIFT;
+    vtcr |= granule.sl0(start_level) << VTCR_EL2_SL0_SHIFT;
+    vtcr |= VTCR_EL2_RGN_WBWA << VTCR_EL2_IRGN0_SHIFT;
+    vtcr |= VTCR_EL2_RGN_WBWA << VTCR_EL2_ORGN0_SHIFT;
+    vtcr |= VTCR_EL2_SH_INNER << VTCR_EL2_SH0_SHIFT;
+    vtcr |= granule.tg0() << VTCR_EL2_TG0_SHIFT;
+    vtcr |= pa_range.ps() << VTCR_EL2_PS_SHIFT;
+
+    if vmid_bits(mmfr1) == 16 {
+        vtcr |= VTCR_EL2_VS;
+    }
+
+    /* Hardware management of the Access flag, and of dirty state */
+    if mmfr1 & ID_AA64MMFR1_HAFDBS_MASK == ID_AA64MMFR1_HAFDBS_AF {
+        vtcr |= VTCR_EL2_HA;
+    } else if hardware_dirty_state(mmfr1) {
+        vtcr |= VTCR_EL2_HA | VTCR_EL2_HD;
+    }
+
+    vtcr
+}
+
+pub fn init_vtcr(granule: Granule,
+                 start_level: usize,
+                 ipa_bits: u64,
+                 pa_range: PhysAddrRange) -> () {
+    VTCR_EL2::set(vtcr_el2(granule, start_level, ipa_bits, pa_range, id_aa64mmfr1()));
+    isb();
+}
 
-        let mut reg = VTCR_EL2::get();
+const MAX_RAM_REGIONS: usize = 8;
 
-        reg &= !MASK;
-        reg |= (val << SHIFT) & MASK;
+/* Guest RAM is backed with level 2 blocks, 2MB with a 4KB granule */
+const HUGE_PAGE_LEVEL: usize = 2;
 
-        msr!("VTCR_EL2", reg);
+/// Guest RAM at [ipa, ipa + size) that is only backed by frames once
+/// the guest touches it, see Vm::handle_translation_fault()
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct RamRegion {
+    pub ipa: u64,
+    pub size: u64,
+}
+
+impl RamRegion {
+    pub fn contains(&self, ipa: u64) -> bool {
+        ipa >= self.ipa && ipa < self.ipa + self.size
     }
+}
 
-    #[inline]
-    pub fn set_sl0(val: u64) -> () {
-        const SHIFT: u64 = 6;
-        const MASK: u64 =  0b11 << SHIFT;
+/// Give back the frames start_dirty_logging() took for log's bitmap
+fn free_dirty_bitmap(mem: &mut dyn PhysicalMemory, granule: Granule, log: DirtyLog<'static>) -> () {
+    let bitmap = log.into_bitmap();
+    let bytes = align_up((bitmap.len() * 8) as u64, granule);
 
-        let mut reg = VTCR_EL2::get();
+    mem.free_frames(bitmap.as_ptr() as u64, bytes);
+}
+
+/// A guest, and the stage 2 state that goes with it
+pub struct Vm {
+    pub stage2: PageTableTreeStage2,
+    pub vmid: Option<Vmid>,
+    pub dirty_log: Option<DirtyLog<'static>>,
+    ram: [Option<RamRegion>; MAX_RAM_REGIONS],
 
-        reg &= !MASK;
-        reg |= (val << SHIFT) & MASK;
+    /* Where idle() picks up collapsing huge pages, in blocks */
+    collapse_cursor: u64,
+}
 
-        msr!("VTCR_EL2", reg);
+impl Vm {
+    pub fn new(stage2: PageTableTreeStage2) -> Vm {
+        Vm {
+            stage2: stage2,
+            vmid: None,
+            dirty_log: None,
+            ram: [None; MAX_RAM_REGIONS],
+            collapse_cursor: 0,
+        }
     }
 
+    /// Load the VM's stage 2 tables and VMID into VTTBR_EL2
+    pub fn switch_to(&mut self) -> () {
+        vmid::switch_vttbr(self.stage2.root_address(), &mut self.vmid);
+    }
 
-    #[inline]
-    pub fn set_tg0(val: u64) -> () {
-        const SHIFT: u64 = 14;
-        const MASK: u64 =  0b11 << SHIFT;
+    /// Start logging writes to [start, start + size) of the guest's
+    /// IPA space, see dirty.rs.  The VM must be the current one.
+    pub fn start_dirty_logging(&mut self, start: u64, size: u64) -> Result<(), MapError> {
+        let granule = self.stage2.granule();
+        let words = DirtyLog::bitmap_words(size >> granule.page_shift());
+        let bytes = align_up((words * 8) as u64, granule);
+
+        assert!(self.dirty_log.is_none());
+
+        /* RAM is identity mapped at EL2, see free_dirty_bitmap() */
+        let address = match frame_allocator().alloc_frames(bytes, granule.page_size()) {
+            Some(address) => address,
+            None => return Err(MapError::OutOfMemory),
+        };
+        let bitmap = unsafe { core::slice::from_raw_parts_mut(address as *mut u64, words) };
+
+        let log = dirty::start_logging(&mut self.stage2, &mut *frame_allocator(), start, size,
+                                       hardware_dirty_state(id_aa64mmfr1()), bitmap)?;
+        self.dirty_log = Some(log);
+        Ok(())
+    }
 
-        let mut reg = VTCR_EL2::get();
+    /// Copy the pages written since the last call (or since logging
+    /// started) into `dirty`, one bit per page, and start over
+    pub fn take_dirty_bitmap(&mut self, dirty: &mut [u64]) -> () {
+        let log = self.dirty_log.as_mut().unwrap();
 
-        reg &= !MASK;
-        reg |= (val << SHIFT) & MASK;
+        dirty::take_dirty_bitmap(&mut self.stage2, &mut *frame_allocator(), log, dirty)
+    }
+
+    pub fn stop_dirty_logging(&mut self) -> Result<(), MapError> {
+        let log = match self.dirty_log.take() {
+            Some(log) => log,
+            None => return Ok(()),
+        };
+        let mut allocator = frame_allocator();
+        let result = dirty::stop_logging(&mut self.stage2, &mut *allocator, &log);
 
-        msr!("VTCR_EL2", reg);
+        free_dirty_bitmap(&mut *allocator, self.stage2.granule(), log);
+        result
     }
 
-    #[inline]
-    pub fn set_ps(val: u64) -> () {
-        const SHIFT: u64 = 16;
+    /// Declare [ipa, ipa + size) guest RAM, without backing it yet
+    pub fn declare_ram(&mut self, ipa: u64, size: u64) -> () {
+        assert_eq!((ipa | size) & !self.stage2.granule().mask(), 0);
+        assert!(self.ram.iter()
+                .filter_map(|region| *region)
+                .all(|region| ipa + size <= region.ipa || region.ipa + region.size <= ipa));
 
-        let mut reg = VTCR_EL2::get();
+        let slot = self.ram.iter().position(|region| region.is_none()).unwrap();
+        self.ram[slot] = Some(RamRegion { ipa: ipa, size: size });
+    }
 
-        reg |= val << SHIFT;
+    /// The declared RAM region containing ipa, if any
+    pub fn ram_region(&self, ipa: u64) -> Option<RamRegion> {
+        self.ram.iter()
+            .filter_map(|region| *region)
+            .find(|region| region.contains(ipa))
+    }
 
-        msr!("VTCR_EL2", reg);
+    /// Handle a stage 2 translation fault on ipa.  Returns true if the
+    /// guest can retry the access, false if ipa is not RAM (or there
+    /// was no memory left to back it).
+    ///
+    /// Declared RAM gets zeroed frames the first time the guest touches
+    /// it: a whole huge page if the block around ipa is all RAM, none of
+    /// it is mapped yet and the allocator has an aligned block to spare,
+    /// a single page otherwise.  ipa may also be mapped after all,
+    /// because the fault hit the window where break-before-make had the
+    /// entry invalid.
+    pub fn handle_translation_fault(&mut self, mem: &mut dyn PhysicalMemory, ipa: u64) -> bool {
+        let granule = self.stage2.granule();
+        let page_size = granule.page_size();
+        let block_size = granule.level_size(HUGE_PAGE_LEVEL);
+        let block = ipa & !(block_size - 1);
+
+        let failure = match translate(&self.stage2, mem, ipa) {
+            Ok(_) => return true,
+            Err(failure) => failure,
+        };
+
+        let region = match self.ram_region(ipa) {
+            Some(region) => region,
+            None => return false,
+        };
+
+        /* A huge page would be logged, and so dirtied, as a whole */
+        let logged = match self.dirty_log.as_ref() {
+            Some(log) => log.overlaps(block, block_size),
+            None => false,
+        };
+
+        /* The walk stopping above the huge page level means none of the block is mapped */
+        if failure.level <= HUGE_PAGE_LEVEL && !logged &&
+           region.contains(block) && region.contains(block + block_size - 1) {
+            if let Some(frame) = mem.alloc_frames(block_size, block_size) {
+                return self.back(mem, block, frame, block_size);
+            }
+        }
+
+        match mem.alloc_frames(page_size, page_size) {
+            Some(frame) => self.back(mem, align(ipa, granule), frame, page_size),
+            None => false,
+        }
     }
 
-    #[inline]
-    pub fn reserved() -> () {
-        // RES0 [63:32], [24:23], 20
-        const RES1: u64 = 1 << 31;
-        const RES0_CLR: u64 = (1<<(63-32)) - 1;
-        const RES0: u64 = RES0_CLR << 32 | bit(24) | bit(23) | bit(20);
+    /// Map [ipa, ipa + size) to fresh frames at frame, zeroing them first
+    fn back(&mut self, mem: &mut dyn PhysicalMemory, ipa: u64, frame: u64, size: u64) -> bool {
+        for address in (frame..frame + size).step_by(8) {
+            mem.write_u64(address, 0);
+        }
+
+        /* The guest may well run with its MMU, and so its caches, off */
+        dcache_clean_invalidate_poc(frame, size);
+
+        /* A logged page starts out write-protected, see dirty::log_mapping() */
+        let logged = match self.dirty_log.as_ref() {
+            Some(log) => log.contains(ipa),
+            None => false,
+        };
+        let flags = if logged { Stage2Flags::normal().read_only() } else { Stage2Flags::normal() };
+
+        let result = self.stage2.map_range(mem, ipa, frame, size, flags);
+
+        /* Make the new descriptor visible to the table walker */
+        data_barrier(Shareable::Inner);
+
+        match result {
+            Ok(()) if logged => {
+                let log = self.dirty_log.as_ref().unwrap();
+
+                /* Exactly one leaf, so this cannot need a split */
+                dirty::log_mapping(&mut self.stage2, mem, log, ipa, size).unwrap();
+                true
+            },
+            Ok(()) => true,
+
+            /* Backed since the fault was taken, the guest just retries */
+            Err(MapError::AlreadyMapped(_)) | Err(MapError::BlockInTheWay(_)) => {
+                mem.free_frames(frame, size);
+                true
+            },
+            Err(_) => {
+                mem.free_frames(frame, size);
+                false
+            },
+        }
+    }
+
+    /// Turn the declared RAM that is mapped page by page back into huge
+    /// pages where possible, returning how many were made.
+    ///
+    /// A block qualifies when every page of it is mapped with the same
+    /// attributes and is neither dirty logged nor shared.  If the pages
+    /// are not already one aligned run of frames they are copied into a
+    /// newly allocated block and their frames freed, so this must not
+    /// run while the guest does, and is best left to idle time.  The VM
+    /// must be the current one.
+    pub fn collapse_huge_pages(&mut self, mem: &mut dyn PhysicalMemory) -> Result<usize, MapError> {
+        self.collapse_blocks(mem, 0, !0).map(|(collapsed, _)| collapsed)
+    }
 
-        let mut reg = VTCR_EL2::get();
-        reg |= RES1;
-        msr!("VTCR_EL2", reg);
+    /// Try to collapse at most `budget` blocks of the declared RAM,
+    /// starting `first` blocks in.  Returns how many were collapsed, and
+    /// where to carry on from, 0 once every block has been tried.
+    fn collapse_blocks(&mut self,
+                       mem: &mut dyn PhysicalMemory,
+                       first: u64,
+                       budget: u64) -> Result<(usize, u64), MapError> {
+        let block_size = self.stage2.granule().level_size(HUGE_PAGE_LEVEL);
+        let ram = self.ram;
+        let mut position = 0;
+        let mut collapsed = 0;
+
+        for region in ram.iter().filter_map(|region| *region) {
+            let mut block = (region.ipa + block_size - 1) & !(block_size - 1);
+
+            while block + block_size <= region.ipa + region.size {
+                if position >= first {
+                    if position == first + budget {
+                        return Ok((collapsed, position));
+                    }
+                    if self.collapse_block(mem, block)? {
+                        collapsed += 1;
+                    }
+                }
+                position += 1;
+                block += block_size;
+            }
+        }
+
+        Ok((collapsed, 0))
+    }
+
+    /// Collapse a few more huge pages, see collapse_huge_pages(), from
+    /// where the last call left off.  For idle time, the VM must be the
+    /// current one.
+    pub fn idle(&mut self, mem: &mut dyn PhysicalMemory) -> () {
+        let first = self.collapse_cursor;
+
+        /* Best effort, the guest runs just as well without */
+        self.collapse_cursor = match self.collapse_blocks(mem, first, IDLE_COLLAPSE_BUDGET) {
+            Ok((_, next)) => next,
+            Err(_) => 0,
+        };
+    }
 
-        assert!((reg & RES0) == 0);
+    fn collapse_block(&mut self, mem: &mut dyn PhysicalMemory, block: u64) -> Result<bool, MapError> {
+        let granule = self.stage2.granule();
+        let page_size = granule.page_size();
+        let block_size = granule.level_size(HUGE_PAGE_LEVEL);
+
+        /* A huge page would be logged, and so dirtied, as a whole */
+        if let Some(log) = self.dirty_log.as_ref() {
+            if log.overlaps(block, block_size) {
+                return Ok(false);
+            }
+        }
+
+        /* Pages that happen to be contiguous only need the table gone */
+        if self.stage2.collapse(mem, block, HUGE_PAGE_LEVEL)? {
+            return Ok(true);
+        }
+
+        let mut attributes = None;
+        for ipa in (block..block + block_size).step_by(page_size as usize) {
+            let descriptor = match translate(&self.stage2, mem, ipa) {
+                Ok(ref page) if page.level == 3 => page.descriptor,
+                _ => return Ok(false),
+            };
+
+            /* Logged and shared pages are tracked page by page */
+            if descriptor.software() != 0 {
+                return Ok(false);
+            }
+
+            let mut bare = descriptor;
+            bare.set_output_address(0, granule);
+            bare.set_contiguous(false);
+
+            match attributes {
+                None => attributes = Some(bare),
+                Some(first) if first == bare => (),
+                Some(_) => return Ok(false),
+            }
+        }
+
+        let frame = match mem.alloc_frames(block_size, block_size) {
+            Some(frame) => frame,
+            None => return Ok(false),
+        };
+
+        for offset in (0..block_size).step_by(page_size as usize) {
+            let old = translate(&self.stage2, mem, block + offset).unwrap().output_address;
+            let new = frame + offset;
+
+            /* The guest may have written with its caches off */
+            dcache_clean_invalidate_poc(old, page_size);
+            for i in (0..page_size).step_by(8) {
+                let value = mem.read_u64(old + i);
+                mem.write_u64(new + i, value);
+            }
+            dcache_clean_invalidate_poc(new, page_size);
+
+            /* Exactly one page, so this cannot need a split */
+            self.stage2.update_range(mem, block + offset, page_size, &mut |entry, _address, _level| {
+                let mut moved = entry;
+                moved.set_output_address(new, granule);
+                moved
+            }).unwrap();
+
+            mem.release_frames(old, page_size);
+        }
+
+        assert!(self.stage2.collapse(mem, block, HUGE_PAGE_LEVEL)?);
+        Ok(true)
+    }
+
+    /// Tear the VM down.  The frames backing its RAM are released, to be
+    /// scrubbed before anyone else gets them, and its stage 2 tables
+    /// and TLB entries go.  The VM must not be running, nor be the
+    /// current one, see destroy_current_vm().
+    ///
+    /// Only frames the VM was given for its declared RAM are its own to
+    /// release, whatever else it maps (its image, devices) is not.
+    pub fn destroy(mut self, mem: &mut dyn PhysicalMemory) -> () {
+        let page_size = self.stage2.granule().page_size();
+        let mut scanner = dedup::dedup_scanner();
+
+        if let Some(vmid) = self.vmid {
+            vmid::flush_vmid(vmid);
+        }
+
+        for region in self.ram.iter().filter_map(|region| *region) {
+            let mut ipa = region.ipa;
+
+            while ipa < region.ipa + region.size {
+                let translation = match translate(&self.stage2, mem, ipa) {
+                    Ok(translation) => translation,
+                    Err(_) => {
+                        ipa += page_size;
+                        continue;
+                    },
+                };
+
+                let size = translation.block_size;
+                let frame = translation.output_address & !(size - 1);
+
+                /* A shared frame is only ours once the other VMs are done with it */
+                let owned = if translation.descriptor.software() & PTE_SW_SHARED != 0 {
+                    scanner.as_mut().unwrap().drop_reference(frame)
+                } else {
+                    true
+                };
+
+                if owned {
+                    mem.release_frames(frame, size);
+                }
+                ipa = (ipa & !(size - 1)) + size;
+            }
+        }
+
+        if let Some(log) = self.dirty_log.take() {
+            free_dirty_bitmap(mem, self.stage2.granule(), log);
+        }
+        self.stage2.destroy(mem);
+    }
+
+    /// Handle a stage 2 permission fault on a write to ipa.  Returns
+    /// false if the hypervisor did not expect it.
+    pub fn handle_write_fault(&mut self, mem: &mut dyn PhysicalMemory, ipa: u64) -> bool {
+        let page_size = self.stage2.granule().page_size();
+        let logged = match self.dirty_log.as_ref() {
+            Some(log) => log.contains(ipa),
+            None => false,
+        };
+
+        /* A write to a shared page, see dedup.rs */
+        if let Some(scanner) = dedup::dedup_scanner().as_mut() {
+            if scanner.break_sharing(&mut self.stage2, mem, ipa, logged) {
+                if let Some(log) = self.dirty_log.as_mut() {
+                    log.mark(ipa & !(page_size - 1), page_size);
+                }
+                return true;
+            }
+        }
+
+        match self.dirty_log.as_mut() {
+            Some(log) => dirty::handle_write_fault(&mut self.stage2, mem, log, ipa),
+            None => false,
+        }
     }
 }
 
+const MAX_VMS: usize = 8;
 
-pub fn show_vtcr_el2() -> () {
-    uart_write("VTCR_EL2: ");
-    print_hex(VTCR_EL2::get());
-    uart_write("\n");
-    uart_write("VTCR_EL2.T0SZ: ");
+/* How much one trapped WFI does, so that the guest is not kept waiting */
+const IDLE_COLLAPSE_BUDGET: u64 = 4;
+const IDLE_SCAN_BUDGET: u64 = 256;
 
-    let val = VTCR_EL2::t0sz();
-    print_hex(val);
-    uart_write("\n");
+/// Every VM, and which of them runs
+pub struct VmList {
+    vms: [Option<Box<Vm>>; MAX_VMS],
+    current: Option<usize>,
 }
 
-const VTCR_EL2_T0SZ: u64 = 24; // 64 bit width - 40 bit address size
-const VTCR_EL2_SH0: u64 = 0x3 << 12; // Inner Shareable
-const VTCR_EL2_RES1: u64 = 1 << 31;
-
-
-pub fn init_vtcr() -> () {
-    /* A53
-     *
-    let pa_range: u64 = 2;
-    let pa_bits: u64 = 40;
-    let t0sz: u64 = 24;
-    let root_order: u64 = 1;
-    let sl0: u64 = 1;
-    const TG0_4K: u64 = 0;
-
-    VTCR_EL2::set_sh0(0x3);
-    VTCR_EL2::set_t0sz(t0sz);
-    VTCR_EL2::set_tg0(TG0_4K);
-    VTCR_EL2::set_sl0(sl0);
-    VTCR_EL2::set_ps(pa_range);
-    VTCR_EL2::reserved();
-    */
-
-    /*
-     * PS    = 010    (2) # 40-bit Phys Addr
-     * TG0   = 00     (0) # 4KB Translation Granule
-     * SH0   = 11     (3)
-     * ORGN0 = 01     (1)
-     * IRGN0 = 01     (1)
-     * SL0   = 01     (start at level 1)
-     * T0SZ  = 011000 (24)
-     *
-     * RES0=0000_0000_0000_0000_0000_0000_0000_0000 RES1=1 NSa=0 NSw=0 HWx=0_000 RES=00 HD=0 HA=0   RES=0 VS=0_010_00_11_01_01_01_011000
-     */
-
-    /* A53 */
-    VTCR_EL2::set(0x80023558);
-
-    /* A57 */
-    // RES1=1, RES0=000000000000,  PS=100 (44-bit Phys A), TG0=00 (4KB Granule), SH0=11, ORGN0=01, IRGN0=01, SL0=10 (start at level 0), TOSZ=010100 (20)
-    //VTCR_EL2::set(0x80043594);
+impl VmList {
+    const fn new() -> VmList {
+        VmList {
+            vms: [None, None, None, None, None, None, None, None],
+            current: None,
+        }
+    }
+
+    /// The VM that runs, and that guest exceptions are handled for
+    pub fn current(&mut self) -> Option<&mut Vm> {
+        match self.current {
+            Some(slot) => self.vms[slot].as_mut().map(|vm| &mut **vm),
+            None => None,
+        }
+    }
+
+    /// Housekeeping for while the current guest waits for an interrupt,
+    /// a little at a time: collapse its huge pages, then, if page sharing
+    /// is on, scan every VM's RAM for pages to merge.
+    pub fn idle(&mut self, mem: &mut dyn PhysicalMemory) -> () {
+        if let Some(vm) = self.current() {
+            vm.idle(mem);
+        }
+
+        let mut scanner = dedup::dedup_scanner();
+        let scanner = match scanner.as_mut() {
+            Some(scanner) => scanner,
+            None => return,
+        };
+
+        let mut ranges = [ScanRange { vm: 0, ipa: 0, size: 0 }; MAX_VMS * MAX_RAM_REGIONS];
+        let mut count = 0;
+
+        for (slot, vm) in self.vms.iter().enumerate() {
+            let vm = match vm {
+                Some(vm) => vm,
+                None => continue,
+            };
+
+            for region in vm.ram.iter().filter_map(|region| *region) {
+                ranges[count] = ScanRange { vm: slot, ipa: region.ipa, size: region.size };
+                count += 1;
+            }
+        }
+
+        /* Best effort, as with the huge pages */
+        let _ = scanner.scan(&mut self.vms, mem, &ranges[..count], IDLE_SCAN_BUDGET);
+    }
+}
+
+static VMS: SpinLock<VmList> = SpinLock::new(VmList::new());
+
+/// Add vm to the list and make it the one that runs
+pub fn set_current_vm(vm: Vm) -> () {
+    let vm = Box::new(vm);
+    let mut vms = VMS.lock();
+    let slot = vms.vms.iter().position(|vm| vm.is_none()).unwrap();
+
+    vms.vms[slot] = Some(vm);
+    vms.current = Some(slot);
+}
+
+/// The VMs, locked until the guard is dropped.
+///
+/// The lock serializes everything that changes a VM's stage 2 tables,
+/// so that one vCPU's fault is never handled halfway through another
+/// update, in the middle of a break-before-make say.  Take it before the
+/// frame allocator, see spinlock.rs.
+pub fn vms() -> SpinLockGuard<'static, VmList> {
+    VMS.lock()
 }
 
+/// Tear down the current VM, see Vm::destroy().  Guest exceptions are
+/// not handled from here on.
+pub fn destroy_current_vm() -> () {
+    let vm = {
+        let mut vms = VMS.lock();
+
+        match vms.current.take() {
+            Some(slot) => vms.vms[slot].take(),
+            None => None,
+        }
+    };
+
+    if let Some(vm) = vm {
+        /* Nothing may walk the tables while they are freed */
+        msr!("VTTBR_EL2", 0);
+        isb();
+
+        vm.destroy(&mut *frame_allocator());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::phys::SimulatedMemory;
+    use crate::lpae::PTE_SW_LOGGED;
+    use crate::memory_attrs::Stage2Access;
+
+    #[test]
+    fn cortex_a53() {
+        /* 40-bit PA, 8-bit VMIDs, no hardware AF/dirty management */
+        let pa_range = PhysAddrRange::new(0b0010, Granule::Kb4);
+
+        assert_eq!(vtcr_el2(Granule::Kb4, 1, 40, pa_range, 0), 0x80023558);
+    }
+
+    #[test]
+    fn cortex_a57() {
+        let pa_range = PhysAddrRange::new(0b0100, Granule::Kb4);
+
+        assert_eq!(vtcr_el2(Granule::Kb4, 0, 44, pa_range, 0), 0x80043594);
+    }
+
+    #[test]
+    fn optional_features() {
+        let pa_range = PhysAddrRange::new(0b0101, Granule::Kb64);
+        let mmfr1 = (ID_AA64MMFR1_VMIDBITS_16 << ID_AA64MMFR1_VMIDBITS_SHIFT) |
+                    ID_AA64MMFR1_HAFDBS_AF_DBS;
+        let vtcr = vtcr_el2(Granule::Kb64, 2, 48, pa_range, mmfr1);
+
+        assert_eq!(vtcr & (VTCR_EL2_VS | VTCR_EL2_HA | VTCR_EL2_HD),
+                   VTCR_EL2_VS | VTCR_EL2_HA | VTCR_EL2_HD);
+        assert_eq!((vtcr >> VTCR_EL2_TG0_SHIFT) & 0b11, 0b01);
+        assert_eq!((vtcr >> VTCR_EL2_SL0_SHIFT) & 0b11, 0b01);
+        assert_eq!(vmid_bits(mmfr1), 16);
+        assert_eq!(vmid_bits(0), 8);
+    }
+
+    #[test]
+    fn ram_is_backed_on_first_touch() {
+        let granule = Granule::Kb4;
+        let mut mem = SimulatedMemory::new(0x1000_0000, 0x10_0000);
+        let stage2 = PageTableTreeStage2::new(&mut mem, granule,
+                                              PhysAddrRange::new(0b0010, granule)).unwrap();
+        let mut vm = Vm::new(stage2);
+
+        vm.declare_ram(0x4000_0000, 0x10_0000);
+        let before = mem.allocated();
+
+        /* MMIO, say */
+        assert!(!vm.handle_translation_fault(&mut mem, 0x0900_0000));
+        assert_eq!(mem.allocated(), before);
+
+        assert!(vm.handle_translation_fault(&mut mem, 0x4000_5678));
+        let page = translate(&vm.stage2, &mem, 0x4000_5678).unwrap();
+        assert_eq!(page.level, 3);
+        assert_eq!(mem.read_u64(page.output_address & !0xfff), 0);
+        assert!(translate(&vm.stage2, &mem, 0x4000_6000).is_err());
+
+        /* A second fault on the page (another vCPU's, say) costs nothing */
+        let allocated = mem.allocated();
+        assert!(vm.handle_translation_fault(&mut mem, 0x4000_5000));
+        assert_eq!(mem.allocated(), allocated);
+
+        /* Nor does finding it mapped only when backing it */
+        let frame = mem.alloc_frames(0x1000, 0x1000).unwrap();
+        assert!(vm.back(&mut mem, 0x4000_5000, frame, 0x1000));
+        assert_eq!(mem.allocated(), allocated);
+    }
+
+    #[test]
+    fn ram_backed_while_logging_is_logged() {
+        let granule = Granule::Kb4;
+        let mut mem = SimulatedMemory::new(0x1000_0000, 0x80_0000);
+        let stage2 = PageTableTreeStage2::new(&mut mem, granule,
+                                              PhysAddrRange::new(0b0010, granule)).unwrap();
+        let mut vm = Vm::new(stage2);
+
+        vm.declare_ram(0x4000_0000, 0x40_0000);
+
+        let bitmap = Box::leak(vec![0; 8].into_boxed_slice());
+        let log = dirty::start_logging(&mut vm.stage2, &mut mem, 0x4020_0000, 0x2_0000,
+                                       false, bitmap).unwrap();
+        vm.dirty_log = Some(log);
+
+        /* A page, not a block, and write-protected until written */
+        assert!(vm.handle_translation_fault(&mut mem, 0x4021_2345));
+        let page = translate(&vm.stage2, &mem, 0x4021_2345).unwrap();
+        assert_eq!(page.level, 3);
+        assert_eq!(page.descriptor.s2ap(), Stage2Access::ReadOnly as u64);
+        assert_eq!(page.descriptor.software(), PTE_SW_LOGGED);
+
+        assert!(vm.handle_write_fault(&mut mem, 0x4021_2345));
+        assert!(vm.dirty_log.as_ref().unwrap().is_dirty(0x4021_2000));
+        assert_eq!(translate(&vm.stage2, &mem, 0x4021_2345).unwrap().descriptor.s2ap(),
+                   Stage2Access::ReadWrite as u64);
+
+        /* Outside the log's block, huge pages as usual */
+        assert!(vm.handle_translation_fault(&mut mem, 0x4000_0000));
+        assert_eq!(translate(&vm.stage2, &mem, 0x4000_0000).unwrap().level, 2);
+    }
+
+    #[test]
+    fn huge_pages_back_ram_and_collapse() {
+        let granule = Granule::Kb4;
+        let mut mem = SimulatedMemory::new(0x1000_0000, 0x80_0000);
+        let stage2 = PageTableTreeStage2::new(&mut mem, granule,
+                                              PhysAddrRange::new(0b0010, granule)).unwrap();
+        let mut vm = Vm::new(stage2);
+
+        /* One whole 2MB block of RAM, and part of another */
+        vm.declare_ram(0x4000_0000, 0x30_0000);
+
+        assert!(vm.handle_translation_fault(&mut mem, 0x4001_2345));
+        let block = translate(&vm.stage2, &mem, 0x4001_2345).unwrap();
+        assert_eq!(block.level, 2);
+        assert_eq!(block.output_address & 0x1f_ffff, 0x1_2345);
+
+        assert!(vm.handle_translation_fault(&mut mem, 0x4020_0000));
+        assert_eq!(translate(&vm.stage2, &mem, 0x4020_0000).unwrap().level, 3);
+
+        /* Split, then scatter the pages, then collapse the copies */
+        vm.stage2.split_range(&mut mem, 0x4000_0000, 0x20_0000).unwrap();
+        let old = translate(&vm.stage2, &mem, 0x4000_1000).unwrap().output_address;
+        let frame = mem.alloc_frames(0x1000, 0x1000).unwrap();
+        mem.write_u64(frame, 0x1234);
+        vm.stage2.update_range(&mut mem, 0x4000_1000, 0x1000, &mut |entry, _address, _level| {
+            let mut moved = entry;
+            moved.set_output_address(frame, granule);
+            moved
+        }).unwrap();
+        mem.free_frames(old, 0x1000);
+
+        assert_eq!(vm.collapse_huge_pages(&mut mem).unwrap(), 1);
+        let block = translate(&vm.stage2, &mem, 0x4000_1000).unwrap();
+        assert_eq!(block.level, 2);
+        assert_eq!(mem.read_u64(block.output_address), 0x1234);
+        assert_eq!(translate(&vm.stage2, &mem, 0x4020_0000).unwrap().level, 3);
+    }
+
+    #[test]
+    fn logged_blocks_stay_split() {
+        let granule = Granule::Kb4;
+        let mut mem = SimulatedMemory::new(0x1000_0000, 0x80_0000);
+        let stage2 = PageTableTreeStage2::new(&mut mem, granule,
+                                              PhysAddrRange::new(0b0010, granule)).unwrap();
+        let mut vm = Vm::new(stage2);
+
+        vm.declare_ram(0x4000_0000, 0x20_0000);
+        assert!(vm.handle_translation_fault(&mut mem, 0x4000_0000));
+        assert_eq!(translate(&vm.stage2, &mem, 0x4000_0000).unwrap().level, 2);
+
+        let bitmap = Box::leak(vec![0; 8].into_boxed_slice());
+        let log = dirty::start_logging(&mut vm.stage2, &mut mem, 0x4000_0000, 0x20_0000,
+                                       false, bitmap).unwrap();
+        vm.dirty_log = Some(log);
+
+        /* Still one contiguous run of frames, but logged page by page */
+        assert!(!vm.stage2.collapse(&mut mem, 0x4000_0000, 2).unwrap());
+        assert_eq!(vm.collapse_huge_pages(&mut mem).unwrap(), 0);
+        assert_eq!(translate(&vm.stage2, &mem, 0x4000_0000).unwrap().level, 3);
+
+        assert!(vm.handle_write_fault(&mut mem, 0x4000_0000));
+        let log = vm.dirty_log.as_ref().unwrap();
+        assert!(log.is_dirty(0x4000_0000));
+        assert!(!log.is_dirty(0x4000_1000));
+    }
+
+    #[test]
+    fn destroy_scrubs_and_frees_everything() {
+        let granule = Granule::Kb4;
+        let mut mem = SimulatedMemory::new(0x1000_0000, 0x10_0000);
+        let before = mem.allocated();
+        let stage2 = PageTableTreeStage2::new(&mut mem, granule,
+                                              PhysAddrRange::new(0b0010, granule)).unwrap();
+        let mut vm = Vm::new(stage2);
+
+        vm.declare_ram(0x4000_0000, 0x10_0000);
+        assert!(vm.handle_translation_fault(&mut mem, 0x4000_0000));
+        assert!(vm.handle_translation_fault(&mut mem, 0x4000_3000));
+
+        let page = translate(&vm.stage2, &mem, 0x4000_3000).unwrap().output_address;
+        mem.write_u64(page + 0x10, 0x5ec7e7);
+
+        /* Not guest RAM, so not the VM's to give back */
+        vm.stage2.map_range(&mut mem, 0x0900_0000, 0x0900_0000, 0x1000, Stage2Flags::device()).unwrap();
+
+        vm.destroy(&mut mem);
+        assert_eq!(mem.allocated(), before);
+        assert_eq!(mem.read_u64(page + 0x10), 0);
+    }
+}
@@ -0,0 +1,235 @@
+/*
+ * Dynamic EL2 mappings.
+ *
+ * Everything that is not part of the identity map of RAM (device
+ * registers, mostly) is mapped into the vmap area, in the spirit of
+ * Linux's vmalloc/ioremap.  A region gets a range of virtual addresses
+ * of its own, followed by an unmapped guard page, and can be unmapped
+ * again once it is no longer needed.
+ *
+ * The hypervisor only runs on one CPU and never maps anything from an
+ * exception handler, so the state here is not locked.
+ */
+
+use crate::lpae::{PageTableTree, TranslationTree, MapError, Granule, align, align_up};
+use crate::frame_alloc::frame_allocator;
+use crate::memory_attrs::MapFlags;
+use crate::aarch64::{data_barrier, isb, Shareable};
+
+/* The top half of the 48-bit EL2 VA space, well clear of the identity map */
+pub const VMAP_START: u64 = 0x8000_0000_0000;
+pub const VMAP_SIZE: u64 = 1 << 40;
+
+const MAX_VMAP_REGIONS: usize = 64;
+
+/// A range of the vmap area handed out by VmapArea::alloc()
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct VmapRegion {
+    pub start: u64,
+    pub size: u64,
+}
+
+impl VmapRegion {
+    /// The end of the region, including its guard page
+    fn reserved_end(&self, granule: Granule) -> u64 {
+        self.start + self.size + granule.page_size()
+    }
+}
+
+/// Hands out non-overlapping, granule aligned ranges of [start, end)
+pub struct VmapArea {
+    start: u64,
+    end: u64,
+    granule: Granule,
+    regions: [Option<VmapRegion>; MAX_VMAP_REGIONS],
+}
+
+impl VmapArea {
+    pub fn new(start: u64, end: u64, granule: Granule) -> VmapArea {
+        VmapArea {
+            start: start,
+            end: end,
+            granule: granule,
+            regions: [None; MAX_VMAP_REGIONS],
+        }
+    }
+
+    /// Reserve `size` bytes (rounded up to the granule) plus a guard
+    /// page, at the lowest free address.
+    pub fn alloc(&mut self, size: u64) -> Option<VmapRegion> {
+        let granule = self.granule;
+        let size = align_up(size, granule);
+        let slot = self.regions.iter().position(|region| region.is_none())?;
+        let mut candidate = VmapRegion { start: self.start, size: size };
+
+        /*
+         * Move past every region that overlaps the candidate, until
+         * one fits.  Regions are not kept sorted, so go round again
+         * whenever the candidate moves.
+         */
+        loop {
+            if candidate.reserved_end(granule) > self.end {
+                return None;
+            }
+
+            let overlap = self.regions.iter()
+                .filter_map(|region| *region)
+                .find(|region| candidate.start < region.reserved_end(granule) &&
+                               region.start < candidate.reserved_end(granule));
+
+            match overlap {
+                Some(region) => candidate.start = region.reserved_end(granule),
+                None => break,
+            }
+        }
+
+        self.regions[slot] = Some(candidate);
+        Some(candidate)
+    }
+
+    /// Returns the region containing address, if any
+    pub fn find(&self, address: u64) -> Option<VmapRegion> {
+        self.regions.iter()
+            .filter_map(|region| *region)
+            .find(|region| address >= region.start && address < region.start + region.size)
+    }
+
+    /// Release the region starting at `start`
+    pub fn free(&mut self, start: u64) -> Option<VmapRegion> {
+        for slot in self.regions.iter_mut() {
+            match *slot {
+                Some(region) if region.start == start => {
+                    *slot = None;
+                    return Some(region);
+                },
+                _ => (),
+            }
+        }
+
+        None
+    }
+}
+
+static mut HYP_TREE: Option<PageTableTree> = None;
+static mut VMAP_AREA: Option<VmapArea> = None;
+
+/// Take over the hypervisor's stage 1 tree, which all vmap mappings
+/// are made in.
+pub fn init(tree: PageTableTree) -> () {
+    let granule = tree.granule();
+
+    unsafe {
+        VMAP_AREA = Some(VmapArea::new(VMAP_START, VMAP_START + VMAP_SIZE, granule));
+        HYP_TREE = Some(tree);
+    }
+}
+
+/// The hypervisor's stage 1 tree
+pub fn hyp_tree() -> &'static mut PageTableTree {
+    unsafe { HYP_TREE.as_mut().unwrap() }
+}
+
+fn vmap_area() -> &'static mut VmapArea {
+    unsafe { VMAP_AREA.as_mut().unwrap() }
+}
+
+/// Map `size` bytes of physical memory at `paddr` with `flags`, and
+/// return a pointer to them.  Neither needs to be aligned.
+pub fn vmap(paddr: u64, size: u64, flags: MapFlags) -> Result<*mut u8, MapError> {
+    let tree = hyp_tree();
+    let granule = tree.granule();
+
+    let base = align(paddr, granule);
+    let size = align_up(paddr + size, granule) - base;
+
+    let region = match vmap_area().alloc(size) {
+        Some(region) => region,
+        None => return Err(MapError::NoVirtualSpace),
+    };
+
+    if let Err(error) = tree.map_range(&mut *frame_allocator(), region.start, base, size, flags) {
+        /* Tidy up whatever part of the range did get mapped */
+        let _ = tree.unmap_range(&mut *frame_allocator(), region.start, size);
+        vmap_area().free(region.start);
+        return Err(error);
+    }
+
+    /* Make the new descriptors visible to the table walker */
+    data_barrier(Shareable::Inner);
+    isb();
+
+    Ok((region.start + (paddr - base)) as *mut u8)
+}
+
+/// Unmap a region returned by vmap() or ioremap()
+pub fn vunmap(address: *mut u8) -> () {
+    let region = match vmap_area().find(address as u64) {
+        Some(region) => region,
+        None => loop {},
+    };
+
+    /* Unmapping whole pages never needs a block split, so cannot fail */
+    hyp_tree().unmap_range(&mut *frame_allocator(), region.start, region.size).unwrap();
+    vmap_area().free(region.start);
+}
+
+/// Map a device's MMIO registers
+pub fn ioremap(paddr: u64, size: u64) -> Result<*mut u8, MapError> {
+    vmap(paddr, size, MapFlags::device())
+}
+
+pub fn iounmap(address: *mut u8) -> () {
+    vunmap(address)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PAGE: u64 = 0x1000;
+
+    fn area() -> VmapArea {
+        VmapArea::new(VMAP_START, VMAP_START + 64 * PAGE, Granule::Kb4)
+    }
+
+    #[test]
+    fn regions_do_not_overlap_and_have_guard_pages() {
+        let mut area = area();
+
+        let a = area.alloc(1).unwrap();
+        let b = area.alloc(3 * PAGE).unwrap();
+        let c = area.alloc(PAGE + 1).unwrap();
+
+        assert_eq!(a, VmapRegion { start: VMAP_START, size: PAGE });
+        assert_eq!(b, VmapRegion { start: VMAP_START + 2 * PAGE, size: 3 * PAGE });
+        assert_eq!(c, VmapRegion { start: VMAP_START + 6 * PAGE, size: 2 * PAGE });
+
+        assert_eq!(area.find(VMAP_START + 4 * PAGE + 8), Some(b));
+        assert_eq!(area.find(VMAP_START + PAGE), None);
+    }
+
+    #[test]
+    fn freed_ranges_are_reused() {
+        let mut area = area();
+
+        let a = area.alloc(2 * PAGE).unwrap();
+        let b = area.alloc(PAGE).unwrap();
+        assert_eq!(area.free(a.start), Some(a));
+        assert_eq!(area.free(a.start), None);
+
+        /* Too big for the hole a left behind */
+        let c = area.alloc(3 * PAGE).unwrap();
+        assert_eq!(c.start, b.start + 2 * PAGE);
+
+        assert_eq!(area.alloc(PAGE).unwrap().start, a.start);
+    }
+
+    #[test]
+    fn exhaustion() {
+        let mut area = area();
+
+        assert!(area.alloc(64 * PAGE).is_none());
+        assert_eq!(area.alloc(63 * PAGE).unwrap().start, VMAP_START);
+        assert!(area.alloc(PAGE).is_none());
+    }
+}
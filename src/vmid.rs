@@ -0,0 +1,186 @@
+/*
+ * VMID allocation.
+ *
+ * Stage 2 (and combined stage 1 + 2) TLB entries are tagged with the
+ * VMID in VTTBR_EL2, so guests with different VMIDs can share the TLBs
+ * and switching between them needs no invalidation.
+ *
+ * VMIDs are handed out in generations, as Linux does for ASIDs.  Each
+ * allocation is tagged with the generation it was made in.  When the
+ * VMIDs run out the generation moves on, every TLB entry is thrown
+ * away, and each VM picks up a fresh VMID the next time it is
+ * switched to.
+ *
+ * VMID 0 is never handed out, so a VTTBR_EL2 without a VMID is never
+ * mistaken for a guest's.
+ *
+ * The hypervisor only runs on one CPU, so there is no need to keep the
+ * VMIDs that other CPUs are running across a rollover.
+ */
+
+use crate::{msr, mrs};
+use crate::aarch64::{id_aa64mmfr1, isb, tlbi_alle1is, tlbi_vmalls12e1is};
+use crate::vm::vmid_bits;
+
+// VTTBR_EL2.VMID == VTTBR_EL2[63:48], or [55:48] with 8-bit VMIDs
+const VTTBR_EL2_VMID_SHIFT: u64 = 48;
+
+/// A VMID, along with the generation it belongs to
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Vmid {
+    generation: u64,
+    id: u64,
+}
+
+impl Vmid {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+}
+
+pub struct VmidAllocator {
+    bits: u64,
+    generation: u64,
+    next: u64,
+}
+
+impl VmidAllocator {
+    pub fn new(bits: u64) -> VmidAllocator {
+        VmidAllocator {
+            bits: bits,
+            generation: 1,
+            next: 1,
+        }
+    }
+
+    pub fn bits(&self) -> u64 {
+        self.bits
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Whether vmid still belongs to its VM, i.e. was allocated in the
+    /// current generation
+    pub fn is_live(&self, vmid: Vmid) -> bool {
+        vmid.generation == self.generation
+    }
+
+    /// Returns vmid if it is still live, otherwise allocates a new one.
+    /// Starting a new generation invalidates the TLBs for all VMIDs.
+    pub fn refresh(&mut self, vmid: Option<Vmid>) -> Vmid {
+        match vmid {
+            Some(vmid) if self.is_live(vmid) => return vmid,
+            _ => (),
+        }
+
+        if self.next == 1 << self.bits {
+            self.generation += 1;
+            self.next = 1;
+
+            /* The old generation's VMIDs are about to be reused */
+            tlbi_alle1is();
+        }
+
+        let vmid = Vmid { generation: self.generation, id: self.next };
+        self.next += 1;
+        vmid
+    }
+}
+
+static mut VMID_ALLOCATOR: Option<VmidAllocator> = None;
+
+/// Size the VMIDs to what the CPU supports, see vtcr_el2() for VTCR_EL2.VS
+pub fn init() -> () {
+    unsafe {
+        VMID_ALLOCATOR = Some(VmidAllocator::new(vmid_bits(id_aa64mmfr1())));
+    }
+}
+
+fn vmid_allocator() -> &'static mut VmidAllocator {
+    unsafe { VMID_ALLOCATOR.as_mut().unwrap() }
+}
+
+pub fn vttbr_el2(root_address: u64, vmid: Vmid) -> u64 {
+    root_address | (vmid.id << VTTBR_EL2_VMID_SHIFT)
+}
+
+/// Run a VM with stage 2 tables at root_address.  vmid is the VM's own
+/// and is replaced whenever it has gone stale.
+pub fn switch_vttbr(root_address: u64, vmid: &mut Option<Vmid>) -> () {
+    let live = vmid_allocator().refresh(*vmid);
+
+    *vmid = Some(live);
+    msr!("VTTBR_EL2", vttbr_el2(root_address, live));
+    isb();
+}
+
+/// Invalidate all the TLB entries for a VM's VMID, whether or not the
+/// VM is the one currently loaded
+pub fn flush_vmid(vmid: Vmid) -> () {
+    /* A stale VMID's entries went with the last rollover */
+    if !vmid_allocator().is_live(vmid) {
+        return;
+    }
+
+    /* TLBI by VMID works on the VMID in VTTBR_EL2, borrow it */
+    let vttbr: u64;
+    mrs!(vttbr, "VTTBR_EL2");
+
+    msr!("VTTBR_EL2", vmid.id << VTTBR_EL2_VMID_SHIFT);
+    isb();
+    tlbi_vmalls12e1is();
+
+    msr!("VTTBR_EL2", vttbr);
+    isb();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn live_vmids_are_kept() {
+        let mut allocator = VmidAllocator::new(8);
+
+        let a = allocator.refresh(None);
+        let b = allocator.refresh(None);
+
+        assert_eq!((a.id(), b.id()), (1, 2));
+        assert_eq!(allocator.refresh(Some(a)), a);
+        assert_eq!(vttbr_el2(0x4008_0000, b), 0x0002_0000_4008_0000);
+    }
+
+    #[test]
+    fn rollover_starts_a_new_generation() {
+        let mut allocator = VmidAllocator::new(8);
+
+        let first = allocator.refresh(None);
+        let last = (2..256).map(|_| allocator.refresh(None)).last().unwrap();
+        assert_eq!(last.id(), 255);
+        assert!(allocator.is_live(first));
+
+        /* Out of VMIDs: everyone's goes stale, VMID 0 stays reserved */
+        let next = allocator.refresh(None);
+        assert_eq!(allocator.generation(), 2);
+        assert_eq!(next.id(), 1);
+        assert!(!allocator.is_live(first) && !allocator.is_live(last));
+
+        let again = allocator.refresh(Some(first));
+        assert_eq!(again.id(), 2);
+        assert!(allocator.is_live(again));
+    }
+
+    #[test]
+    fn sixteen_bit_vmids() {
+        let mut allocator = VmidAllocator::new(16);
+
+        for _ in 1..256 {
+            allocator.refresh(None);
+        }
+
+        assert_eq!(allocator.refresh(None).id(), 256);
+        assert_eq!(allocator.generation(), 1);
+    }
+}
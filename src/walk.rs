@@ -0,0 +1,359 @@
+/*
+ * A software translation table walker.
+ *
+ * This walks the same tables the MMU does, and is used to find out
+ * what the hypervisor believes is mapped when a translation fault
+ * happens.  It works for both the EL2 stage 1 tree and a guest's
+ * stage 2 tree.
+ */
+
+use crate::lpae::{
+    PageTable,
+    PageTableEntry,
+    TranslationTree,
+    Granule,
+    Attributes,
+    Stage1Attributes,
+    Stage2Attributes,
+};
+use crate::phys::PhysicalMemory;
+use crate::memory_attrs::{MemoryType, Shareability, Stage2Access, Stage2MemoryType};
+use crate::common::{print_hex, to_hex};
+use crate::uart::uart_write;
+
+/// A successful translation of an input address
+#[derive(Copy, Clone, Debug)]
+pub struct Translation {
+    /// The output address the input address translates to
+    pub output_address: u64,
+
+    /// The level of the block or page descriptor that mapped it
+    pub level: usize,
+
+    /// The size of the block or page
+    pub block_size: u64,
+
+    pub descriptor: PageTableEntry,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum WalkFault {
+    /// The input address is larger than the tree translates
+    OutOfRange,
+
+    /// The descriptor is not valid
+    Invalid,
+
+    /// A level 3 descriptor with bit 1 clear, or a block descriptor at a
+    /// level the granule has no blocks at, both of which are reserved
+    Reserved,
+}
+
+/// Where, and why, a walk failed
+#[derive(Copy, Clone, Debug)]
+pub struct WalkFailure {
+    pub fault: WalkFault,
+
+    /// The level of the table that holds the faulting descriptor
+    pub level: usize,
+
+    /// The address of that table
+    pub table_address: u64,
+
+    /// The index of the faulting descriptor within that table
+    pub index: usize,
+
+    pub descriptor: PageTableEntry,
+}
+
+fn root_size<T: TranslationTree>(tree: &T) -> u64 {
+    tree.root().len() as u64 * tree.granule().level_size(tree.start_level())
+}
+
+/// Translate `address` (an EL2 VA for a stage 1 tree, an IPA for a
+/// stage 2 tree) down to its output address.
+pub fn translate<T: TranslationTree>(tree: &T,
+                                     mem: &dyn PhysicalMemory,
+                                     address: u64) -> Result<Translation, WalkFailure> {
+    let granule = tree.granule();
+    let mut table = tree.root();
+    let mut level = tree.start_level();
+
+    if address >= root_size(tree) {
+        return Err(WalkFailure {
+            fault: WalkFault::OutOfRange,
+            level: level,
+            table_address: table.address(),
+            index: 0,
+            descriptor: PageTableEntry(0),
+        });
+    }
+
+    loop {
+        let index = table.index(address, level, granule);
+        let descriptor = table.read(mem, index);
+
+        let fault = if !descriptor.is_valid() {
+            Some(WalkFault::Invalid)
+        } else if !descriptor.is_table() && (level == 3 || !granule.has_blocks_at(level)) {
+            Some(WalkFault::Reserved)
+        } else {
+            None
+        };
+
+        if let Some(fault) = fault {
+            return Err(WalkFailure {
+                fault: fault,
+                level: level,
+                table_address: table.address(),
+                index: index,
+                descriptor: descriptor,
+            });
+        }
+
+        if descriptor.is_leaf(level) {
+            let size = granule.level_size(level);
+
+            return Ok(Translation {
+                output_address: descriptor.output_address(granule) | (address & (size - 1)),
+                level: level,
+                block_size: size,
+                descriptor: descriptor,
+            });
+        }
+
+        table = descriptor.as_pagetable(granule);
+        level += 1;
+    }
+}
+
+fn print_level(level: usize) -> () {
+    uart_write("L");
+    uart_write(to_hex(level as u64));
+}
+
+fn print_size(size: u64) -> () {
+    match size {
+        0x1000 => uart_write("4KB"),
+        0x4000 => uart_write("16KB"),
+        0x10000 => uart_write("64KB"),
+        0x200000 => uart_write("2MB"),
+        0x2000000 => uart_write("32MB"),
+        0x20000000 => uart_write("512MB"),
+        0x40000000 => uart_write("1GB"),
+        _ => print_hex(size),
+    }
+}
+
+fn print_shareability(shareability: Shareability) -> () {
+    match shareability {
+        Shareability::NonShareable => uart_write(" SH=NS"),
+        Shareability::OuterShareable => uart_write(" SH=OS"),
+        Shareability::InnerShareable => uart_write(" SH=IS"),
+    }
+}
+
+fn print_flag(set: bool, name: &str) -> () {
+    if set {
+        uart_write(" ");
+        uart_write(name);
+    }
+}
+
+/// Decode the attributes of a stage 1 (EL2) block or page descriptor
+fn print_stage1_attributes(attributes: Stage1Attributes) -> () {
+    match attributes.memory_type() {
+        Some(MemoryType::NormalWriteBack) => uart_write(" Normal-WB"),
+        Some(MemoryType::NormalNonCacheable) => uart_write(" Normal-NC"),
+        Some(MemoryType::Device_nGnRnE) => uart_write(" Device-nGnRnE"),
+        Some(MemoryType::Device_nGnRE) => uart_write(" Device-nGnRE"),
+        None => {
+            uart_write(" AttrIndx=");
+            uart_write(to_hex(attributes.attr_index));
+        },
+    }
+
+    if attributes.read_only {
+        uart_write(" RO");
+    } else {
+        uart_write(" RW");
+    }
+
+    print_shareability(attributes.shareability);
+    print_flag(attributes.non_global, "nG");
+    print_flag(attributes.access_flag, "AF");
+    print_flag(attributes.execute_never, "XN");
+    print_flag(attributes.contiguous, "CONT");
+}
+
+/// Decode the attributes of a stage 2 block or page descriptor
+fn print_stage2_attributes(attributes: Stage2Attributes) -> () {
+    match attributes.memory_type() {
+        Some(Stage2MemoryType::Device_nGnRnE) => uart_write(" Device-nGnRnE"),
+        Some(Stage2MemoryType::Device_nGnRE) => uart_write(" Device-nGnRE"),
+        Some(Stage2MemoryType::Device_nGRE) => uart_write(" Device-nGRE"),
+        Some(Stage2MemoryType::Device_GRE) => uart_write(" Device-GRE"),
+        Some(Stage2MemoryType::NormalNonCacheable) => uart_write(" Normal-NC"),
+        Some(Stage2MemoryType::NormalWriteThrough) => uart_write(" Normal-WT"),
+        Some(Stage2MemoryType::NormalWriteBack) => uart_write(" Normal-WB"),
+        None => {
+            uart_write(" MemAttr=");
+            uart_write(to_hex(attributes.mem_attr));
+        },
+    }
+
+    match attributes.access {
+        Stage2Access::None => uart_write(" S2AP=none"),
+        Stage2Access::ReadOnly => uart_write(" S2AP=RO"),
+        Stage2Access::WriteOnly => uart_write(" S2AP=WO"),
+        Stage2Access::ReadWrite => uart_write(" S2AP=RW"),
+    }
+
+    print_shareability(attributes.shareability);
+    print_flag(attributes.access_flag, "AF");
+    print_flag(attributes.execute_never, "XN");
+    print_flag(attributes.contiguous, "CONT");
+}
+
+fn print_attributes(descriptor: PageTableEntry, level: usize, granule: Granule, stage2: bool) -> () {
+    match descriptor.decode(level, granule, stage2).attributes() {
+        Some(Attributes::Stage1(attributes)) => print_stage1_attributes(attributes),
+        Some(Attributes::Stage2(attributes)) => print_stage2_attributes(attributes),
+        None => (),
+    }
+}
+
+/// Walk `tree` for `address` and print the result over the UART
+pub fn print_translation<T: TranslationTree>(tree: &T,
+                                             mem: &dyn PhysicalMemory,
+                                             address: u64) -> () {
+    print_hex(address);
+
+    match translate(tree, mem, address) {
+        Ok(translation) => {
+            uart_write(" -> ");
+            print_hex(translation.output_address);
+            uart_write(" ");
+            print_level(translation.level);
+            uart_write(" ");
+            print_size(translation.block_size);
+            print_attributes(translation.descriptor, translation.level,
+                             tree.granule(), tree.is_stage2());
+        },
+        Err(failure) => {
+            uart_write(" fault: ");
+            match failure.fault {
+                WalkFault::OutOfRange => uart_write("out of range"),
+                WalkFault::Invalid => uart_write("invalid descriptor"),
+                WalkFault::Reserved => uart_write("reserved descriptor"),
+            }
+            uart_write(" at ");
+            print_level(failure.level);
+            uart_write(" table ");
+            print_hex(failure.table_address);
+            uart_write(" index ");
+            print_hex(failure.index as u64);
+            uart_write(" descriptor ");
+            print_hex(failure.descriptor.0);
+        },
+    }
+
+    uart_write("\n");
+}
+
+fn print_indent(level: usize) -> () {
+    for _ in 0..level {
+        uart_write("  ");
+    }
+}
+
+fn dump_table(mem: &dyn PhysicalMemory,
+              table: PageTable,
+              level: usize,
+              granule: Granule,
+              base: u64,
+              stage2: bool) -> () {
+    for index in 0..table.len() {
+        let descriptor = table.read(mem, index);
+        if !descriptor.is_valid() {
+            continue;
+        }
+
+        let address = base + (index as u64) * granule.level_size(level);
+
+        print_indent(level);
+        print_level(level);
+        uart_write("[");
+        print_hex(index as u64);
+        uart_write("] ");
+        print_hex(address);
+
+        if descriptor.is_leaf(level) {
+            uart_write(" -> ");
+            print_hex(descriptor.output_address(granule));
+            uart_write(" ");
+            print_size(granule.level_size(level));
+            print_attributes(descriptor, level, granule, stage2);
+            uart_write("\n");
+        } else if level == 3 {
+            uart_write(" reserved ");
+            print_hex(descriptor.0);
+            uart_write("\n");
+        } else {
+            uart_write(" table ");
+            print_hex(descriptor.output_address(granule));
+            uart_write("\n");
+
+            dump_table(mem, descriptor.as_pagetable(granule), level + 1, granule, address, stage2);
+        }
+    }
+}
+
+/// Print every valid descriptor in `tree` over the UART
+pub fn dump_tree<T: TranslationTree>(tree: &T, mem: &dyn PhysicalMemory) -> () {
+    if tree.is_stage2() {
+        uart_write("Stage 2 translation tables:\n");
+    } else {
+        uart_write("EL2 stage 1 translation tables:\n");
+    }
+
+    dump_table(mem, tree.root(), tree.start_level(), tree.granule(), 0, tree.is_stage2());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lpae::{PageTableTree, PhysAddrRange};
+    use crate::memory_attrs::MapFlags;
+    use crate::phys::SimulatedMemory;
+
+    #[test]
+    fn faults_report_the_failing_table() {
+        let granule = Granule::Kb4;
+        let mut mem = SimulatedMemory::new(0x4000_0000, 1 << 20);
+        let mut tree = PageTableTree::new(&mut mem, granule, PhysAddrRange::new(0b0101, granule)).unwrap();
+
+        let failure = translate(&tree, &mem, 0x1000).unwrap_err();
+        assert_eq!(failure.fault, WalkFault::Invalid);
+        assert_eq!(failure.level, 0);
+        assert_eq!(failure.table_address, tree.root().address());
+        assert_eq!(failure.index, 0);
+
+        tree.map(&mut mem, 0x1000, 0x4000_0000, MapFlags::normal()).unwrap();
+
+        let failure = translate(&tree, &mem, 0x5000).unwrap_err();
+        assert_eq!(failure.fault, WalkFault::Invalid);
+        assert_eq!(failure.level, 3);
+        assert_eq!(failure.index, 5);
+
+        let failure = translate(&tree, &mem, 1 << 48).unwrap_err();
+        assert_eq!(failure.fault, WalkFault::OutOfRange);
+
+        /* The 4KB granule has no level 0 blocks */
+        tree.root().write(&mut mem, 1, PageTableEntry(0x80_0000_0000 | 1));
+        let failure = translate(&tree, &mem, 0x80_0000_1000).unwrap_err();
+        assert_eq!(failure.fault, WalkFault::Reserved);
+        assert_eq!(failure.level, 0);
+        assert_eq!(failure.index, 1);
+    }
+}